@@ -0,0 +1,8 @@
+-- Signed decimal degrees computed from the GPS rationals and their Ref tags.
+-- gps_latitude/gps_longitude keep the raw exif strings as they were read.
+alter table exif add column latitude REAL;
+alter table exif add column longitude REAL;
+-- Meters, negative when below sea level
+alter table exif add column altitude REAL;
+
+create index IF NOT EXISTS exif_location on exif(latitude, longitude);
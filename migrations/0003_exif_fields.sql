@@ -0,0 +1,27 @@
+-- Brings the exif table in line with image_helpers::EXIF_FIELDS, which the insert and
+-- read code use. The registry and this table are checked against each other in tests.
+alter table exif rename column shutter_speed to shutter_speed_value;
+alter table exif add column x_resolution varchar;
+alter table exif add column y_resolution varchar;
+alter table exif add column resolution_unit varchar;
+alter table exif add column software varchar;
+alter table exif add column copyright varchar;
+alter table exif add column photographic_sensitivity varchar;
+alter table exif add column sensitivity_type varchar;
+alter table exif add column datetime varchar;
+alter table exif add column offset_time varchar;
+alter table exif add column max_aperture_value varchar;
+alter table exif add column light_source varchar;
+alter table exif add column pixel_x_dimension varchar;
+alter table exif add column pixel_y_dimension varchar;
+alter table exif add column sensing_method varchar;
+alter table exif add column file_source varchar;
+alter table exif add column scene_type varchar;
+alter table exif add column custom_rendered varchar;
+alter table exif add column scene_capture_type varchar;
+alter table exif add column subject_distance varchar;
+alter table exif add column subject_distance_range varchar;
+alter table exif add column compression varchar;
+alter table exif add column orientation varchar;
+alter table exif add column description varchar;
+alter table exif add column user_comment varchar;
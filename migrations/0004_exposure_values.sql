@@ -0,0 +1,14 @@
+-- Typed exposure values next to the raw exif strings, so that images can be filtered by
+-- ranges like "f/2.8 or wider" or "ISO over 3200"
+alter table exif add column exposure_seconds REAL;
+alter table exif add column f_stop REAL;
+alter table exif add column iso INTEGER;
+alter table exif add column focal_length_mm REAL;
+alter table exif add column focal_length_35mm REAL;
+-- Human readable versions, e.g. "1/250 s", "f/2.8", "ISO 400", "85 mm"
+alter table exif add column exposure_display varchar;
+alter table exif add column f_stop_display varchar;
+alter table exif add column iso_display varchar;
+alter table exif add column focal_length_display varchar;
+
+create index IF NOT EXISTS exif_image_id on exif(image_id);
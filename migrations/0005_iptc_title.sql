@@ -0,0 +1,2 @@
+-- Title of the image, read from IPTC ObjectName or xmp dc:title
+alter table iptc add column title varchar;
@@ -0,0 +1,17 @@
+-- Catalog wide settings, e.g. whether metadata changes are written back to xmp
+create table if not exists setting (
+    key varchar PRIMARY KEY NOT NULL,
+    value varchar NOT NULL
+);
+
+-- State of the file we last read xmp from or wrote xmp to for an image (the sidecar,
+-- or the image itself when xmp is embedded). If the file on disk no longer matches,
+-- another program changed it since.
+create table if not exists xmp_sync (
+    image_id INTEGER PRIMARY KEY NOT NULL,
+    path varchar NOT NULL,
+    file_modified_time varchar NOT NULL,
+    hash varchar NOT NULL,
+    synced_at DATETIME not null DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(image_id) REFERENCES image(id)
+);
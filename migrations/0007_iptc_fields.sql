@@ -0,0 +1,30 @@
+-- IPTC Core and Extension fields, see image_helpers::IPTC_FIELDS. Fields which can
+-- have more than one value (creators, persons shown etc.) are stored joined with "; ".
+alter table iptc add column headline varchar;
+alter table iptc add column sublocation varchar;
+alter table iptc add column province_state varchar;
+alter table iptc add column credit varchar;
+alter table iptc add column source varchar;
+alter table iptc add column usage_terms varchar;
+alter table iptc add column creator_job_title varchar;
+alter table iptc add column description_writer varchar;
+alter table iptc add column instructions varchar;
+alter table iptc add column job_id varchar;
+alter table iptc add column date_created varchar;
+alter table iptc add column intellectual_genre varchar;
+alter table iptc add column subject_codes varchar;
+alter table iptc add column scene_codes varchar;
+alter table iptc add column contact_address varchar;
+alter table iptc add column contact_city varchar;
+alter table iptc add column contact_region varchar;
+alter table iptc add column contact_postal_code varchar;
+alter table iptc add column contact_country varchar;
+alter table iptc add column contact_email varchar;
+alter table iptc add column contact_phone varchar;
+alter table iptc add column contact_url varchar;
+alter table iptc add column event varchar;
+alter table iptc add column persons_shown varchar;
+alter table iptc add column organisations_shown varchar;
+alter table iptc add column digital_source_type varchar;
+
+create index if not exists iptc_image_id on iptc(image_id);
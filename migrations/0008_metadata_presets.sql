@@ -0,0 +1,17 @@
+-- Named sets of iptc values (copyright, creator, usage terms etc.) which can be applied
+-- while importing or to a selection of images later
+create table if not exists metadata_preset (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    name varchar NOT NULL UNIQUE,
+    created_at DATETIME not null DEFAULT CURRENT_TIMESTAMP
+);
+
+-- mode is either 'overwrite' or 'fillEmpty'
+create table if not exists metadata_preset_field (
+    preset_id INTEGER NOT NULL,
+    column_name varchar NOT NULL,
+    value varchar NOT NULL,
+    mode varchar NOT NULL DEFAULT 'fillEmpty',
+    FOREIGN KEY(preset_id) REFERENCES metadata_preset(id) ON DELETE CASCADE,
+    UNIQUE (preset_id, column_name)
+);
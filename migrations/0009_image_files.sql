@@ -0,0 +1,15 @@
+-- Every file belonging to an image. Files shot together (RAW+JPEG) share a base name
+-- and are one image, with image.library_file_id pointing at the primary file. Sidecars
+-- are listed here too.
+create table if not exists image_file (
+    image_id INTEGER NOT NULL,
+    library_file_id INTEGER NOT NULL UNIQUE,
+    role varchar NOT NULL, -- can be 'primary', 'secondary' or 'sidecar'
+    FOREIGN KEY(image_id) REFERENCES image(id),
+    FOREIGN KEY(library_file_id) REFERENCES library_file(id)
+);
+
+create index if not exists image_file_image_id on image_file(image_id);
+
+insert into image_file (image_id, library_file_id, role)
+select id, library_file_id, 'primary' from image where library_file_id is not null;
@@ -0,0 +1,15 @@
+-- Stacks collapse related images (bursts, brackets, versions) behind one top image.
+-- A collapsed stack only shows its top image in image queries.
+create table if not exists stack (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    top_image_id INTEGER NOT NULL,
+    collapsed INTEGER NOT NULL DEFAULT 1,
+    created_at DATETIME not null DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(top_image_id) REFERENCES image(id)
+);
+
+alter table image add column stack_id INTEGER REFERENCES stack(id);
+create index if not exists image_stack_id on image(stack_id);
+
+-- Needed for bracket detection
+alter table exif add column exposure_bias varchar;
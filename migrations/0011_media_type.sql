@@ -0,0 +1,3 @@
+-- Videos are imported next to images. duration_seconds is only set for videos.
+alter table image add column media_type varchar NOT NULL DEFAULT 'image';
+alter table image add column duration_seconds REAL;
@@ -0,0 +1,3 @@
+-- The format a file really is, from its magic bytes. It can differ from the extension,
+-- and files without an extension only have this.
+alter table library_file add column detected_format varchar;
@@ -0,0 +1,13 @@
+-- The moving part of Live Photos (a separate mov, also listed in image_file with the
+-- role 'motion') and of motion photos (an mp4 appended to the jpeg). video_offset and
+-- video_length locate the video inside the file.
+create table if not exists motion_component (
+    image_id INTEGER PRIMARY KEY,
+    kind varchar NOT NULL, -- can be 'livePhoto' or 'motionPhoto'
+    library_file_id INTEGER NOT NULL,
+    video_offset INTEGER NOT NULL,
+    video_length INTEGER NOT NULL,
+    content_identifier varchar,
+    FOREIGN KEY(image_id) REFERENCES image(id),
+    FOREIGN KEY(library_file_id) REFERENCES library_file(id)
+);
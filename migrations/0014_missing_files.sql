@@ -0,0 +1,5 @@
+-- Size and hash identify a file again after it was moved outside the app. missing is set
+-- by the missing file check and cleared when the file is found again.
+alter table library_file add column file_size INTEGER;
+alter table library_file add column hash varchar;
+alter table library_file add column missing INTEGER NOT NULL DEFAULT 0;
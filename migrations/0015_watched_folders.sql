@@ -0,0 +1,8 @@
+-- Folders whose new files are imported automatically. Watching can be turned off per
+-- folder without forgetting the folder.
+create table if not exists watched_folder (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    path varchar NOT NULL UNIQUE,
+    enabled INTEGER NOT NULL DEFAULT 1,
+    created_at DATETIME not null DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,14 @@
+-- Top level folders (e.g. the photo drive) the files of the catalog live in. A file's
+-- relative_path is its path inside its root, so a drive which is mounted somewhere else
+-- only needs its root_folder.path updated. relative_path uses '/' on every platform, so
+-- the catalog can move between systems. path and parent_path stay absolute, for all the
+-- code which reads them, and are rebuilt from the root when it's re-pointed.
+create table if not exists root_folder (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    name varchar NOT NULL,
+    path varchar NOT NULL UNIQUE,
+    created_at DATETIME not null DEFAULT CURRENT_TIMESTAMP
+);
+
+alter table library_file add column root_folder_id INTEGER REFERENCES root_folder(id);
+alter table library_file add column relative_path varchar;
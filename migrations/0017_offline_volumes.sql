@@ -0,0 +1,12 @@
+-- Whether the root was there when we last looked. Files of roots which aren't, e.g. an
+-- unplugged drive, are still in the catalog but can't be read or written.
+alter table root_folder add column online INTEGER NOT NULL DEFAULT 1;
+
+-- Metadata write-backs for images on a root which is offline, done when it's back.
+-- force has the meaning of xmp::write_back_image.
+create table if not exists pending_write_back (
+    image_id INTEGER PRIMARY KEY NOT NULL,
+    force INTEGER NOT NULL DEFAULT 0,
+    queued_at DATETIME not null DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY(image_id) REFERENCES image(id)
+);
@@ -0,0 +1,443 @@
+use crate::{file_operations, image_helpers};
+use chrono::{DateTime, Utc};
+use sqlx::{Row, SqlitePool};
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum OnCollision {
+    // IMG_0001 becomes IMG_0001-2 when IMG_0001 is taken
+    #[default]
+    AddSuffix,
+    // The image keeps its name
+    Skip,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RenameOptions {
+    // e.g. "{year}-{month}-{day}_{camera}_{seq:4}", see parse_template for the tokens
+    pub template: String,
+    #[serde(default = "default_sequence_start")]
+    pub sequence_start: u32,
+    #[serde(default)]
+    pub on_collision: OnCollision,
+}
+
+fn default_sequence_start() -> u32 {
+    1
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RenamePreview {
+    pub image_id: i64,
+    // File names of the primary file, with extension
+    pub old_name: String,
+    pub new_name: String,
+    // The name the files of the image get, before their extensions
+    pub new_base_name: String,
+    // The name from the template was taken, by another file or an earlier image
+    pub collision: bool,
+    pub skipped: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    Camera,
+    Make,
+    Lens,
+    Original,
+    // Zero padded to the width
+    Sequence(usize),
+    Rating,
+    Keyword,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Token(Token),
+}
+
+// What the tokens of one image are filled with
+struct ImageValues {
+    original: String,
+    capture_time: Option<DateTime<Utc>>,
+    make: Option<String>,
+    model: Option<String>,
+    lens: Option<String>,
+    rating: u32,
+    keyword: Option<String>,
+}
+
+fn invalid_template(message: String) -> sqlx::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message).into()
+}
+
+/// Splits a template into text and tokens. Tokens are written in braces: {year},
+/// {month}, {day}, {hour}, {minute}, {second}, {camera}, {make}, {lens}, {original},
+/// {seq} or {seq:4} for a zero padded sequence number, {rating} and {keyword}.
+fn parse_template(template: &str) -> Result<Vec<Part>, sqlx::Error> {
+    let mut parts = vec![];
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            parts.push(Part::Text(rest[..start].to_string()));
+        }
+        let Some(end) = rest[start..].find('}') else {
+            return Err(invalid_template(format!("unclosed token in {template:?}")));
+        };
+        let token = &rest[start + 1..start + end];
+        let token = match token.split_once(':') {
+            Some(("seq", width)) => Token::Sequence(
+                width
+                    .parse()
+                    .map_err(|_| invalid_template(format!("bad sequence width {width:?}")))?,
+            ),
+            _ => match token {
+                "year" => Token::Year,
+                "month" => Token::Month,
+                "day" => Token::Day,
+                "hour" => Token::Hour,
+                "minute" => Token::Minute,
+                "second" => Token::Second,
+                "camera" => Token::Camera,
+                "make" => Token::Make,
+                "lens" => Token::Lens,
+                "original" => Token::Original,
+                "seq" => Token::Sequence(1),
+                "rating" => Token::Rating,
+                "keyword" => Token::Keyword,
+                _ => return Err(invalid_template(format!("unknown token {{{token}}}"))),
+            },
+        };
+        parts.push(Part::Token(token));
+        rest = &rest[start + end + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Text(rest.to_string()));
+    }
+    Ok(parts)
+}
+
+fn render(parts: &[Part], values: &ImageValues, sequence: u32) -> String {
+    let date = |format: &str| {
+        values
+            .capture_time
+            .map(|time| time.format(format).to_string())
+            .unwrap_or_default()
+    };
+    let name: String = parts
+        .iter()
+        .map(|part| match part {
+            Part::Text(text) => text.clone(),
+            Part::Token(token) => match token {
+                Token::Year => date("%Y"),
+                Token::Month => date("%m"),
+                Token::Day => date("%d"),
+                Token::Hour => date("%H"),
+                Token::Minute => date("%M"),
+                Token::Second => date("%S"),
+                // The model usually has the make in it already ("Canon EOS R5")
+                Token::Camera => values
+                    .model
+                    .clone()
+                    .or(values.make.clone())
+                    .unwrap_or_default(),
+                Token::Make => values.make.clone().unwrap_or_default(),
+                Token::Lens => values.lens.clone().unwrap_or_default(),
+                Token::Original => values.original.clone(),
+                Token::Sequence(width) => format!("{sequence:0width$}"),
+                Token::Rating => values.rating.to_string(),
+                Token::Keyword => values.keyword.clone().unwrap_or_default(),
+            },
+        })
+        .collect();
+    sanitize(&name)
+}
+
+// Characters which aren't allowed in file names on one system or another
+fn sanitize(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '-',
+            c if c.is_control() => '-',
+            c => c,
+        })
+        .collect();
+    name.trim_matches([' ', '.']).to_string()
+}
+
+/// Fills a template for a file which isn't in the catalog yet, so only the original
+/// name and the capture time are known. Used for the folders of copy imports.
+pub(crate) fn render_for_file(
+    template: &str,
+    original: &str,
+    capture_time: Option<DateTime<Utc>>,
+) -> Result<String, sqlx::Error> {
+    let values = ImageValues {
+        original: original.to_string(),
+        capture_time,
+        make: None,
+        model: None,
+        lens: None,
+        rating: 0,
+        keyword: None,
+    };
+    Ok(render(&parse_template(template)?, &values, 1))
+}
+
+/// Works out the new names without touching any file
+pub async fn preview_rename(
+    pool: &SqlitePool,
+    image_ids: &[i64],
+    options: &RenameOptions,
+) -> Result<Vec<RenamePreview>, sqlx::Error> {
+    let parts = parse_template(&options.template)?;
+    let mut taken: HashSet<PathBuf> = HashSet::new();
+    let mut previews = vec![];
+    for (index, image_id) in image_ids.iter().enumerate() {
+        let (values, files) = get_image_values(pool, *image_id).await?;
+        let Some(primary) = files.first() else {
+            return Err(sqlx::Error::RowNotFound);
+        };
+        let sequence = options.sequence_start + index as u32;
+        let mut new_name = render(&parts, &values, sequence);
+        if new_name.is_empty() {
+            new_name = values.original.clone();
+        }
+
+        // A name collides when any of the image's files would land on an existing file,
+        // other than one of its own, or on a name given to an earlier image
+        let collides = |name: &str| {
+            files
+                .iter()
+                .filter_map(|file| file_operations::renamed_path(file, &values.original, name))
+                .any(|path| taken.contains(&path) || (path.exists() && !files.contains(&path)))
+        };
+        let collision = collides(&new_name);
+        let mut skipped = false;
+        if collision {
+            match options.on_collision {
+                OnCollision::AddSuffix => {
+                    let base = new_name.clone();
+                    let mut counter = 2;
+                    while collides(&new_name) {
+                        new_name = format!("{base}-{counter}");
+                        counter += 1;
+                    }
+                }
+                OnCollision::Skip => {
+                    skipped = true;
+                    new_name = values.original.clone();
+                }
+            }
+        }
+        taken.extend(
+            files.iter().filter_map(|file| {
+                file_operations::renamed_path(file, &values.original, &new_name)
+            }),
+        );
+
+        let new_primary = file_operations::renamed_path(primary, &values.original, &new_name)
+            .unwrap_or_else(|| primary.clone());
+        previews.push(RenamePreview {
+            image_id: *image_id,
+            old_name: file_name(primary),
+            new_name: file_name(&new_primary),
+            new_base_name: new_name,
+            collision,
+            skipped,
+        });
+    }
+    Ok(previews)
+}
+
+/// Renames the images according to the template. Either all of them are renamed or,
+/// when something fails, none. Returns what was done, as preview_rename describes it.
+pub async fn batch_rename(
+    pool: &SqlitePool,
+    image_ids: &[i64],
+    options: &RenameOptions,
+) -> Result<Vec<RenamePreview>, sqlx::Error> {
+    let previews = preview_rename(pool, image_ids, options).await?;
+    let renames: Vec<(i64, String)> = previews
+        .iter()
+        .filter(|preview| !preview.skipped && preview.old_name != preview.new_name)
+        .map(|preview| (preview.image_id, preview.new_base_name.clone()))
+        .collect();
+    file_operations::rename_images(pool, &renames).await?;
+    Ok(previews)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+// The values for the template and the files of the image, primary file first
+async fn get_image_values(
+    pool: &SqlitePool,
+    image_id: i64,
+) -> Result<(ImageValues, Vec<PathBuf>), sqlx::Error> {
+    let row = sqlx::query(
+        r#"select lf.base_name, i.capture_time, i.rating, e.datetime_original,
+        e.camera_make, e.camera_model, e.lens_model,
+        (select min(tag_name) from tag where image_id = i.id) as keyword
+        from image i join library_file lf on lf.id = i.library_file_id
+        left join exif e on e.image_id = i.id
+        where i.id=? limit 1"#,
+    )
+    .bind(image_id)
+    .fetch_one(pool)
+    .await?;
+    let files = sqlx::query(
+        r#"select lf.path from image_file f join library_file lf on lf.id = f.library_file_id
+        where f.image_id=? order by f.role != 'primary', lf.path"#,
+    )
+    .bind(image_id)
+    .fetch_all(pool)
+    .await?
+    .iter()
+    .map(|row| PathBuf::from(row.get::<String, _>("path")))
+    .collect();
+
+    // The name should have the time on the camera's clock, so the offset isn't applied
+    let capture_time = row
+        .get::<Option<String>, _>("datetime_original")
+        .or(row.get("capture_time"))
+        .and_then(|datetime| image_helpers::parse_capture_time(&datetime, None));
+    let text = |column: &str| {
+        row.get::<Option<String>, _>(column)
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+    let values = ImageValues {
+        original: row.get("base_name"),
+        capture_time,
+        make: text("camera_make"),
+        model: text("camera_model"),
+        lens: text("lens_model"),
+        rating: row.get::<Option<u32>, _>("rating").unwrap_or(0),
+        keyword: text("keyword"),
+    };
+    Ok((values, files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use std::fs;
+
+    #[test]
+    fn test_parse_and_render_template() {
+        let parts = parse_template("{year}-{month}-{day}_{camera}_{seq:3}").unwrap();
+        assert_eq!(parts.len(), 9);
+        let values = ImageValues {
+            original: "WhatsApp Image 2024-03-24 at 11.51.54 AM (2)".to_string(),
+            capture_time: image_helpers::parse_capture_time("2024:03:24 11:51:54", None),
+            make: Some("FUJIFILM".to_string()),
+            model: Some("X-T4".to_string()),
+            lens: None,
+            rating: 4,
+            keyword: Some("trip/iceland".to_string()),
+        };
+        assert_eq!(render(&parts, &values, 7), "2024-03-24_X-T4_007");
+
+        let parts = parse_template("{keyword} {hour}.{minute} [{rating}] {lens}").unwrap();
+        assert_eq!(render(&parts, &values, 1), "trip-iceland 11.51 [4]");
+
+        assert!(parse_template("{year}-{unknown}").is_err());
+        assert!(parse_template("{year").is_err());
+        assert!(parse_template("{seq:x}").is_err());
+    }
+
+    #[sqlx::test]
+    async fn test_batch_rename(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let names = [
+            "WhatsApp Image 2024-03-24 at 11.51.54 AM (2).jpeg",
+            "WhatsApp Image 2024-03-24 at 11.51.54 AM.jpeg",
+            "WhatsApp Image 2024-03-24 at 11.52.10 AM.jpeg",
+        ];
+        for name in names {
+            fs::write(dir.path().join(name), name)?;
+        }
+        fs::write(dir.path().join("trip.jpeg"), b"already there")?;
+        db::insert_images(&pool, &dir.path().to_string_lossy()).await?;
+        let image_ids: Vec<i64> = sqlx::query("select i.id from image i join library_file lf on lf.id = i.library_file_id where lf.original_file_name like 'WhatsApp%' order by lf.original_file_name")
+            .fetch_all(&pool)
+            .await?
+            .iter()
+            .map(|row| row.get("id"))
+            .collect();
+
+        let options = RenameOptions {
+            template: "trip".to_string(),
+            sequence_start: 1,
+            on_collision: OnCollision::AddSuffix,
+        };
+        let previews = preview_rename(&pool, &image_ids, &options).await?;
+        let new_names: Vec<&str> = previews
+            .iter()
+            .map(|preview| preview.new_name.as_str())
+            .collect();
+        assert_eq!(new_names, vec!["trip-2.jpeg", "trip-3.jpeg", "trip-4.jpeg"]);
+        assert!(previews.iter().all(|preview| preview.collision));
+        // A dry run doesn't touch the files
+        assert!(dir.path().join(names[0]).is_file());
+
+        let options = RenameOptions {
+            template: "trip_{seq:2}".to_string(),
+            sequence_start: 9,
+            on_collision: OnCollision::Skip,
+        };
+        fs::write(dir.path().join("trip_10.jpeg"), b"in the way")?;
+        let previews = batch_rename(&pool, &image_ids, &options).await?;
+        assert!(previews[1].skipped);
+        assert!(dir.path().join("trip_09.jpeg").is_file());
+        assert!(dir.path().join("trip_11.jpeg").is_file());
+        assert!(!dir.path().join(names[0]).exists());
+        assert!(!dir.path().join(names[2]).exists());
+        assert!(dir.path().join(names[1]).is_file());
+
+        let row = sqlx::query("select original_file_name, base_name from library_file lf join image i on i.library_file_id = lf.id where i.id=?")
+            .bind(image_ids[2])
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(row.get::<String, _>("original_file_name"), "trip_11.jpeg");
+        assert_eq!(row.get::<String, _>("base_name"), "trip_11");
+
+        // Messaging apps send files without an extension, the dot in the name stays
+        let no_extension = dir.path().join("IMG-20240324-WA0001");
+        fs::write(&no_extension, b"\xFF\xD8\xFF\xE0 a jpeg without extension")?;
+        db::insert_images(&pool, &dir.path().to_string_lossy()).await?;
+        let image_id: i64 = sqlx::query("select i.id from image i join library_file lf on lf.id = i.library_file_id where lf.original_file_name='IMG-20240324-WA0001'")
+            .fetch_one(&pool)
+            .await?
+            .get("id");
+        let options = RenameOptions {
+            template: "trip 11.51".to_string(),
+            sequence_start: 1,
+            on_collision: OnCollision::Skip,
+        };
+        let previews = batch_rename(&pool, &[image_id], &options).await?;
+        assert_eq!(previews[0].new_name, "trip 11.51");
+        assert!(dir.path().join("trip 11.51").is_file());
+        Ok(())
+    }
+}
@@ -0,0 +1,421 @@
+use crate::{
+    batch_rename,
+    db::{self, ImportOptions},
+    file_operations, image_helpers, media, missing_files, xmp,
+};
+use chrono::{DateTime, Utc};
+use sqlx::{Row, SqlitePool};
+use std::{
+    collections::{hash_map::Entry, BTreeSet, HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CopyImportOptions {
+    // Root of the organized tree the files are copied into
+    pub destination: PathBuf,
+    // Folders below the destination, with the date tokens of batch_rename, one template
+    // per level
+    #[serde(default = "default_folder_template")]
+    pub folder_template: String,
+    // A second copy, with the same folders, e.g. on another disk
+    #[serde(default)]
+    pub backup_destination: Option<PathBuf>,
+    // Leave out files whose content is in the catalog already
+    #[serde(default = "default_skip_duplicates")]
+    pub skip_duplicates: bool,
+    #[serde(default)]
+    pub import: ImportOptions,
+}
+
+fn default_folder_template() -> String {
+    "{year}/{year}-{month}-{day}".to_string()
+}
+
+fn default_skip_duplicates() -> bool {
+    true
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FailedCopy {
+    pub source: PathBuf,
+    pub error: String,
+}
+
+#[derive(serde::Serialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CopyImportResult {
+    // Where the imported files are now
+    pub copied: Vec<PathBuf>,
+    // Source files which were imported before
+    pub duplicates: Vec<PathBuf>,
+    // Files which couldn't be copied or whose copy didn't match. They aren't imported.
+    pub failed: Vec<FailedCopy>,
+}
+
+/// Imports from a memory card or any other folder by copying the files into the
+/// destination tree first. Every copy is checked against the checksum of its source,
+/// and the copies are what ends up in the catalog.
+pub async fn import_with_copy(
+    pool: &SqlitePool,
+    source: &Path,
+    options: &CopyImportOptions,
+) -> Result<CopyImportResult, sqlx::Error> {
+    let mut known: HashSet<(i64, String)> = HashSet::new();
+    if options.skip_duplicates {
+        known = sqlx::query("select file_size, hash from library_file where hash is not null")
+            .fetch_all(pool)
+            .await?
+            .iter()
+            .map(|row| (row.get("file_size"), row.get("hash")))
+            .collect();
+    }
+
+    let mut result = CopyImportResult::default();
+    let mut folders = BTreeSet::new();
+    let mut stems = HashMap::new();
+    for file in missing_files::files_under(source) {
+        if !image_helpers::is_importable_file(&file) {
+            continue;
+        }
+        // A file which can't be read, e.g. on a failing card, doesn't stop the others
+        let read = image_helpers::file_hash(&file)
+            .and_then(|hash| Ok((hash, fs::metadata(&file)?.len() as i64)));
+        let (hash, size) = match read {
+            Ok(read) => read,
+            Err(err) => {
+                result.failed.push(FailedCopy {
+                    source: file,
+                    error: err.to_string(),
+                });
+                continue;
+            }
+        };
+        // Also catches the same picture twice on the card
+        if !known.insert((size, hash.clone())) {
+            result.duplicates.push(file);
+            continue;
+        }
+
+        match copy_file(&file, &hash, options, &mut stems) {
+            Ok(copied) => {
+                if let Some(folder) = copied.parent() {
+                    folders.insert(folder.to_path_buf());
+                }
+                result.copied.push(copied);
+            }
+            Err(err) => result.failed.push(FailedCopy {
+                source: file,
+                error: err.to_string(),
+            }),
+        }
+    }
+
+    for folder in folders {
+        db::insert_images_with_options(pool, &folder.to_string_lossy(), &options.import).await?;
+    }
+    Ok(result)
+}
+
+// Copies a file and its sidecar into the destination, and into the backup when there is
+// one. Returns the path of the copy. stems holds the base name each group of files on
+// the card got in a destination folder.
+fn copy_file(
+    file: &Path,
+    hash: &str,
+    options: &CopyImportOptions,
+    stems: &mut HashMap<(PathBuf, PathBuf), String>,
+) -> Result<PathBuf, io::Error> {
+    let name = file
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "file without a name"))?;
+    let stem = file_operations::file_stem(file);
+    let capture_time = capture_time(file);
+    let mut folder = PathBuf::new();
+    for level in options.folder_template.split('/') {
+        let level = batch_rename::render_for_file(level, &stem, capture_time)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+        if !level.is_empty() {
+            folder.push(level);
+        }
+    }
+
+    let destination = options.destination.join(&folder);
+    fs::create_dir_all(&destination)?;
+    let mut targets = vec![destination.clone()];
+    if let Some(backup) = &options.backup_destination {
+        let backup = backup.join(&folder);
+        fs::create_dir_all(&backup)?;
+        targets.push(backup);
+    }
+    let new_stem = match stems.entry((file.with_file_name(&stem), folder)) {
+        Entry::Occupied(entry) => entry.get().clone(),
+        Entry::Vacant(entry) => entry.insert(free_stem(file, &targets)?).clone(),
+    };
+    let renamed = |path: &Path, target: &Path| {
+        file_operations::renamed_path(path, &stem, &new_stem)
+            .and_then(|path| path.file_name().map(|name| target.join(name)))
+    };
+
+    let sidecar = xmp::sidecar_path(file);
+    for target in &targets {
+        let copy = renamed(file, target).unwrap_or_else(|| target.join(name));
+        file_operations::refuse_existing(&copy)?;
+        copy_verified(file, &copy, hash)?;
+        // The sidecar of a RAW+JPEG pair comes along with both files, the second time
+        // it's there already
+        if let Some(sidecar) = &sidecar {
+            match renamed(sidecar, target) {
+                Some(sidecar_copy) if !sidecar_copy.exists() => {
+                    fs::copy(sidecar, sidecar_copy)?;
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(renamed(file, &destination).unwrap_or_else(|| destination.join(name)))
+}
+
+// The files on the card which belong together, e.g. RAW, JPEG and sidecar, keep a common
+// base name in the destination, so that they are still one image there. When any of
+// their names is taken by something else, in the destination or the backup, they all get
+// the same suffix. A sidecar with the same content doesn't count, it's from this group.
+fn free_stem(file: &Path, targets: &[PathBuf]) -> Result<String, io::Error> {
+    let stem = file_operations::file_stem(file);
+    let prefix = format!("{stem}.");
+    let group: Vec<PathBuf> = fs::read_dir(file.parent().unwrap_or(Path::new(".")))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with(&prefix))
+        })
+        .collect();
+    let taken = |member: &Path, new_stem: &str| {
+        let Some(new_name) = file_operations::renamed_path(member, &stem, new_stem)
+            .and_then(|path| path.file_name().map(|name| name.to_os_string()))
+        else {
+            return false;
+        };
+        targets.iter().any(|target| {
+            let path = target.join(&new_name);
+            let is_sidecar = member
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("xmp"));
+            path.exists() && !(is_sidecar && fs::read(member).ok() == fs::read(&path).ok())
+        })
+    };
+    Ok(file_operations::numbered_paths(file)
+        .map(|candidate| file_operations::file_stem(&candidate))
+        .find(|new_stem| !group.iter().any(|member| taken(member, new_stem)))
+        .unwrap())
+}
+
+// A copy which doesn't match its source, e.g. because the card is failing, is removed
+fn copy_verified(source: &Path, destination: &Path, hash: &str) -> Result<(), io::Error> {
+    fs::copy(source, destination)?;
+    if image_helpers::file_hash(destination)? != hash {
+        let _ = fs::remove_file(destination);
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("checksum of {} doesn't match", destination.display()),
+        ));
+    }
+    Ok(())
+}
+
+// When the picture was taken, as the camera's clock had it. Cameras write the file
+// time too, which covers files exiv2 can't read.
+fn capture_time(path: &Path) -> Option<DateTime<Utc>> {
+    let from_exif = rexiv2::Metadata::new_from_path(path)
+        .ok()
+        .and_then(|meta| meta.get_tag_string("Exif.Photo.DateTimeOriginal").ok())
+        .and_then(|datetime| image_helpers::parse_capture_time(&datetime, None));
+    from_exif
+        .or_else(|| {
+            Some(path)
+                .filter(|path| media::media_type(path) == media::MediaType::Video)
+                .and_then(media::read_video_info)
+                .and_then(|video| video.creation_time)
+        })
+        .or_else(|| {
+            fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .map(DateTime::<Utc>::from)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    // Sets the file time the way the camera would have
+    fn write_shot(path: &Path, content: &[u8], timestamp: u64) -> io::Result<()> {
+        fs::write(path, content)?;
+        fs::File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(timestamp))
+    }
+
+    #[sqlx::test]
+    async fn test_import_with_copy(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let card = dir.path().join("card").join("DCIM").join("100FUJI");
+        fs::create_dir_all(&card)?;
+        // 2024-03-24 11:51:54 and 2024-03-25 08:00:00 UTC
+        write_shot(&card.join("DSCF0001.jpg"), b"first", 1_711_281_114)?;
+        fs::write(card.join("DSCF0001.xmp"), "<rdf:Description/>")?;
+        write_shot(&card.join("DSCF0002.jpg"), b"second", 1_711_353_600)?;
+        write_shot(
+            &card.join("DSCF0003.jpg"),
+            b"imported before",
+            1_711_353_600,
+        )?;
+        fs::write(card.join("notes.txt"), b"not an image")?;
+
+        let earlier = dir.path().join("earlier");
+        fs::create_dir(&earlier)?;
+        fs::write(earlier.join("IMG_0001.jpg"), b"imported before")?;
+        db::insert_images(&pool, &earlier.to_string_lossy()).await?;
+
+        let library = dir.path().join("library");
+        let backup = dir.path().join("backup");
+        let options = CopyImportOptions {
+            destination: library.clone(),
+            folder_template: default_folder_template(),
+            backup_destination: Some(backup.clone()),
+            skip_duplicates: true,
+            import: ImportOptions::default(),
+        };
+        let result = import_with_copy(&pool, &dir.path().join("card"), &options).await?;
+        let first = library.join("2024").join("2024-03-24").join("DSCF0001.jpg");
+        let second = library.join("2024").join("2024-03-25").join("DSCF0002.jpg");
+        assert_eq!(result.copied, vec![first.clone(), second.clone()]);
+        assert_eq!(result.duplicates, vec![card.join("DSCF0003.jpg")]);
+        assert!(result.failed.is_empty());
+        assert!(first.with_extension("xmp").is_file());
+        assert!(backup
+            .join("2024")
+            .join("2024-03-25")
+            .join("DSCF0002.jpg")
+            .is_file());
+        // The card is left alone
+        assert!(card.join("DSCF0001.jpg").is_file());
+
+        let row = sqlx::query("select lf.path from image i join library_file lf on lf.id = i.library_file_id where lf.original_file_name='DSCF0001.jpg'")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(row.get::<String, _>("path"), first.to_string_lossy());
+
+        // Importing the card, or the folder, again finds nothing new
+        let result = import_with_copy(&pool, &dir.path().join("card"), &options).await?;
+        assert!(result.copied.is_empty());
+        assert_eq!(result.duplicates.len(), 3);
+        db::insert_images(&pool, &first.parent().unwrap().to_string_lossy()).await?;
+        let row = sqlx::query("select count(*) as count from image")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(row.get::<i64, _>("count"), 3);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[sqlx::test]
+    async fn test_import_with_copy_unreadable_file(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let card = dir.path().join("card");
+        fs::create_dir(&card)?;
+        write_shot(&card.join("DSCF0001.jpg"), b"first", 1_711_281_114)?;
+        // Listed, but gone when it's read
+        std::os::unix::fs::symlink(card.join("gone.jpg"), card.join("DSCF0002.jpg"))?;
+
+        let library = dir.path().join("library");
+        let options = CopyImportOptions {
+            destination: library.clone(),
+            folder_template: default_folder_template(),
+            backup_destination: None,
+            skip_duplicates: true,
+            import: ImportOptions::default(),
+        };
+        let result = import_with_copy(&pool, &card, &options).await?;
+        assert_eq!(
+            result.copied,
+            vec![library.join("2024").join("2024-03-24").join("DSCF0001.jpg")]
+        );
+        assert_eq!(result.failed.len(), 1);
+        assert_eq!(result.failed[0].source, card.join("DSCF0002.jpg"));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_import_with_copy_sidecar_collision(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let card = dir.path().join("card");
+        fs::create_dir(&card)?;
+        write_shot(&card.join("DSCF0001.RAF"), b"raw", 1_711_281_114)?;
+        write_shot(&card.join("DSCF0001.jpg"), b"jpeg", 1_711_281_114)?;
+        fs::write(card.join("DSCF0001.xmp"), "<rdf:Description/>")?;
+
+        // Left over from something else
+        let library = dir.path().join("library");
+        let backup = dir.path().join("backup");
+        for root in [&library, &backup] {
+            let folder = root.join("2024").join("2024-03-24");
+            fs::create_dir_all(&folder)?;
+            fs::write(folder.join("DSCF0001.xmp"), "somebody else")?;
+        }
+        let options = CopyImportOptions {
+            destination: library.clone(),
+            folder_template: default_folder_template(),
+            backup_destination: Some(backup.clone()),
+            skip_duplicates: true,
+            import: ImportOptions::default(),
+        };
+        let result = import_with_copy(&pool, &card, &options).await?;
+        // The whole group moves out of the way, so that it's still one image
+        let folder = library.join("2024").join("2024-03-24");
+        assert_eq!(
+            result.copied,
+            vec![
+                folder.join("DSCF0001 (1).RAF"),
+                folder.join("DSCF0001 (1).jpg")
+            ]
+        );
+        for root in [&library, &backup] {
+            let folder = root.join("2024").join("2024-03-24");
+            assert_eq!(
+                fs::read_to_string(folder.join("DSCF0001.xmp"))?,
+                "somebody else"
+            );
+            // Once for the pair
+            assert_eq!(
+                fs::read_to_string(folder.join("DSCF0001 (1).xmp"))?,
+                "<rdf:Description/>"
+            );
+            assert!(!folder.join("DSCF0001 (2).xmp").exists());
+            assert!(folder.join("DSCF0001 (1).RAF").is_file());
+        }
+        let rows = sqlx::query("select lf.original_file_name, f.role from image_file f join library_file lf on lf.id = f.library_file_id order by lf.original_file_name")
+            .fetch_all(&pool)
+            .await?;
+        let files: Vec<(String, String)> = rows
+            .iter()
+            .map(|row| (row.get("original_file_name"), row.get("role")))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                ("DSCF0001 (1).RAF".to_string(), "primary".to_string()),
+                ("DSCF0001 (1).jpg".to_string(), "secondary".to_string()),
+                ("DSCF0001 (1).xmp".to_string(), "sidecar".to_string()),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,311 @@
+use crate::{
+    db::{self, Filter, Image},
+    file_operations,
+    xmp::WriteBackOutcome,
+};
+use sqlx::{Row, SqlitePool};
+use std::{collections::HashSet, path::PathBuf};
+
+/// The images being culled: a folder with the filter and sort order of the grid
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CullView {
+    pub folder: String,
+    pub sort_option: String,
+    pub sort_order: String,
+    pub filter: Filter,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase", tag = "type", content = "value")]
+pub enum CullAction {
+    Pick,
+    Reject,
+    Unflag,
+    Rating(u32),
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Advance {
+    Next,
+    Previous,
+    // Stay on the image, unless the action took it out of the view
+    Stay,
+}
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CullResult {
+    // None when there is nothing left in that direction
+    pub next: Option<Image>,
+    // Of the changed image, so that a sidecar conflict can be shown
+    pub write_back: WriteBackOutcome,
+}
+
+#[derive(serde::Serialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeletedRejects {
+    pub image_ids: Vec<i64>,
+    // Where the files went, empty when they were left in place
+    pub trashed: Vec<PathBuf>,
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderFlagCounts {
+    pub folder: String,
+    pub picked: i64,
+    pub rejected: i64,
+    pub unflagged: i64,
+}
+
+async fn get_view_images(pool: &SqlitePool, view: &CullView) -> Result<Vec<Image>, sqlx::Error> {
+    db::get_images_in_path(
+        pool,
+        &view.folder,
+        &view.sort_option,
+        &view.sort_order,
+        &view.filter,
+    )
+    .await
+}
+
+/// Applies the action to the image and returns the image to show next, in the order of
+/// the view as it was before the action. Images the action made disappear from the view,
+/// e.g. a rejected one when only picks are shown, are passed over.
+pub async fn cull(
+    pool: &SqlitePool,
+    view: &CullView,
+    image_path: &str,
+    action: CullAction,
+    advance: Advance,
+) -> Result<CullResult, sqlx::Error> {
+    let before: Vec<String> = get_view_images(pool, view)
+        .await?
+        .into_iter()
+        .map(|image| image.path)
+        .collect();
+    let position = before
+        .iter()
+        .position(|path| path == image_path)
+        .ok_or(sqlx::Error::RowNotFound)?;
+
+    let write_back = match action {
+        CullAction::Pick => db::update_flag(pool, image_path, "picked").await?,
+        CullAction::Reject => db::update_flag(pool, image_path, "rejected").await?,
+        CullAction::Unflag => db::update_flag(pool, image_path, "unpicked").await?,
+        CullAction::Rating(rating) => db::update_image_rating(pool, image_path, rating).await?,
+    };
+
+    let after = get_view_images(pool, view).await?;
+    let visible: HashSet<&str> = after.iter().map(|image| image.path.as_str()).collect();
+    let mut next = before[position + 1..].iter();
+    let mut previous = before[..position].iter().rev();
+    let target = match advance {
+        Advance::Next => next.find(|path| visible.contains(path.as_str())),
+        Advance::Previous => previous.find(|path| visible.contains(path.as_str())),
+        Advance::Stay => std::iter::once(&before[position])
+            .chain(next)
+            .chain(previous)
+            .find(|path| visible.contains(path.as_str())),
+    }
+    .cloned();
+    Ok(CullResult {
+        next: target.and_then(|target| after.into_iter().find(|image| image.path == target)),
+        write_back,
+    })
+}
+
+/// Takes the rejected images of the folder out of the catalog. Their files go to the
+/// trash when move_to_trash is set, and stay where they are otherwise.
+pub async fn delete_rejected(
+    pool: &SqlitePool,
+    folder: &str,
+    move_to_trash: bool,
+) -> Result<DeletedRejects, sqlx::Error> {
+    let image_ids: Vec<i64> = sqlx::query(
+        "select i.id from image i join library_file lf on lf.id = i.library_file_id where lf.parent_path=? and i.flag='rejected' order by i.id",
+    )
+    .bind(folder)
+    .fetch_all(pool)
+    .await?
+    .iter()
+    .map(|row| row.get("id"))
+    .collect();
+    let trashed = if move_to_trash {
+        file_operations::delete_images(pool, &image_ids).await?
+    } else {
+        file_operations::remove_images(pool, &image_ids).await?;
+        vec![]
+    };
+    Ok(DeletedRejects { image_ids, trashed })
+}
+
+/// Picked, rejected and unflagged images of every folder of the catalog
+pub async fn get_flag_counts(pool: &SqlitePool) -> Result<Vec<FolderFlagCounts>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"select lf.parent_path,
+        sum(i.flag = 'picked') as picked,
+        sum(i.flag = 'rejected') as rejected,
+        sum(i.flag is null or i.flag not in ('picked', 'rejected')) as unflagged
+        from image i join library_file lf on lf.id = i.library_file_id
+        group by lf.parent_path order by lf.parent_path"#,
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .iter()
+        .map(|row| FolderFlagCounts {
+            folder: row.get("parent_path"),
+            picked: row.get("picked"),
+            rejected: row.get("rejected"),
+            unflagged: row.get("unflagged"),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::Path};
+
+    async fn import(pool: &SqlitePool, dir: &Path, names: &[&str]) -> sqlx::Result<()> {
+        for name in names {
+            fs::write(dir.join(name), name)?;
+        }
+        db::insert_images(pool, &dir.to_string_lossy()).await
+    }
+
+    fn view(dir: &Path, flag: &str) -> CullView {
+        CullView {
+            folder: dir.to_string_lossy().to_string(),
+            sort_option: "original_file_name".to_string(),
+            sort_order: "asc".to_string(),
+            filter: Filter {
+                flag: flag.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_cull(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        import(&pool, dir.path(), &["a.jpg", "b.jpg", "c.jpg", "d.jpg"]).await?;
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        let all = view(dir.path(), "unpicked");
+
+        let next = cull(&pool, &all, &path("a.jpg"), CullAction::Pick, Advance::Next).await?;
+        assert_eq!(next.next.map(|image| image.path), Some(path("b.jpg")));
+        assert_eq!(next.write_back, WriteBackOutcome::Disabled);
+        let stay = cull(
+            &pool,
+            &all,
+            &path("b.jpg"),
+            CullAction::Rating(3),
+            Advance::Stay,
+        )
+        .await?;
+        assert_eq!(stay.next.map(|image| image.rating), Some(3));
+
+        // Going through the picks, a rejected pick drops out of the view
+        let picks = view(dir.path(), "picked");
+        cull(&pool, &all, &path("c.jpg"), CullAction::Pick, Advance::Next).await?;
+        let previous = cull(
+            &pool,
+            &picks,
+            &path("c.jpg"),
+            CullAction::Reject,
+            Advance::Previous,
+        )
+        .await?;
+        assert_eq!(previous.next.map(|image| image.path), Some(path("a.jpg")));
+        let stay = cull(
+            &pool,
+            &picks,
+            &path("a.jpg"),
+            CullAction::Unflag,
+            Advance::Stay,
+        )
+        .await?;
+        assert!(stay.next.is_none());
+
+        let end = cull(
+            &pool,
+            &all,
+            &path("d.jpg"),
+            CullAction::Reject,
+            Advance::Next,
+        )
+        .await?;
+        assert!(end.next.is_none());
+        assert!(matches!(
+            cull(
+                &pool,
+                &picks,
+                &path("d.jpg"),
+                CullAction::Pick,
+                Advance::Next
+            )
+            .await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_delete_rejected(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let other = dir.path().join("other");
+        fs::create_dir(&other)?;
+        import(&pool, dir.path(), &["a.jpg", "b.jpg", "c.jpg"]).await?;
+        import(&pool, &other, &["d.jpg"]).await?;
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        db::update_flag(&pool, &path("a.jpg"), "picked").await?;
+        db::update_flag(&pool, &path("b.jpg"), "rejected").await?;
+        db::update_flag(&pool, &path("c.jpg"), "rejected").await?;
+
+        let folder = dir.path().to_string_lossy().to_string();
+        let counts = get_flag_counts(&pool).await?;
+        assert_eq!(
+            counts,
+            vec![
+                FolderFlagCounts {
+                    folder: folder.clone(),
+                    picked: 1,
+                    rejected: 2,
+                    unflagged: 0,
+                },
+                FolderFlagCounts {
+                    folder: other.to_string_lossy().to_string(),
+                    picked: 0,
+                    rejected: 0,
+                    unflagged: 1,
+                },
+            ]
+        );
+
+        // Out of the catalog only
+        let deleted = delete_rejected(&pool, &folder, false).await?;
+        assert_eq!(deleted.image_ids.len(), 2);
+        assert!(deleted.trashed.is_empty());
+        assert!(dir.path().join("b.jpg").is_file());
+
+        // And to the trash
+        db::insert_images(&pool, &folder).await?;
+        db::update_flag(&pool, &path("b.jpg"), "rejected").await?;
+        let deleted = delete_rejected(&pool, &folder, true).await?;
+        assert_eq!(
+            deleted.trashed,
+            vec![dir.path().join(".trash").join("b.jpg")]
+        );
+        assert!(!dir.path().join("b.jpg").exists());
+        let counts = get_flag_counts(&pool).await?;
+        assert_eq!(
+            (counts[0].picked, counts[0].rejected, counts[0].unflagged),
+            (1, 0, 1)
+        );
+        Ok(())
+    }
+}
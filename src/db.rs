@@ -1,7 +1,16 @@
+use crate::file_groups::{self, FileRole};
+use crate::geo::{self, BoundingBox};
 use crate::image_helpers;
+use crate::media;
+use crate::motion::{self, MotionKind};
+use crate::presets;
+use crate::root_folders;
+use crate::xmp;
 use chrono::prelude::{DateTime, Utc};
-use sqlx::{Execute, QueryBuilder, Row, Sqlite, SqlitePool, Transaction};
+use sqlx::{query_builder::Separated, Execute, QueryBuilder, Row, Sqlite, SqlitePool, Transaction};
 use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
     fs,
     path::{Path, PathBuf},
 };
@@ -12,22 +21,83 @@ pub struct Filter {
     pub rating: u32,
     pub flag: String,
     pub color_label: String,
+    // Some(true) only returns images with gps coordinates, Some(false) only the ones without
+    #[serde(default)]
+    pub has_location: Option<bool>,
+    #[serde(default)]
+    pub exif_ranges: Vec<ExifRange>,
+    // Only return the top image of collapsed stacks
+    #[serde(default)]
+    pub stack_tops_only: bool,
+}
+
+/// The typed exif values images can be filtered by
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ExifNumber {
+    ExposureSeconds,
+    FStop,
+    Iso,
+    FocalLength,
+    FocalLength35mm,
+}
+
+impl ExifNumber {
+    fn column(&self) -> &'static str {
+        match self {
+            ExifNumber::ExposureSeconds => "exposure_seconds",
+            ExifNumber::FStop => "f_stop",
+            ExifNumber::Iso => "iso",
+            ExifNumber::FocalLength => "focal_length_mm",
+            ExifNumber::FocalLength35mm => "focal_length_35mm",
+        }
+    }
+}
+
+/// Inclusive range on one of the typed exif values, e.g. "f/2.8 or wider" is
+/// { field: FStop, min: None, max: Some(2.8) }. Images without the value never match.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExifRange {
+    pub field: ExifNumber,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter {
+            rating: 0,
+            flag: "unpicked".to_string(),
+            color_label: "none".to_string(),
+            has_location: None,
+            exif_ranges: vec![],
+            stack_tops_only: false,
+        }
+    }
 }
 
 #[derive(sqlx::FromRow, serde::Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
-struct Image {
-    original_file_name: String,
-    extension: String,
-    file_created_time: String,
-    path: String,
-    parent_path: String,
-    rating: u32,
-    flag: String,
-    color_label: String,
-    capture_time: String,
-    file_width: u32,
-    file_height: u32,
+pub struct Image {
+    pub original_file_name: String,
+    pub extension: String,
+    pub file_created_time: String,
+    pub path: String,
+    pub parent_path: String,
+    pub rating: u32,
+    pub flag: String,
+    pub color_label: String,
+    pub capture_time: String,
+    pub file_width: Option<u32>,
+    pub file_height: Option<u32>,
+    pub stack_id: Option<i64>,
+    pub media_type: String,
+    pub duration_seconds: Option<f64>,
+    // Set by missing_files::check_missing_files
+    pub missing: bool,
+    // On a root which isn't connected. Everything but the file itself is still there.
+    pub offline: bool,
 }
 
 pub async fn has_images_for_path(
@@ -43,7 +113,7 @@ pub async fn has_images_for_path(
     Ok(has_images)
 }
 
-async fn get_images_in_path(
+pub async fn get_images_in_path(
     pool: &SqlitePool,
     path: &str,
     sort_option: &str,
@@ -53,28 +123,54 @@ async fn get_images_in_path(
     // We use json_group_array so that instead of getting multiple rows for each image tag,
     // we group all the tags for particular image into an array
     let mut query_builder = QueryBuilder::new(
-        r#"select *, json_group_array(t.tag_name) as tags from library_file as lf join image as i on lf.id == i.library_file_id left join tag as t on t.image_id = i.id
-        where lf.parent_path=? and i.rating >= ?"#,
+        r#"select *, json_group_array(t.tag_name) as tags, exists (select 1 from root_folder r where r.id = lf.root_folder_id and r.online = 0) as offline from library_file as lf join image as i on lf.id == i.library_file_id left join tag as t on t.image_id = i.id
+        where lf.parent_path="#,
     );
+    // Values are bound as we go, since which conditions are there depends on the filter
+    query_builder.push_bind(path);
+    query_builder.push(" and i.rating >= ");
+    query_builder.push_bind(filter.rating);
     if filter.flag != "unpicked" {
-        query_builder.push(" and i.flag=?");
+        query_builder.push(" and i.flag=");
+        query_builder.push_bind(&filter.flag);
     }
     if filter.color_label != "none" {
-        query_builder.push(" and i.color_label=?");
+        query_builder.push(" and i.color_label=");
+        query_builder.push_bind(&filter.color_label);
+    }
+    match filter.has_location {
+        Some(true) => {
+            query_builder.push(" and exists (select 1 from exif e where e.image_id = i.id and e.latitude is not null and e.longitude is not null)");
+        }
+        Some(false) => {
+            query_builder.push(" and not exists (select 1 from exif e where e.image_id = i.id and e.latitude is not null and e.longitude is not null)");
+        }
+        None => {}
+    }
+    for range in &filter.exif_ranges {
+        let column = range.field.column();
+        query_builder.push(format!(
+            " and exists (select 1 from exif e where e.image_id = i.id and e.{column} is not null"
+        ));
+        if let Some(min) = range.min {
+            query_builder.push(format!(" and e.{column} >= "));
+            query_builder.push_bind(min);
+        }
+        if let Some(max) = range.max {
+            query_builder.push(format!(" and e.{column} <= "));
+            query_builder.push_bind(max);
+        }
+        query_builder.push(")");
+    }
+    if filter.stack_tops_only {
+        query_builder.push(" and (i.stack_id is null or exists (select 1 from stack s where s.id = i.stack_id and (s.collapsed = 0 or s.top_image_id = i.id)))");
     }
     query_builder.push(" group by i.id");
 
     if sort_option != "default" {
         query_builder.push(" order by ".to_string() + sort_option + " " + sort_order);
     }
-    let mut query = query_builder.build_query_as::<Image>();
-    query = query.bind(path).bind(filter.rating);
-    if filter.flag != "unpicked" {
-        query = query.bind(&filter.flag);
-    }
-    if filter.color_label != "none" {
-        query = query.bind(&filter.color_label);
-    }
+    let query = query_builder.build_query_as::<Image>();
 
     println!("Query: {:?}", query.sql());
     let query_result = query.fetch_all(pool).await?;
@@ -90,6 +186,8 @@ pub struct DirContent {
     pub parent_path: String,
     pub extension: String,
     pub is_directory: bool,
+    // For a folder, whether it or a folder below it has been imported
+    pub in_catalog: bool,
 }
 
 #[derive(sqlx::FromRow, serde::Serialize, Debug, Default)]
@@ -102,58 +200,127 @@ struct LibraryFile {
     file_modified_time: String,
     path: String,
     parent_path: String,
+    detected_format: Option<String>,
+    file_size: i64,
+    hash: Option<String>,
+    // Offset and length of the video of a motion photo
+    embedded_video: Option<(u64, u64)>,
 }
 
 fn path_to_string(path: &Path) -> String {
     path.to_owned().to_string_lossy().to_owned().to_string()
 }
 
+fn library_file_from_path(path: &Path) -> Result<LibraryFile, std::io::Error> {
+    let name = path.file_name().unwrap().to_string_lossy().to_string();
+    // Is it better to keep parent_path as Path?
+    let parent_path = path.parent().unwrap();
+    let metadata = fs::metadata(path)?;
+    // Not every file system knows when a file was created
+    let created_time: DateTime<Utc> = metadata.created().or_else(|_| metadata.modified())?.into();
+    let modified_time: DateTime<Utc> = metadata.modified()?.into();
+    // How to convert rust SystemTime into ISO8601 string using chrono
+    // https://stackoverflow.com/a/64148017
+    // I have actually used the solution given by first comment in the answer
+    // So that i have something similar to what javascript toISOString() method returns
+    let file_created_time = created_time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+    let file_modified_time = modified_time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+    let detected_format = image_helpers::sniff_format(path);
+    // Every file is hashed once, here, and the hash is reused by the rest of the import
+    let (hash, embedded_video) = motion::hash_and_find_embedded_video(path, detected_format);
+    // metadata.
+    Ok(LibraryFile {
+        path: path_to_string(path),
+        parent_path: path_to_string(parent_path),
+        original_file_name: name,
+        base_name: path.file_stem().unwrap().to_string_lossy().to_string(),
+        // Files from messaging apps often come without one
+        extension: path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        file_created_time,
+        file_modified_time,
+        detected_format: detected_format.map(|format| format.to_string()),
+        // Used to find the file again if it's moved outside the app
+        file_size: metadata.len() as i64,
+        hash,
+        embedded_video,
+    })
+}
+
+/// Reads the exif of the image's primary file again, e.g. after another program changed
+/// the file. What we have is kept when the file can't be read.
+pub async fn refresh_exif(pool: &SqlitePool, image_id: i64) -> Result<(), sqlx::Error> {
+    let row = sqlx::query(
+        "select lf.path from image i join library_file lf on lf.id = i.library_file_id where i.id=?",
+    )
+    .bind(image_id)
+    .fetch_one(pool)
+    .await?;
+    let Ok(meta) = rexiv2::Metadata::new_from_path(Path::new(&row.get::<String, _>("path"))) else {
+        return Ok(());
+    };
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE from exif where image_id=?")
+        .bind(image_id)
+        .execute(&mut *tx)
+        .await?;
+    insert_exif_data(&mut tx, image_id, &meta).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+// The primary and secondary files of the images in the folder by file stem, with the
+// primary file first
+async fn get_folder_image_files(
+    pool: &SqlitePool,
+    parent_path: &str,
+) -> Result<HashMap<OsString, (i64, Vec<PathBuf>)>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"select f.image_id, lf.path from image_file f
+        join library_file lf on lf.id = f.library_file_id
+        where lf.parent_path=? and f.role in ('primary', 'secondary')
+        order by f.role = 'primary' desc, lf.path"#,
+    )
+    .bind(parent_path)
+    .fetch_all(pool)
+    .await?;
+    let mut images: HashMap<OsString, (i64, Vec<PathBuf>)> = HashMap::new();
+    for row in rows {
+        let path = PathBuf::from(row.get::<String, _>("path"));
+        let Some(stem) = path.file_stem().map(|stem| stem.to_os_string()) else {
+            continue;
+        };
+        images
+            .entry(stem)
+            .or_insert_with(|| (row.get("image_id"), vec![]))
+            .1
+            .push(path);
+    }
+    Ok(images)
+}
+
 /// function which returns the contents of a given directory
 /// The argument is a direct path to the directory
 fn get_dir_image_files(dir_path: &str) -> Result<Vec<LibraryFile>, std::io::Error> {
-    let entries = fs::read_dir(dir_path).expect("Could not read directory content");
+    let entries = fs::read_dir(dir_path)?;
     let entries = entries
         // Didn't know about filter_map being used to get value out of Option's inside
         // an iterator
         .filter_map(|entry| entry.ok())
-        .filter(|entry| !entry.metadata().unwrap().is_dir())
-        .filter(|entry| image_helpers::is_image_file(&entry.path()));
+        // An entry which is gone by now, e.g. a temporary file which was renamed, is
+        // left out
+        .filter(|entry| entry.metadata().is_ok_and(|metadata| !metadata.is_dir()))
+        .filter(|entry| image_helpers::is_importable_file(&entry.path()));
     let mut contents = vec![];
 
     for entry in entries {
-        let path = entry.path();
-        let name = entry.file_name().to_str().unwrap().to_string();
-        // Is it better to keep parent_path as Path?
-        let parent_path = path.parent().unwrap();
-        let metadata = fs::metadata(&path)?;
-        let created_time: DateTime<Utc> = metadata.created().unwrap().into();
-        let modified_time: DateTime<Utc> = metadata.modified().unwrap().into();
-        // How to convert rust SystemTime into ISO8601 string using chrono
-        // https://stackoverflow.com/a/64148017
-        // I have actually used the solution given by first comment in the answer
-        // So that i have something similar to what javascript toISOString() method returns
-        let file_created_time = created_time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
-        let file_modified_time = modified_time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
-        // metadata.
-        contents.push(LibraryFile {
-            path: path_to_string(&path),
-            parent_path: path_to_string(parent_path),
-            original_file_name: name,
-            base_name: path
-                .file_stem()
-                .unwrap()
-                .to_os_string()
-                .into_string()
-                .unwrap(),
-            extension: path
-                .extension()
-                .unwrap()
-                .to_os_string()
-                .into_string()
-                .unwrap(),
-            file_created_time,
-            file_modified_time,
-        });
+        match library_file_from_path(&entry.path()) {
+            Ok(file) => contents.push(file),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
     }
     Ok(contents)
 }
@@ -163,50 +330,27 @@ async fn insert_exif_data<'a>(
     image_id: i64,
     meta: &rexiv2::Metadata,
 ) -> Result<i64, sqlx::Error> {
-    // TODO: Move this vector to a global constant
-    let exif_columns = vec![
-        ("camera_make", "Exif.Image.Make"),
-        ("camera_model", "Exif.Image.Model"),
-        ("exposure_time", "Exif.Image.ExposureTime"),
-        ("f_number", "Exif.Image.FNumber"),
-        ("exposure_program", "Exif.Image.ExposureProgram"),
-        ("iso_speed", "Exif.Photo.ISOSpeed"),
-        ("exif_version", "Exif.Photo.ExifVersion"),
-        ("datetime_original", "Exif.Photo.DateTimeOriginal"),
-        ("offset_time_original", "Exif.Photo.OffsetTimeOriginal"),
-        ("shutter_speed", "Exif.Photo.ShutterSpeedValue"),
-        ("aperture_value", "Exif.Photo.ApertureValue"),
-        ("brightness_value", "Exif.Photo.BrightnessValue"),
-        ("metering_mode", "Exif.Photo.MeteringMode"),
-        ("flash", "Exif.Photo.Flash"),
-        ("exposure_mode", "Exif.Photo.ExposureMode"),
-        ("white_balance", "Exif.Photo.WhiteBalance"),
-        ("focal_length", "Exif.Photo.FocalLength"),
-        (
-            "focal_length_in_35mm_film",
-            "Exif.Photo.FocalLengthIn35mmFilm",
-        ),
-        ("sharpness", "Exif.Photo.Sharpness"),
-        ("lens_specification", "Exif.Photo.LensSpecification"),
-        ("lens_make", "Exif.Photo.LensMake"),
-        ("lens_model", "Exif.Photo.LensModel"),
-        ("body_serial_number", "Exif.Photo.BodySerialNumber"),
-        ("saturation", "Exif.Photo.Saturation"),
-        ("contrast", "Exif.Photo.Contrast"),
-        ("gps_latitude", "Exif.GPSInfo.GPSLatitude"),
-        ("gps_longitude", "Exif.GPSInfo.GPSLongitude"),
-        ("gps_altitude", "Exif.GPSInfo.GPSAltitude"),
-        ("gps_timestamp", "Exif.GPSInfo.GPSTimeStamp"),
-        ("gps_status", "Exif.GPSInfo.GPSStatus"),
-        ("artist", "Exif.Image.Artist"),
-    ];
     // Figuring the query builder part took me 2 days!
     let mut query_builder: QueryBuilder<Sqlite> = QueryBuilder::new("INSERT INTO exif (");
-    for exif_column in exif_columns.clone() {
-        if let Ok(_column_value) = meta.get_tag_string(exif_column.1) {
-            query_builder.push(exif_column.0.to_string() + ", ");
+    for exif_field in image_helpers::EXIF_FIELDS {
+        if let Ok(_column_value) = meta.get_tag_string(exif_field.tag) {
+            query_builder.push(exif_field.column.to_string() + ", ");
         }
     }
+    // The raw gps_latitude/gps_longitude strings can't be used for maps, so we also store
+    // the signed decimal coordinates computed from them and their Ref tags
+    let location = image_helpers::read_gps_location(meta);
+    if location.is_some() {
+        query_builder.push("latitude, longitude, altitude, ");
+    }
+    // Same for the exposure values, which are stored as numbers and display strings too
+    let exposure_values = image_helpers::ExposureValues::from_exif_columns(|column| {
+        image_helpers::EXIF_FIELDS
+            .iter()
+            .find(|field| field.column == column)
+            .and_then(|field| meta.get_tag_string(field.tag).ok())
+    });
+    query_builder.push(EXPOSURE_VALUE_COLUMNS.join(", ") + ", ");
     query_builder.push("image_id ");
     query_builder.push(" ) VALUES (");
     let mut separated = query_builder.separated(", ");
@@ -215,11 +359,17 @@ async fn insert_exif_data<'a>(
     // And building the (?) list and binding values to them in the second pass
     // because query_builder is mutably borrowed by both push and separated.push_bind methods
     // And we can only borrow it mutably once
-    for exif_column in exif_columns {
-        if let Ok(column_value) = meta.get_tag_string(exif_column.1) {
+    for exif_field in image_helpers::EXIF_FIELDS {
+        if let Ok(column_value) = meta.get_tag_string(exif_field.tag) {
             separated.push_bind(column_value);
         }
     }
+    if let Some(location) = location {
+        separated.push_bind(location.latitude);
+        separated.push_bind(location.longitude);
+        separated.push_bind(location.altitude);
+    }
+    push_exposure_values(&mut separated, &exposure_values);
     separated.push_bind(image_id);
     separated.push_unseparated(" )");
     let query = query_builder.build();
@@ -227,45 +377,91 @@ async fn insert_exif_data<'a>(
     Ok(query_result.last_insert_rowid())
 }
 
+const EXPOSURE_VALUE_COLUMNS: [&str; 9] = [
+    "exposure_seconds",
+    "f_stop",
+    "iso",
+    "focal_length_mm",
+    "focal_length_35mm",
+    "exposure_display",
+    "f_stop_display",
+    "iso_display",
+    "focal_length_display",
+];
+
+// Binds the values for EXPOSURE_VALUE_COLUMNS, in the same order
+fn push_exposure_values(
+    separated: &mut Separated<'_, '_, Sqlite, &'static str>,
+    values: &image_helpers::ExposureValues,
+) {
+    separated.push_bind(values.exposure_seconds);
+    separated.push_bind(values.f_stop);
+    separated.push_bind(values.iso);
+    separated.push_bind(values.focal_length_mm);
+    separated.push_bind(values.focal_length_35mm);
+    separated.push_bind(values.exposure_display());
+    separated.push_bind(values.f_stop_display());
+    separated.push_bind(values.iso_display());
+    separated.push_bind(values.focal_length_display());
+}
+
+// Reads an iptc field, preferring the IIM dataset and falling back to xmp. Fields with
+// several values are joined with IPTC_LIST_SEPARATOR.
+fn read_iptc_field(meta: &rexiv2::Metadata, field: &image_helpers::IptcField) -> Option<String> {
+    field
+        .iim
+        .into_iter()
+        .chain([field.xmp])
+        .filter_map(|tag| meta.get_tag_multiple_strings(tag).ok())
+        .map(|values| {
+            values
+                .iter()
+                .map(|value| xmp::strip_lang_alt(value).trim())
+                .filter(|value| !value.is_empty())
+                .collect::<Vec<_>>()
+                .join(image_helpers::IPTC_LIST_SEPARATOR)
+        })
+        .find(|value| !value.is_empty())
+}
+
 async fn insert_iptc_data<'a>(
     tx: &mut Transaction<'a, Sqlite>,
     image_id: i64,
     meta: &rexiv2::Metadata,
 ) -> Result<i64, sqlx::Error> {
-    // TODO: Move this vector to a global constant
-    let iptc_columns = vec![
-        ("copyright", "Iptc.Application2.Copyright"),
-        ("city", "Iptc.Application2.City"),
-        ("creator", "Iptc.Application2.Byline"),
-        ("country_iso_code", "Iptc.Application2.CountryCode"),
-        ("country_name", "Iptc.Application2.CountryName"),
-        ("description", "Iptc.Application2.Caption"),
-    ];
+    let values: Vec<(&str, String)> = image_helpers::IPTC_FIELDS
+        .iter()
+        .filter_map(|field| Some((field.column, read_iptc_field(meta, field)?)))
+        .collect();
     // Figuring the query builder part took me 2 days!
     let mut query_builder: QueryBuilder<Sqlite> = QueryBuilder::new("INSERT INTO iptc (");
-    for iptc_column in iptc_columns.clone() {
-        if let Ok(_column_value) = meta.get_tag_string(iptc_column.1) {
-            query_builder.push(iptc_column.0.to_string() + ", ");
-        }
+    for (column, _) in &values {
+        query_builder.push(column.to_string() + ", ");
     }
     query_builder.push("image_id ");
     query_builder.push(" ) VALUES (");
     let mut separated = query_builder.separated(", ");
-    // Why are we running the loop twice?
-    // Because we are building the column names list in the sql, e.g. (camera_make, lens_model) etc., in the first pass
-    // And building the (?) list and binding values to them in the second pass
-    // because query_builder is mutably borrowed by both push and separated.push_bind methods
-    // And we can only borrow it mutably once
-    for iptc_column in iptc_columns {
-        if let Ok(column_value) = meta.get_tag_string(iptc_column.1) {
-            separated.push_bind(column_value);
-        }
+    for (_, value) in values {
+        separated.push_bind(value);
     }
     separated.push_bind(image_id);
     separated.push_unseparated(" )");
     let query = query_builder.build();
     let query_result = query.execute(&mut **tx).await?;
-    Ok(query_result.last_insert_rowid())
+    let iptc_id = query_result.last_insert_rowid();
+
+    // IIM keywords go to the tag table like the xmp ones
+    for keyword in meta
+        .get_tag_multiple_strings("Iptc.Application2.Keywords")
+        .unwrap_or_default()
+    {
+        sqlx::query("INSERT OR IGNORE into tag (image_id, tag_name) values (?, ?)")
+            .bind(image_id)
+            .bind(keyword.trim())
+            .execute(&mut **tx)
+            .await?;
+    }
+    Ok(iptc_id)
 }
 
 async fn write_exif_and_iptc_to_db<'a>(
@@ -286,15 +482,27 @@ async fn write_exif_and_iptc_to_db<'a>(
         Err(_e) => {
             // When we can't read exif, we insert the file creation date as datetime_original
             // If we had exif, datetime_original would correspond to the date and time the
-            // photo was taken
-            let file_creation_time = library_file.file_created_time.clone();
-            let exif_query_result =
-                sqlx::query("Insert into exif (image_id, , datetime_original) values (?, ?, ?)")
-                    .bind(image_id)
-                    .bind(file_creation_time.to_string())
-                    .execute(&mut **tx)
-                    .await?;
-            let iptc_query_result = sqlx::query("Insert into iptc (image_id) values (?, ?, ?)")
+            // photo was taken. Videos carry their recording time and location in the
+            // container instead.
+            let video = Some(Path::new(image_path))
+                .filter(|path| media::media_type(path) == media::MediaType::Video)
+                .and_then(media::read_video_info);
+            let datetime_original = video
+                .and_then(|video| video.creation_time)
+                .map(|time| time.format("%Y:%m:%d %H:%M:%S").to_string())
+                .unwrap_or_else(|| library_file.file_created_time.clone());
+            let location = video.and_then(|video| video.location);
+            let exif_query_result = sqlx::query(
+                "Insert into exif (image_id, datetime_original, latitude, longitude, altitude) values (?, ?, ?, ?, ?)",
+            )
+            .bind(image_id)
+            .bind(datetime_original)
+            .bind(location.map(|location| location.latitude))
+            .bind(location.map(|location| location.longitude))
+            .bind(location.and_then(|location| location.altitude))
+            .execute(&mut **tx)
+            .await?;
+            let iptc_query_result = sqlx::query("Insert into iptc (image_id) values (?)")
                 .bind(image_id)
                 .execute(&mut **tx)
                 .await?;
@@ -310,7 +518,7 @@ async fn insert_library_file<'a>(
     tx: &mut Transaction<'a, Sqlite>,
     file: &LibraryFile,
 ) -> Result<i64, sqlx::Error> {
-    let query = sqlx::query("INSERT INTO library_file (original_file_name, base_name, extension, file_created_time, file_modified_time, path, parent_path) values (?, ?, ?, ?, ?, ?, ?)")
+    let query = sqlx::query("INSERT INTO library_file (original_file_name, base_name, extension, file_created_time, file_modified_time, path, parent_path, detected_format, file_size, hash) values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
         .bind(&file.original_file_name)
         .bind(&file.base_name)
         .bind(&file.extension)
@@ -318,8 +526,12 @@ async fn insert_library_file<'a>(
         .bind(&file.file_modified_time)
         .bind(&file.path)
         .bind(&file.parent_path)
+        .bind(&file.detected_format)
+        .bind(file.file_size)
+        .bind(&file.hash)
         .execute(&mut **tx)
         .await?;
+    root_folders::assign_root_folder(tx, query.last_insert_rowid()).await?;
     Ok(query.last_insert_rowid())
 }
 
@@ -340,40 +552,347 @@ async fn insert_image_details<'a>(
             image_capture_time = meta.get_tag_string("Exif.Image.DateTime").unwrap();
         }
     }
-    let query = sqlx::query("INSERT INTO image (library_file_id, capture_time) values (?, ?)")
+    let path = Path::new(&library_file.path);
+    let media_type = media::media_type(path);
+    // exiv2 doesn't read videos, their capture time comes from the container
+    let video = match media_type {
+        media::MediaType::Video => media::read_video_info(path),
+        media::MediaType::Image => None,
+    };
+    if let Some(creation_time) = video.and_then(|video| video.creation_time) {
+        image_capture_time = creation_time.format("%Y:%m:%d %H:%M:%S").to_string();
+    }
+    // Format and dimensions come from the file header, which we can read even for files
+    // exiv2 doesn't understand
+    let header = image_helpers::read_image_header(path);
+    // Most raw formats read as tiff, the extension is what tells a nef from a dng
+    let file_format = if image_helpers::is_raw_image(path) {
+        library_file
+            .detected_format
+            .clone()
+            .filter(|format| format != "tiff")
+            .unwrap_or_else(|| library_file.extension.to_lowercase())
+    } else {
+        header
+            .map(|header| header.format)
+            .or(library_file.detected_format.as_deref())
+            .unwrap_or("unset")
+            .to_string()
+    };
+    let query = sqlx::query("INSERT INTO image (library_file_id, capture_time, file_format, file_width, file_height, media_type, duration_seconds) values (?, ?, ?, ?, ?, ?, ?)")
         .bind(library_file_id)
         .bind(image_capture_time)
+        .bind(file_format)
+        .bind(header.map(|header| header.width))
+        .bind(header.map(|header| header.height))
+        .bind(media_type.as_str())
+        .bind(video.and_then(|video| video.duration_seconds))
         .execute(&mut **tx)
         .await?;
 
     Ok(query.last_insert_rowid())
 }
 
+#[derive(serde::Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtensionMismatch {
+    pub library_file_id: i64,
+    pub image_id: i64,
+    pub path: String,
+    // Empty for files without an extension
+    pub extension: String,
+    pub detected_format: String,
+    // Extensions which would fit the content
+    pub expected_extensions: Vec<&'static str>,
+}
+
+/// Files whose extension doesn't fit what they contain, e.g. a jpeg named .png or a
+/// file without an extension
+pub async fn get_extension_mismatches(
+    pool: &SqlitePool,
+) -> Result<Vec<ExtensionMismatch>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"select lf.id, f.image_id, lf.path, lf.extension, lf.detected_format from library_file lf
+        join image_file f on f.library_file_id = lf.id
+        where lf.detected_format is not null
+        order by lf.path"#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .iter()
+        .filter_map(|row| {
+            let path = row.get::<String, _>("path");
+            let detected_format = row.get::<String, _>("detected_format");
+            if image_helpers::extension_matches_format(Path::new(&path), &detected_format) {
+                return None;
+            }
+            Some(ExtensionMismatch {
+                library_file_id: row.get("id"),
+                image_id: row.get("image_id"),
+                path,
+                extension: row.get("extension"),
+                expected_extensions: image_helpers::format_extensions(&detected_format).to_vec(),
+                detected_format,
+            })
+        })
+        .collect())
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportOptions {
+    // Metadata preset applied to every imported image, see presets.rs
+    #[serde(default)]
+    pub preset_id: Option<i64>,
+}
+
 // Find all images in the given path, extract exif and other metadata for the images and
 // insert into the database
 pub async fn insert_images(pool: &SqlitePool, path: &str) -> Result<(), sqlx::Error> {
+    insert_images_with_options(pool, path, &ImportOptions::default()).await
+}
+
+pub async fn insert_images_with_options(
+    pool: &SqlitePool,
+    path: &str,
+    options: &ImportOptions,
+) -> Result<(), sqlx::Error> {
+    let preset = match options.preset_id {
+        Some(preset_id) => Some(presets::get_preset(pool, preset_id).await?),
+        None => None,
+    };
+    let primary_preference = file_groups::get_primary_preference(pool).await?;
     // We will run all insert queries inside a transaction so that inserts are fast
     match get_dir_image_files(path) {
-        Ok(dir_image_files) => {
+        Ok(mut dir_image_files) => {
+            // Files which are already in the catalog, e.g. when a folder is imported again.
+            // Sidecars are added to it as they are claimed, so that each is inserted once.
+            let mut cataloged: HashSet<String> = HashSet::new();
+            let mut images = HashMap::new();
+            if let Some(first) = dir_image_files.first() {
+                cataloged = sqlx::query("select path from library_file where parent_path=?")
+                    .bind(&first.parent_path)
+                    .fetch_all(pool)
+                    .await?
+                    .iter()
+                    .map(|row| row.get::<String, _>("path"))
+                    .collect();
+                images = get_folder_image_files(pool, &first.parent_path).await?;
+                dir_image_files.retain(|file| !cataloged.contains(&file.path));
+            }
+            // A file shot together with an image we have already, e.g. the JPEG of a RAW
+            // which was imported while the JPEG was still being copied, joins that image
+            let (late_files, new_files): (Vec<_>, Vec<_>) =
+                dir_image_files.into_iter().partition(|file| {
+                    let path = Path::new(&file.path);
+                    path.file_stem()
+                        .and_then(|stem| images.get(stem))
+                        .is_some_and(|(_, files)| {
+                            image_helpers::is_image_file(path)
+                                && std::iter::once(path)
+                                    .chain(files.iter().map(PathBuf::as_path))
+                                    .any(image_helpers::is_raw_image)
+                        })
+                });
             let mut conn = pool.begin().await?;
+            let mut attached: Vec<(i64, Vec<PathBuf>, i64, PathBuf)> = vec![];
+            for file in late_files {
+                let stem = Path::new(&file.path).file_stem().unwrap_or_default();
+                let Some((image_id, files)) = images.get_mut(stem) else {
+                    continue;
+                };
+                let library_file_id = insert_library_file(&mut conn, &file).await?;
+                file_groups::insert_image_file(
+                    &mut conn,
+                    *image_id,
+                    library_file_id,
+                    FileRole::Secondary,
+                )
+                .await?;
+                attached.push((
+                    *image_id,
+                    files.clone(),
+                    library_file_id,
+                    PathBuf::from(&file.path),
+                ));
+                files.push(PathBuf::from(&file.path));
+            }
+            // Sidecars which showed up next to images we have, e.g. written by another
+            // program or along with a late file
+            for (image_id, files) in images.values() {
+                let mut has_new_sidecar = false;
+                for file in files {
+                    let Some(sidecar) = xmp::sidecar_path(file) else {
+                        continue;
+                    };
+                    if cataloged.insert(path_to_string(&sidecar)) {
+                        let sidecar_id =
+                            insert_library_file(&mut conn, &library_file_from_path(&sidecar)?)
+                                .await?;
+                        file_groups::insert_image_file(
+                            &mut conn,
+                            *image_id,
+                            sidecar_id,
+                            FileRole::Sidecar,
+                        )
+                        .await?;
+                        has_new_sidecar = true;
+                    }
+                }
+                if has_new_sidecar {
+                    xmp::write_xmp_to_db(&mut conn, *image_id, &xmp::read_xmp(&files[0])).await?;
+                    xmp::record_sync_state(&mut *conn, *image_id, &xmp::metadata_source(&files[0]))
+                        .await?;
+                }
+            }
+            // RAW+JPEG pairs become one image, the other files are stored as secondary
+            // files of the primary one
+            let groups = file_groups::group_files(
+                new_files,
+                |file| Path::new(&file.path),
+                primary_preference,
+            );
+            // The mov of a Live Photo is part of the still, not an image of its own
+            let groups = motion::pair_live_photos(groups, |file| Path::new(&file.path));
             // todo
             // First insert library_file
             // Then insert image
             // Then insert exif
             // Then insert iptc
-            for file in dir_image_files {
+            for (group, live_video) in groups {
+                let file = &group.primary;
                 // println!("{file:?}");
-                let library_file_id = insert_library_file(&mut conn, &file).await?;
-                let image_id = insert_image_details(&mut conn, library_file_id, &file).await?;
-                write_exif_and_iptc_to_db(&mut conn, &file, image_id).await?;
+                let library_file_id = insert_library_file(&mut conn, file).await?;
+                let image_id = insert_image_details(&mut conn, library_file_id, file).await?;
+                file_groups::insert_image_file(
+                    &mut conn,
+                    image_id,
+                    library_file_id,
+                    FileRole::Primary,
+                )
+                .await?;
+                write_exif_and_iptc_to_db(&mut conn, file, image_id).await?;
+                // Ratings, labels and keywords set in other applications live in xmp,
+                // either embedded or in a sidecar next to the image
+                let xmp = xmp::read_xmp(Path::new(&file.path));
+                xmp::write_xmp_to_db(&mut conn, image_id, &xmp).await?;
+                // IMG_0001.jpg and IMG_0001.jpeg find the same IMG_0001.xmp, the first one
+                // gets it
+                let mut sidecars: Vec<LibraryFile> = vec![];
+                for member in std::iter::once(file).chain(&group.secondary) {
+                    if let Some(sidecar) = xmp::sidecar_path(Path::new(&member.path)) {
+                        if cataloged.insert(path_to_string(&sidecar)) {
+                            sidecars.push(library_file_from_path(&sidecar)?);
+                        }
+                    }
+                }
+                let metadata_source = xmp::metadata_source(Path::new(&file.path));
+                let known_hash = std::iter::once(file)
+                    .chain(&sidecars)
+                    .find(|known| Path::new(&known.path) == metadata_source)
+                    .and_then(|known| known.hash.as_deref());
+                match known_hash {
+                    Some(hash) => {
+                        xmp::record_sync_state_with_hash(
+                            &mut *conn,
+                            image_id,
+                            &metadata_source,
+                            hash,
+                        )
+                        .await?
+                    }
+                    None => xmp::record_sync_state(&mut *conn, image_id, &metadata_source).await?,
+                }
+                if let Some(preset) = &preset {
+                    presets::apply_preset_to_image(&mut conn, preset, image_id).await?;
+                }
+
+                for secondary in &group.secondary {
+                    let library_file_id = insert_library_file(&mut conn, secondary).await?;
+                    file_groups::insert_image_file(
+                        &mut conn,
+                        image_id,
+                        library_file_id,
+                        FileRole::Secondary,
+                    )
+                    .await?;
+                }
+                for sidecar in &sidecars {
+                    let library_file_id = insert_library_file(&mut conn, sidecar).await?;
+                    file_groups::insert_image_file(
+                        &mut conn,
+                        image_id,
+                        library_file_id,
+                        FileRole::Sidecar,
+                    )
+                    .await?;
+                }
+                if let Some(video) = &live_video {
+                    let video_path = Path::new(&video.path);
+                    let video_file_id = insert_library_file(&mut conn, video).await?;
+                    file_groups::insert_image_file(
+                        &mut conn,
+                        image_id,
+                        video_file_id,
+                        FileRole::Motion,
+                    )
+                    .await?;
+                    motion::insert_motion_component(
+                        &mut conn,
+                        image_id,
+                        MotionKind::LivePhoto,
+                        video_file_id,
+                        (0, fs::metadata(video_path)?.len()),
+                        media::read_content_identifier(video_path).as_deref(),
+                    )
+                    .await?;
+                } else if let Some(video) = file.embedded_video {
+                    motion::insert_motion_component(
+                        &mut conn,
+                        image_id,
+                        MotionKind::MotionPhoto,
+                        library_file_id,
+                        video,
+                        None,
+                    )
+                    .await?;
+                }
             }
             conn.commit().await?;
+            // The late file can be the one the preference wants shown
+            for (image_id, mut files, library_file_id, path) in attached {
+                files.push(path.clone());
+                let preferred =
+                    file_groups::preferred_file(&files, PathBuf::as_path, primary_preference);
+                if preferred == Some(&path) {
+                    file_groups::set_primary_file(pool, image_id, library_file_id).await?;
+                }
+            }
             Ok(())
         }
-        Err(err) => return Ok(()),
+        Err(err) => Err(err.into()),
     }
 }
 
+pub async fn get_setting(pool: &SqlitePool, key: &str) -> Result<Option<String>, sqlx::Error> {
+    let row = sqlx::query("SELECT value from setting where key=?")
+        .bind(key)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.map(|row| row.get::<String, _>("value")))
+}
+
+pub async fn set_setting(pool: &SqlitePool, key: &str, value: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO setting (key, value) values (?, ?) ON CONFLICT(key) DO UPDATE SET value=excluded.value")
+        .bind(key)
+        .bind(value)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
 pub async fn get_keywords(pool: &SqlitePool) -> Result<Vec<String>, sqlx::Error> {
     let rows = sqlx::query("SELECT DISTINCT tag_name from tag")
         .fetch_all(pool)
@@ -395,11 +914,35 @@ async fn get_image_id_from_path(pool: &SqlitePool, image_path: &str) -> Result<u
     Ok(row.get::<u32, _>("id"))
 }
 
+// Keeps the xmp of an image in step with the catalog when write back is turned on. A
+// sidecar changed by another program is left alone and reported as a conflict, see
+// xmp::write_back_image, and one which can't be written is reported as failed. Paths
+// which aren't in the catalog have nothing to write.
+async fn write_back_xmp(
+    pool: &SqlitePool,
+    image_path: &str,
+) -> Result<xmp::WriteBackOutcome, sqlx::Error> {
+    let mode = xmp::get_xmp_write_back(pool).await?;
+    if mode == xmp::XmpWriteBack::Off {
+        return Ok(xmp::WriteBackOutcome::Disabled);
+    }
+    let row = sqlx::query("Select image.id from image join library_file on image.library_file_id=library_file.id where library_file.path=$1")
+        .bind(image_path)
+        .fetch_optional(pool)
+        .await?;
+    match row {
+        Some(row) => {
+            xmp::failed_on_io(xmp::write_back_image(pool, row.get("id"), mode, false).await)
+        }
+        None => Ok(xmp::WriteBackOutcome::Disabled),
+    }
+}
+
 pub async fn add_keyword(
     pool: &SqlitePool,
     image_path: &str,
     keyword: &str,
-) -> Result<(), sqlx::Error> {
+) -> Result<xmp::WriteBackOutcome, sqlx::Error> {
     let image_id = get_image_id_from_path(pool, image_path).await?;
 
     sqlx::query("INSERT into tag (image_id, tag_name) values (?, ?)")
@@ -408,14 +951,14 @@ pub async fn add_keyword(
         .execute(pool)
         .await?;
 
-    Ok(())
+    write_back_xmp(pool, image_path).await
 }
 
 pub async fn remove_keyword(
     pool: &SqlitePool,
     image_path: &str,
     keyword: &str,
-) -> Result<(), sqlx::Error> {
+) -> Result<xmp::WriteBackOutcome, sqlx::Error> {
     let image_id = get_image_id_from_path(pool, image_path).await?;
 
     sqlx::query("DELETE from tag where image_id=$1 and tag_name=$2")
@@ -424,49 +967,218 @@ pub async fn remove_keyword(
         .execute(pool)
         .await?;
 
-    Ok(())
+    write_back_xmp(pool, image_path).await
 }
 
 pub async fn update_image_rating(
     pool: &SqlitePool,
     image_path: &str,
     rating: u32,
-) -> Result<(), sqlx::Error> {
+) -> Result<xmp::WriteBackOutcome, sqlx::Error> {
     sqlx::query("UPDATE image set rating=? from library_file lf where lf.id=image.library_file_id and lf.path=?")
         .bind(rating)
         .bind(image_path)
         .execute(pool)
         .await?;
 
-    Ok(())
+    write_back_xmp(pool, image_path).await
 }
 
 pub async fn update_color_label(
     pool: &SqlitePool,
     image_path: &str,
     color_label: &str,
-) -> Result<(), sqlx::Error> {
+) -> Result<xmp::WriteBackOutcome, sqlx::Error> {
     sqlx::query("UPDATE image set color_label=? from library_file lf where lf.id=image.library_file_id and lf.path=?")
         .bind(&color_label)
         .bind(&image_path)
         .execute(pool)
         .await?;
 
-    Ok(())
+    write_back_xmp(pool, image_path).await
 }
 
 pub async fn update_flag(
     pool: &SqlitePool,
     image_path: &str,
     flag: &str,
-) -> Result<(), sqlx::Error> {
+) -> Result<xmp::WriteBackOutcome, sqlx::Error> {
     sqlx::query("UPDATE image set flag=? from library_file lf where lf.id=image.library_file_id and lf.path=?")
         .bind(flag)
         .bind(image_path)
         .execute(pool)
         .await?;
 
-    Ok(())
+    write_back_xmp(pool, image_path).await
+}
+
+pub async fn get_exif(
+    pool: &SqlitePool,
+    image_id: i64,
+) -> Result<image_helpers::Exif, sqlx::Error> {
+    let exif = sqlx::query_as::<_, image_helpers::Exif>("SELECT * from exif where image_id=?")
+        .bind(image_id)
+        .fetch_one(pool)
+        .await?;
+    Ok(exif)
+}
+
+pub async fn get_iptc(
+    pool: &SqlitePool,
+    image_id: i64,
+) -> Result<image_helpers::Iptc, sqlx::Error> {
+    let iptc =
+        sqlx::query_as::<_, image_helpers::Iptc>("SELECT * from iptc where image_id=? limit 1")
+            .bind(image_id)
+            .fetch_one(pool)
+            .await?;
+    Ok(iptc)
+}
+
+/// Sets iptc fields on one or more images. Keys are iptc column names (see
+/// image_helpers::IPTC_FIELDS) and None clears the field. Fields which aren't in the map
+/// are left as they are. Returns the xmp write-back outcome of every image updated, so
+/// that conflicts can be shown.
+pub async fn update_iptc_fields(
+    pool: &SqlitePool,
+    image_ids: &[i64],
+    fields: &HashMap<String, Option<String>>,
+) -> Result<Vec<(i64, xmp::WriteBackOutcome)>, sqlx::Error> {
+    if let Some(column) = fields
+        .keys()
+        .find(|column| image_helpers::iptc_field_for_column(column).is_none())
+    {
+        return Err(sqlx::Error::ColumnNotFound(column.to_string()));
+    }
+    if fields.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut tx = pool.begin().await?;
+    for image_id in image_ids {
+        // Images imported without metadata may not have an iptc row yet
+        sqlx::query("INSERT INTO iptc (image_id) select ? where not exists (select 1 from iptc where image_id=?)")
+            .bind(image_id)
+            .bind(image_id)
+            .execute(&mut *tx)
+            .await?;
+
+        let mut query_builder: QueryBuilder<Sqlite> = QueryBuilder::new("UPDATE iptc set ");
+        let mut separated = query_builder.separated(", ");
+        for (column, value) in fields {
+            separated.push(format!("{} = ", column));
+            separated.push_bind_unseparated(value.as_ref().filter(|value| !value.is_empty()));
+        }
+        query_builder.push(" where image_id=");
+        query_builder.push_bind(image_id);
+        query_builder.build().execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+
+    let mut outcomes = vec![];
+    for image_id in image_ids {
+        outcomes.push((
+            *image_id,
+            xmp::write_back_if_enabled(pool, *image_id).await?,
+        ));
+    }
+    Ok(outcomes)
+}
+
+/// Recomputes the typed exposure values and display strings from the raw exif strings.
+/// Images imported before these columns existed only have the raw values.
+pub async fn update_exposure_values(pool: &SqlitePool) -> Result<u64, sqlx::Error> {
+    let rows = sqlx::query("SELECT * from exif").fetch_all(pool).await?;
+
+    let mut tx = pool.begin().await?;
+    let mut updated = 0;
+    for row in rows {
+        let values = image_helpers::ExposureValues::from_exif_columns(|column| {
+            row.try_get::<Option<String>, _>(column).ok().flatten()
+        });
+        let mut query_builder: QueryBuilder<Sqlite> = QueryBuilder::new("UPDATE exif set (");
+        query_builder.push(EXPOSURE_VALUE_COLUMNS.join(", "));
+        query_builder.push(") = (");
+        let mut separated = query_builder.separated(", ");
+        push_exposure_values(&mut separated, &values);
+        separated.push_unseparated(") where id=");
+        query_builder.push_bind(row.get::<i64, _>("id"));
+        let query_result = query_builder.build().execute(&mut *tx).await?;
+        updated += query_result.rows_affected();
+    }
+    tx.commit().await?;
+    Ok(updated)
+}
+
+#[derive(sqlx::FromRow, serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageLocation {
+    pub image_id: i64,
+    pub path: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f64>,
+}
+
+/// Returns all images whose gps coordinates fall inside the bounding box. Boxes crossing
+/// the antimeridian (min_longitude > max_longitude) are supported.
+pub async fn get_images_in_bounding_box(
+    pool: &SqlitePool,
+    bbox: &BoundingBox,
+) -> Result<Vec<ImageLocation>, sqlx::Error> {
+    let mut query_builder: QueryBuilder<Sqlite> = QueryBuilder::new(
+        r#"select i.id as image_id, lf.path, e.latitude, e.longitude, e.altitude from image as i
+        join library_file as lf on lf.id = i.library_file_id
+        join exif as e on e.image_id = i.id
+        where e.latitude between "#,
+    );
+    query_builder.push_bind(bbox.min_latitude);
+    query_builder.push(" and ");
+    query_builder.push_bind(bbox.max_latitude);
+    if bbox.crosses_antimeridian() {
+        query_builder.push(" and (e.longitude >= ");
+        query_builder.push_bind(bbox.min_longitude);
+        query_builder.push(" or e.longitude <= ");
+        query_builder.push_bind(bbox.max_longitude);
+        query_builder.push(")");
+    } else {
+        query_builder.push(" and e.longitude between ");
+        query_builder.push_bind(bbox.min_longitude);
+        query_builder.push(" and ");
+        query_builder.push_bind(bbox.max_longitude);
+    }
+    query_builder.push(" group by i.id");
+
+    let images = query_builder
+        .build_query_as::<ImageLocation>()
+        .fetch_all(pool)
+        .await?;
+    Ok(images)
+}
+
+/// Returns all images taken within radius_km of the given point, nearest first
+pub async fn get_images_within_radius(
+    pool: &SqlitePool,
+    latitude: f64,
+    longitude: f64,
+    radius_km: f64,
+) -> Result<Vec<ImageLocation>, sqlx::Error> {
+    // sqlite doesn't reliably have trigonometric functions, so we let the index narrow
+    // things down to the enclosing bounding box and do the exact distance check here
+    let bbox = BoundingBox::around(latitude, longitude, radius_km);
+    let mut images: Vec<(f64, ImageLocation)> = get_images_in_bounding_box(pool, &bbox)
+        .await?
+        .into_iter()
+        .map(|image| {
+            let distance =
+                geo::haversine_distance_km(latitude, longitude, image.latitude, image.longitude);
+            (distance, image)
+        })
+        .filter(|(distance, _)| *distance <= radius_km)
+        .collect();
+    images.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    Ok(images.into_iter().map(|(_, image)| image).collect())
 }
 
 #[cfg(test)]
@@ -474,6 +1186,7 @@ mod tests {
     use std::time::Instant;
 
     use super::*;
+    use crate::stacks;
     use sqlx::Row;
     use sqlx::SqlitePool;
 
@@ -492,6 +1205,7 @@ mod tests {
             rating: 0,
             flag: "unpicked".to_string(),
             color_label: "none".to_string(),
+            ..Default::default()
         };
         let images = get_images_in_path(
             &pool,
@@ -507,6 +1221,7 @@ mod tests {
             rating: 2,
             flag: "unpicked".to_string(),
             color_label: "none".to_string(),
+            ..Default::default()
         };
         let images = get_images_in_path(
             &pool,
@@ -523,6 +1238,7 @@ mod tests {
             rating: 3,
             flag: "unpicked".to_string(),
             color_label: "green".to_string(),
+            ..Default::default()
         };
         let images = get_images_in_path(
             &pool,
@@ -538,6 +1254,7 @@ mod tests {
             rating: 3,
             flag: "picked".to_string(),
             color_label: "green".to_string(),
+            ..Default::default()
         };
         let images = get_images_in_path(
             &pool,
@@ -551,6 +1268,101 @@ mod tests {
         Ok(())
     }
 
+    #[sqlx::test]
+    async fn test_insert_videos(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(
+            dir.path().join("clip.mp4"),
+            media::tests::sample_mp4(b"isom"),
+        )?;
+        fs::write(dir.path().join("IMG_0001.HEIC"), b"not a real heic")?;
+        let path = dir.path().to_string_lossy().to_string();
+        insert_images(&pool, &path).await?;
+
+        let images = get_images_in_path(&pool, &path, "default", "asc", &Filter::default()).await?;
+        assert_eq!(images.len(), 2);
+        let video = images
+            .iter()
+            .find(|image| image.media_type == "video")
+            .unwrap();
+        assert_eq!(video.duration_seconds, Some(12.5));
+        assert_eq!(
+            (video.file_width, video.file_height),
+            (Some(1920), Some(1080))
+        );
+        assert_eq!(video.capture_time, "2024:01:11 10:00:00");
+
+        let row = sqlx::query("select e.datetime_original, e.latitude from exif e join image i on i.id = e.image_id where i.media_type='video'")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(
+            row.get::<String, _>("datetime_original"),
+            "2024:01:11 10:00:00"
+        );
+        assert_eq!(row.get::<f64, _>("latitude"), 12.9716);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_extension_mismatches(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let jpeg = [0xFF, 0xD8, 0xFF, 0xE0, 0, 16, b'J', b'F', b'I', b'F', 0];
+        fs::write(dir.path().join("DSCF0001.jpg"), jpeg)?;
+        fs::write(dir.path().join("image (10).png"), jpeg)?;
+        // What messaging apps save, no extension at all
+        fs::write(dir.path().join("IMG-20240101-WA0001"), jpeg)?;
+        fs::write(dir.path().join("notes.txt"), b"not an image")?;
+        let path = dir.path().to_string_lossy().to_string();
+        insert_images(&pool, &path).await?;
+
+        let images = get_images_in_path(&pool, &path, "default", "asc", &Filter::default()).await?;
+        assert_eq!(images.len(), 3);
+        // The header is too short to parse, the sniffed format stands in
+        let row = sqlx::query("select count(*) as count from image where file_format='jpeg'")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(row.get::<i64, _>("count"), 3);
+
+        let mismatches = get_extension_mismatches(&pool).await?;
+        let names: Vec<(&str, &str)> = mismatches
+            .iter()
+            .map(|mismatch| {
+                (
+                    mismatch.extension.as_str(),
+                    mismatch.detected_format.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(names, vec![("", "jpeg"), ("png", "jpeg")]);
+        assert_eq!(mismatches[1].expected_extensions[0], "jpg");
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("library_file", "image", "exif", "iptc"))]
+    async fn test_get_stack_tops_only(pool: SqlitePool) -> sqlx::Result<()> {
+        let path = "/Users/fancy-name/Desktop";
+        let stack_id = stacks::create_stack(&pool, &[1, 2, 3], Some(2)).await?;
+        let filter = Filter {
+            stack_tops_only: true,
+            ..Default::default()
+        };
+        let images = get_images_in_path(&pool, path, "default", "asc", &filter).await?;
+        assert_eq!(images.len(), 14);
+        assert_eq!(
+            images
+                .iter()
+                .filter(|image| image.stack_id == Some(stack_id))
+                .count(),
+            1
+        );
+
+        // Expanded stacks show all their images
+        stacks::set_stack_collapsed(&pool, stack_id, false).await?;
+        let images = get_images_in_path(&pool, path, "default", "asc", &filter).await?;
+        assert_eq!(images.len(), 16);
+        Ok(())
+    }
+
     // Testing insert_images will need file system access
     // We need to have some dummy images in some folder inside our project maybe
     #[sqlx::test(fixtures("library_file", "image", "exif", "iptc"))]
@@ -561,6 +1373,7 @@ mod tests {
             rating: 0,
             flag: "unpicked".to_string(),
             color_label: "none".to_string(),
+            ..Default::default()
         };
         let images = get_images_in_path(&pool, &path, "default", "asc", &filter).await?;
         assert_eq!(images.len(), 0);
@@ -576,6 +1389,270 @@ mod tests {
         Ok(())
     }
 
+    #[sqlx::test]
+    async fn test_insert_images_shared_sidecar(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().to_string_lossy().to_string();
+        // Two images with the same stem and one sidecar
+        fs::write(dir.path().join("IMG_1.jpg"), b"one")?;
+        fs::write(dir.path().join("IMG_1.jpeg"), b"another")?;
+        fs::write(dir.path().join("IMG_1.xmp"), "<rdf:Description/>")?;
+        // A raw with a sidecar, and later its jpeg
+        fs::write(dir.path().join("IMG_2.RAF"), b"raw")?;
+        fs::write(dir.path().join("IMG_2.xmp"), "<rdf:Description/>")?;
+        insert_images(&pool, &path).await?;
+        fs::write(dir.path().join("IMG_2.JPG"), b"jpeg")?;
+        insert_images(&pool, &path).await?;
+
+        let rows = sqlx::query(
+            "select lf.original_file_name from library_file lf join image_file f on f.library_file_id = lf.id where f.role = 'sidecar' order by lf.original_file_name",
+        )
+        .fetch_all(&pool)
+        .await?;
+        let sidecars: Vec<String> = rows
+            .iter()
+            .map(|row| row.get("original_file_name"))
+            .collect();
+        assert_eq!(sidecars, vec!["IMG_1.xmp", "IMG_2.xmp"]);
+        let row = sqlx::query("select count(*) as count from library_file")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(row.get::<i64, _>("count"), 6);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_insert_images_without_metadata(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        // Not an image at all, exiv2 can't open it
+        fs::write(dir.path().join("unreadable.jpg"), b"definitely not a jpeg")?;
+        // A valid 1x1 png without any exif, iptc or xmp
+        let png: [u8; 67] = [
+            0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A, 0x00, 0x00, 0x00, 0x0D, 0x49, 0x48,
+            0x44, 0x52, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00,
+            0x00, 0x1F, 0x15, 0xC4, 0x89, 0x00, 0x00, 0x00, 0x0A, 0x49, 0x44, 0x41, 0x54, 0x78,
+            0x9C, 0x63, 0x00, 0x01, 0x00, 0x00, 0x05, 0x00, 0x01, 0x0D, 0x0A, 0x2D, 0xB4, 0x00,
+            0x00, 0x00, 0x00, 0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42, 0x60, 0x82,
+        ];
+        fs::write(dir.path().join("no_metadata.png"), png)?;
+
+        let path = dir.path().to_string_lossy().to_string();
+        insert_images(&pool, &path).await?;
+
+        let images = get_images_in_path(&pool, &path, "default", "asc", &Filter::default()).await?;
+        assert_eq!(images.len(), 2);
+
+        let rows = sqlx::query("select lf.original_file_name, i.file_format, i.file_width, i.file_height, e.datetime_original, lf.file_created_time from image i join library_file lf on lf.id = i.library_file_id join exif e on e.image_id = i.id join iptc on iptc.image_id = i.id order by lf.original_file_name")
+            .fetch_all(&pool)
+            .await?;
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            rows[0].get::<String, _>("original_file_name"),
+            "no_metadata.png"
+        );
+        assert_eq!(rows[0].get::<String, _>("file_format"), "png");
+        assert_eq!(rows[0].get::<u32, _>("file_width"), 1);
+        assert_eq!(rows[0].get::<u32, _>("file_height"), 1);
+        assert_eq!(rows[1].get::<String, _>("file_format"), "unset");
+        assert_eq!(rows[1].get::<Option<u32>, _>("file_width"), None);
+        // Without exif the file creation time stands in for the capture time
+        assert_eq!(
+            rows[1].get::<String, _>("datetime_original"),
+            rows[1].get::<String, _>("file_created_time")
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_insert_tiff_based_raw(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        // Just an IFD0 with 100x50, which is all a nef needs to look like a tiff
+        let mut nef = b"II*\0\x08\0\0\0\x02\0".to_vec();
+        nef.extend([0x00, 0x01, 3, 0, 1, 0, 0, 0, 100, 0, 0, 0]);
+        nef.extend([0x01, 0x01, 3, 0, 1, 0, 0, 0, 50, 0, 0, 0]);
+        nef.extend([0, 0, 0, 0]);
+        fs::write(dir.path().join("DSC_0001.NEF"), nef)?;
+        insert_images(&pool, &dir.path().to_string_lossy()).await?;
+
+        let row = sqlx::query("select file_format, file_width, file_height from image")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(row.get::<String, _>("file_format"), "nef");
+        assert_eq!(row.get::<u32, _>("file_width"), 100);
+        assert_eq!(row.get::<u32, _>("file_height"), 50);
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("library_file", "image", "exif", "iptc"))]
+    async fn test_get_exif(pool: SqlitePool) -> sqlx::Result<()> {
+        let exif = get_exif(&pool, 1).await?;
+        assert_eq!(exif.image_id, 1);
+        assert_eq!(exif.camera_make.as_deref(), Some("Fujifilm"));
+        assert_eq!(exif.lens_model.as_deref(), Some("85mm f/1.8"));
+        assert_eq!(exif.shutter_speed_value.as_deref(), Some("5.121"));
+        assert_eq!(exif.software, None);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_exif_fields_match_schema(pool: SqlitePool) -> sqlx::Result<()> {
+        let rows = sqlx::query("select name from pragma_table_info('exif')")
+            .fetch_all(&pool)
+            .await?;
+        let table_columns: Vec<String> = rows.iter().map(|row| row.get("name")).collect();
+
+        let mut registry_columns: Vec<&str> = image_helpers::EXIF_FIELDS
+            .iter()
+            .map(|field| field.column)
+            .chain(image_helpers::EXIF_DERIVED_COLUMNS.iter().copied())
+            .collect();
+        for column in &registry_columns {
+            assert!(
+                table_columns.iter().any(|c| c == column),
+                "exif table has no column {column}"
+            );
+        }
+
+        // Every metadata column in the table comes from the registry
+        registry_columns.extend(["id", "image_id", "created_at", "modified_at"]);
+        for column in &table_columns {
+            assert!(
+                registry_columns.contains(&column.as_str()),
+                "exif column {column} is not in EXIF_FIELDS"
+            );
+        }
+
+        // And the Exif struct has a field for each of them
+        let exif = serde_json::to_value(image_helpers::Exif::default()).unwrap();
+        let struct_fields = exif.as_object().unwrap();
+        assert_eq!(struct_fields.len(), table_columns.len() - 1);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_iptc_fields_match_schema(pool: SqlitePool) -> sqlx::Result<()> {
+        let rows = sqlx::query("select name from pragma_table_info('iptc')")
+            .fetch_all(&pool)
+            .await?;
+        let table_columns: Vec<String> = rows.iter().map(|row| row.get("name")).collect();
+
+        let mut registry_columns: Vec<&str> = image_helpers::IPTC_FIELDS
+            .iter()
+            .map(|field| field.column)
+            .collect();
+        for column in &registry_columns {
+            assert!(
+                table_columns.iter().any(|c| c == column),
+                "iptc table has no column {column}"
+            );
+        }
+        registry_columns.extend(["id", "image_id"]);
+        for column in &table_columns {
+            assert!(
+                registry_columns.contains(&column.as_str()),
+                "iptc column {column} is not in IPTC_FIELDS"
+            );
+        }
+
+        let iptc = serde_json::to_value(image_helpers::Iptc::default()).unwrap();
+        assert_eq!(iptc.as_object().unwrap().len(), table_columns.len() - 1);
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("library_file", "image", "exif", "iptc"))]
+    async fn test_update_iptc_fields(pool: SqlitePool) -> sqlx::Result<()> {
+        let fields = HashMap::from([
+            (
+                "headline".to_string(),
+                Some("Monsoon in Bengaluru".to_string()),
+            ),
+            ("persons_shown".to_string(), Some("Asha; Ravi".to_string())),
+            ("city".to_string(), None),
+        ]);
+        // Image 2 has no iptc row in the fixtures
+        let outcomes = update_iptc_fields(&pool, &[1, 2], &fields).await?;
+        assert_eq!(
+            outcomes,
+            vec![
+                (1, xmp::WriteBackOutcome::Disabled),
+                (2, xmp::WriteBackOutcome::Disabled)
+            ]
+        );
+
+        for image_id in [1, 2] {
+            let iptc = get_iptc(&pool, image_id).await?;
+            assert_eq!(iptc.headline.as_deref(), Some("Monsoon in Bengaluru"));
+            assert_eq!(iptc.persons_shown.as_deref(), Some("Asha; Ravi"));
+            assert_eq!(iptc.city, None);
+        }
+        // Fields which weren't given are kept
+        let iptc = get_iptc(&pool, 1).await?;
+        assert_eq!(iptc.creator.as_deref(), Some("Mukesh Soni"));
+
+        let fields = HashMap::from([("no_such_field".to_string(), None)]);
+        assert!(update_iptc_fields(&pool, &[1], &fields).await.is_err());
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("library_file", "image", "exif", "iptc"))]
+    async fn test_get_images_with_exif_ranges(pool: SqlitePool) -> sqlx::Result<()> {
+        sqlx::query("UPDATE exif set f_number='28/10', photographic_sensitivity='6400', exposure_time='1/250' where image_id in (1, 2, 3)")
+            .execute(&pool)
+            .await?;
+        sqlx::query("UPDATE exif set f_number='8', iso_speed='100' where image_id=4")
+            .execute(&pool)
+            .await?;
+        assert_eq!(update_exposure_values(&pool).await?, 20);
+
+        let exif = get_exif(&pool, 1).await?;
+        assert_eq!(exif.f_stop, Some(2.8));
+        assert_eq!(exif.exposure_display.as_deref(), Some("1/250 s"));
+        assert_eq!(exif.iso_display.as_deref(), Some("ISO 6400"));
+
+        let wide_open = ExifRange {
+            field: ExifNumber::FStop,
+            min: None,
+            max: Some(2.8),
+        };
+        let filter = Filter {
+            exif_ranges: vec![wide_open.clone()],
+            ..Default::default()
+        };
+        let images = get_images_in_path(
+            &pool,
+            "/Users/fancy-name/Desktop",
+            "default",
+            "asc",
+            &filter,
+        )
+        .await?;
+        // The rest of the fixture images are at f/2
+        assert_eq!(images.len(), 15);
+
+        let filter = Filter {
+            rating: 4,
+            exif_ranges: vec![
+                wide_open,
+                ExifRange {
+                    field: ExifNumber::Iso,
+                    min: Some(3200.0),
+                    max: None,
+                },
+            ],
+            ..Default::default()
+        };
+        let images = get_images_in_path(
+            &pool,
+            "/Users/fancy-name/Desktop",
+            "default",
+            "asc",
+            &filter,
+        )
+        .await?;
+        assert_eq!(images.len(), 2);
+        Ok(())
+    }
+
     #[sqlx::test(fixtures("library_file", "image", "exif", "iptc", "tag"))]
     async fn test_get_keywords(pool: SqlitePool) -> sqlx::Result<()> {
         let keywords = get_keywords(&pool).await?;
@@ -631,6 +1708,11 @@ mod tests {
             .await?;
         let flag = query_result.get::<String, _>("flag");
         assert_eq!(flag, "rejected");
+
+        // Not in the catalog, there is nothing to update or write
+        xmp::set_xmp_write_back(&pool, xmp::XmpWriteBack::Sidecar).await?;
+        let outcome = update_flag(&pool, "/Users/fancy-name/Desktop/unknown.jpg", "picked").await?;
+        assert_eq!(outcome, xmp::WriteBackOutcome::Disabled);
         Ok(())
     }
 
@@ -661,4 +1743,72 @@ mod tests {
         assert_eq!(color_label, "blue");
         Ok(())
     }
+
+    #[sqlx::test(fixtures("library_file", "image", "exif", "iptc", "gps"))]
+    async fn test_get_images_with_location_filter(pool: SqlitePool) -> sqlx::Result<()> {
+        let filter = Filter {
+            has_location: Some(true),
+            ..Default::default()
+        };
+        let images = get_images_in_path(
+            &pool,
+            "/Users/fancy-name/Desktop",
+            "default",
+            "asc",
+            &filter,
+        )
+        .await?;
+        assert_eq!(images.len(), 7);
+
+        let filter = Filter {
+            has_location: Some(false),
+            ..Default::default()
+        };
+        let images = get_images_in_path(
+            &pool,
+            "/Users/fancy-name/Desktop",
+            "default",
+            "asc",
+            &filter,
+        )
+        .await?;
+        assert_eq!(images.len(), 9);
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("library_file", "image", "exif", "iptc", "gps"))]
+    async fn test_get_images_in_bounding_box(pool: SqlitePool) -> sqlx::Result<()> {
+        // Roughly south India
+        let bbox = BoundingBox {
+            min_latitude: 8.0,
+            min_longitude: 74.0,
+            max_latitude: 15.0,
+            max_longitude: 81.0,
+        };
+        let images = get_images_in_bounding_box(&pool, &bbox).await?;
+        assert_eq!(images.len(), 4);
+
+        // Crossing the antimeridian, from Sydney eastwards to Alaska
+        let bbox = BoundingBox {
+            min_latitude: -40.0,
+            min_longitude: 150.0,
+            max_latitude: 70.0,
+            max_longitude: -140.0,
+        };
+        let images = get_images_in_bounding_box(&pool, &bbox).await?;
+        assert_eq!(images.len(), 2);
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("library_file", "image", "exif", "iptc", "gps"))]
+    async fn test_get_images_within_radius(pool: SqlitePool) -> sqlx::Result<()> {
+        let images = get_images_within_radius(&pool, 12.97, 77.59, 10.0).await?;
+        assert_eq!(images.len(), 3);
+
+        // Chennai is ~290km from Bengaluru
+        let images = get_images_within_radius(&pool, 12.97, 77.59, 300.0).await?;
+        assert_eq!(images.len(), 4);
+        assert_eq!(images.last().unwrap().image_id, 4);
+        Ok(())
+    }
 }
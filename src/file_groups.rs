@@ -0,0 +1,364 @@
+use crate::{db, image_helpers};
+use sqlx::{Row, Sqlite, SqlitePool, Transaction};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+const PRIMARY_PREFERENCE_SETTING: &str = "raw_jpeg_primary";
+
+/// Which file of a RAW+JPEG pair is shown and exported
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum PrimaryPreference {
+    Raw,
+    Jpeg,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum FileRole {
+    Primary,
+    Secondary,
+    Sidecar,
+    // The video of a Live Photo
+    Motion,
+}
+
+impl FileRole {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FileRole::Primary => "primary",
+            FileRole::Secondary => "secondary",
+            FileRole::Sidecar => "sidecar",
+            FileRole::Motion => "motion",
+        }
+    }
+
+    fn from_db(value: &str) -> FileRole {
+        match value {
+            "primary" => FileRole::Primary,
+            "sidecar" => FileRole::Sidecar,
+            "motion" => FileRole::Motion,
+            _ => FileRole::Secondary,
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageFile {
+    pub library_file_id: i64,
+    pub path: String,
+    pub extension: String,
+    pub role: FileRole,
+}
+
+/// Files which make up one image. Secondary files are everything else shot together
+/// with the primary one, e.g. the JPEG of a RAW+JPEG pair.
+#[derive(Debug, PartialEq)]
+pub struct FileGroup<T> {
+    pub primary: T,
+    pub secondary: Vec<T>,
+}
+
+pub async fn get_primary_preference(pool: &SqlitePool) -> Result<PrimaryPreference, sqlx::Error> {
+    let value = db::get_setting(pool, PRIMARY_PREFERENCE_SETTING).await?;
+    Ok(match value.as_deref() {
+        Some("jpeg") => PrimaryPreference::Jpeg,
+        _ => PrimaryPreference::Raw,
+    })
+}
+
+/// Stores the preference and switches the primary file of the pairs already in the
+/// catalog to match. Returns the number of images whose primary file changed.
+pub async fn set_primary_preference(
+    pool: &SqlitePool,
+    preference: PrimaryPreference,
+) -> Result<u64, sqlx::Error> {
+    let value = match preference {
+        PrimaryPreference::Raw => "raw",
+        PrimaryPreference::Jpeg => "jpeg",
+    };
+    db::set_setting(pool, PRIMARY_PREFERENCE_SETTING, value).await?;
+
+    let rows = sqlx::query(
+        r#"select f.image_id, f.library_file_id, lf.path from image_file f
+        join library_file lf on lf.id = f.library_file_id
+        where f.role in ('primary', 'secondary') and f.image_id in
+        (select image_id from image_file where role = 'secondary')
+        order by f.image_id, lf.path"#,
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut groups: HashMap<i64, Vec<(i64, PathBuf)>> = HashMap::new();
+    for row in rows {
+        groups.entry(row.get("image_id")).or_default().push((
+            row.get("library_file_id"),
+            PathBuf::from(row.get::<String, _>("path")),
+        ));
+    }
+
+    let mut changed = 0;
+    for (image_id, files) in groups {
+        let Some(preferred) = preferred_file(&files, |(_, path)| path, preference) else {
+            continue;
+        };
+        if set_primary_file(pool, image_id, preferred.0).await? {
+            changed += 1;
+        }
+    }
+    Ok(changed)
+}
+
+/// Groups files in the same folder sharing a base name when one of them is a RAW file.
+/// Files with the same base name but no RAW among them (IMG_1.jpg and IMG_1.jpeg) are
+/// usually different pictures and stay separate. The order of the files is kept.
+pub fn group_files<T>(
+    files: Vec<T>,
+    path: impl Fn(&T) -> &Path,
+    preference: PrimaryPreference,
+) -> Vec<FileGroup<T>> {
+    let key = |file: &T| {
+        let path = path(file);
+        (
+            path.parent().map(|parent| parent.to_path_buf()),
+            path.file_stem().map(|stem| stem.to_os_string()),
+        )
+    };
+    let mut by_key: HashMap<_, Vec<usize>> = HashMap::new();
+    for (index, file) in files.iter().enumerate() {
+        by_key.entry(key(file)).or_default().push(index);
+    }
+
+    // Index of the primary file for each grouped file
+    let mut primary_of: Vec<usize> = (0..files.len()).collect();
+    for indexes in by_key.values() {
+        let members: Vec<(usize, &Path)> = indexes.iter().map(|&i| (i, path(&files[i]))).collect();
+        if indexes.len() < 2
+            || !members
+                .iter()
+                .any(|(_, path)| image_helpers::is_raw_image(path))
+        {
+            continue;
+        }
+        if let Some((primary, _)) = preferred_file(&members, |(_, path)| path, preference) {
+            for &index in indexes {
+                primary_of[index] = *primary;
+            }
+        }
+    }
+
+    let mut groups: Vec<FileGroup<T>> = vec![];
+    let mut group_index: HashMap<usize, usize> = HashMap::new();
+    let mut secondary: Vec<(usize, T)> = vec![];
+    for (index, file) in files.into_iter().enumerate() {
+        if primary_of[index] == index {
+            group_index.insert(index, groups.len());
+            groups.push(FileGroup {
+                primary: file,
+                secondary: vec![],
+            });
+        } else {
+            secondary.push((primary_of[index], file));
+        }
+    }
+    for (primary, file) in secondary {
+        groups[group_index[&primary]].secondary.push(file);
+    }
+    groups
+}
+
+// The file which should be the primary one of a group. Files are tried in the order of
+// the preference, RAW first or JPEG first, and anything else after them.
+pub(crate) fn preferred_file<T>(
+    files: &[T],
+    path: impl Fn(&T) -> &Path,
+    preference: PrimaryPreference,
+) -> Option<&T> {
+    let is_jpeg = |path: &Path| {
+        path.extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .is_some_and(|extension| ["jpg", "jpeg", "jpe"].contains(&extension.as_str()))
+    };
+    let raw = files
+        .iter()
+        .find(|file| image_helpers::is_raw_image(path(file)));
+    let jpeg = files.iter().find(|file| is_jpeg(path(file)));
+    match preference {
+        PrimaryPreference::Raw => raw.or(jpeg),
+        PrimaryPreference::Jpeg => jpeg.or(raw),
+    }
+    .or(files.first())
+}
+
+pub async fn insert_image_file<'a>(
+    tx: &mut Transaction<'a, Sqlite>,
+    image_id: i64,
+    library_file_id: i64,
+    role: FileRole,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO image_file (image_id, library_file_id, role) values (?, ?, ?)")
+        .bind(image_id)
+        .bind(library_file_id)
+        .bind(role.as_str())
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+/// All files of an image, the primary one first and sidecars last
+pub async fn get_image_files(
+    pool: &SqlitePool,
+    image_id: i64,
+) -> Result<Vec<ImageFile>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"select f.library_file_id, f.role, lf.path, lf.extension from image_file f
+        join library_file lf on lf.id = f.library_file_id
+        where f.image_id=?
+        order by case f.role when 'primary' then 0 when 'secondary' then 1 when 'motion' then 2 else 3 end, lf.path"#,
+    )
+    .bind(image_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| ImageFile {
+            library_file_id: row.get("library_file_id"),
+            path: row.get("path"),
+            extension: row.get("extension"),
+            role: FileRole::from_db(&row.get::<String, _>("role")),
+        })
+        .collect())
+}
+
+/// Makes another file of the image its primary one, the one shown and exported.
+/// Returns false if it already was.
+pub async fn set_primary_file(
+    pool: &SqlitePool,
+    image_id: i64,
+    library_file_id: i64,
+) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query(
+        r#"select f.role, lf.path from image_file f join library_file lf on lf.id = f.library_file_id
+        where f.image_id=? and f.library_file_id=? and f.role in ('primary', 'secondary')"#,
+    )
+    .bind(image_id)
+    .bind(library_file_id)
+    .fetch_optional(pool)
+    .await?;
+    let Some(row) = row else {
+        return Err(sqlx::Error::RowNotFound);
+    };
+    if row.get::<String, _>("role") == FileRole::Primary.as_str() {
+        return Ok(false);
+    }
+
+    // The dimensions shown are the ones of the primary file
+    let header = image_helpers::read_image_header(Path::new(&row.get::<String, _>("path")));
+    let mut tx = pool.begin().await?;
+    sqlx::query("UPDATE image_file set role='secondary' where image_id=? and role='primary'")
+        .bind(image_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("UPDATE image_file set role='primary' where library_file_id=?")
+        .bind(library_file_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "UPDATE image set library_file_id=?, file_format=?, file_width=?, file_height=? where id=?",
+    )
+    .bind(library_file_id)
+    .bind(header.map_or("unset", |header| header.format))
+    .bind(header.map(|header| header.width))
+    .bind(header.map(|header| header.height))
+    .bind(image_id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_group_files() {
+        let files = vec![
+            PathBuf::from("/photos/DSCF0001.JPG"),
+            PathBuf::from("/photos/DSCF0001.RAF"),
+            PathBuf::from("/photos/IMG_1.jpg"),
+            PathBuf::from("/photos/IMG_1.jpeg"),
+            PathBuf::from("/other/DSCF0001.JPG"),
+        ];
+        let groups = group_files(files.clone(), |file| file, PrimaryPreference::Raw);
+        assert_eq!(groups.len(), 4);
+        assert_eq!(groups[0].primary, files[1]);
+        assert_eq!(groups[0].secondary, vec![files[0].clone()]);
+        assert!(groups[1..].iter().all(|group| group.secondary.is_empty()));
+
+        let groups = group_files(files.clone(), |file| file, PrimaryPreference::Jpeg);
+        assert_eq!(groups[0].primary, files[0]);
+        assert_eq!(groups[0].secondary, vec![files[1].clone()]);
+    }
+
+    #[sqlx::test]
+    async fn test_raw_jpeg_pairs(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        for name in ["DSCF0001.RAF", "DSCF0001.JPG", "DSCF0002.jpg"] {
+            fs::write(dir.path().join(name), b"not a real image")?;
+        }
+        fs::write(dir.path().join("DSCF0001.xmp"), "<rdf:Description/>")?;
+        db::insert_images(&pool, &dir.path().to_string_lossy()).await?;
+
+        let row = sqlx::query("select count(*) as count from image")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(row.get::<u32, _>("count"), 2);
+
+        let row = sqlx::query("select i.id from image i join library_file lf on lf.id = i.library_file_id where lf.original_file_name='DSCF0001.RAF'")
+            .fetch_one(&pool)
+            .await?;
+        let image_id = row.get::<i64, _>("id");
+        let files = get_image_files(&pool, image_id).await?;
+        let roles: Vec<(&str, FileRole)> = files
+            .iter()
+            .map(|file| (file.extension.as_str(), file.role))
+            .collect();
+        assert_eq!(
+            roles,
+            vec![
+                ("RAF", FileRole::Primary),
+                ("JPG", FileRole::Secondary),
+                ("xmp", FileRole::Sidecar)
+            ]
+        );
+
+        assert_eq!(
+            set_primary_preference(&pool, PrimaryPreference::Jpeg).await?,
+            1
+        );
+        assert_eq!(
+            get_primary_preference(&pool).await?,
+            PrimaryPreference::Jpeg
+        );
+        let files = get_image_files(&pool, image_id).await?;
+        assert_eq!(files[0].extension, "JPG");
+        let row = sqlx::query("select lf.extension from image i join library_file lf on lf.id = i.library_file_id where i.id=?")
+            .bind(image_id)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(row.get::<String, _>("extension"), "JPG");
+
+        // And back by hand
+        assert!(set_primary_file(&pool, image_id, files[1].library_file_id).await?);
+        assert!(!set_primary_file(&pool, image_id, files[1].library_file_id).await?);
+        assert!(set_primary_file(&pool, image_id, files[2].library_file_id)
+            .await
+            .is_err());
+        Ok(())
+    }
+}
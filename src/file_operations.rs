@@ -0,0 +1,782 @@
+use crate::{db, root_folders, stacks, xmp};
+use sqlx::{Row, Sqlite, SqlitePool, Transaction};
+use std::{
+    fs, io,
+    path::{Path, PathBuf, MAIN_SEPARATOR_STR},
+};
+
+const TRASH_FOLDER_SETTING: &str = "trash_folder";
+// Used next to the deleted files when no trash folder is set
+const DEFAULT_TRASH_FOLDER_NAME: &str = ".trash";
+
+// Tables holding one or more rows per image which a copy gets too
+const IMAGE_DATA_TABLES: [&str; 3] = ["exif", "iptc", "tag"];
+
+// A change made on disk, kept so that it can be undone when a later step fails
+enum Change {
+    Moved { from: PathBuf, to: PathBuf },
+    Copied(PathBuf),
+}
+
+/// The file system changes of one operation. Nothing is ever overwritten, so undoing
+/// them in reverse order gets the disk back to how it was.
+#[derive(Default)]
+struct Journal {
+    changes: Vec<Change>,
+}
+
+impl Journal {
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<(), io::Error> {
+        move_file(from, to)?;
+        self.changes.push(Change::Moved {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+        });
+        Ok(())
+    }
+
+    fn copy(&mut self, from: &Path, to: &Path) -> Result<(), io::Error> {
+        refuse_existing(to)?;
+        fs::copy(from, to)?;
+        self.changes.push(Change::Copied(to.to_path_buf()));
+        Ok(())
+    }
+
+    // Undoes as much as it can. The changes which couldn't be undone are returned in the
+    // error, so that the user knows which files to look after.
+    fn rollback(self) -> Result<(), io::Error> {
+        let mut failures = vec![];
+        for change in self.changes.into_iter().rev() {
+            let result = match &change {
+                Change::Moved { from, to } => move_file(to, from),
+                Change::Copied(path) => fs::remove_file(path),
+            };
+            if let Err(err) = result {
+                failures.push(match change {
+                    Change::Moved { from, to } => format!(
+                        "{} could not be moved back to {}: {err}",
+                        to.display(),
+                        from.display()
+                    ),
+                    Change::Copied(path) => {
+                        format!("{} could not be removed: {err}", path.display())
+                    }
+                });
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(io::Error::other(failures.join("; ")))
+        }
+    }
+}
+
+fn move_file(from: &Path, to: &Path) -> Result<(), io::Error> {
+    refuse_existing(to)?;
+    if fs::rename(from, to).is_err() {
+        // Renaming doesn't work across volumes, copy and remove instead
+        fs::copy(from, to)?;
+        if let Err(err) = fs::remove_file(from) {
+            let _ = fs::remove_file(to);
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn refuse_existing(path: &Path) -> Result<(), io::Error> {
+    if path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", path.display()),
+        ));
+    }
+    Ok(())
+}
+
+// Runs the file system and database parts of an operation. When anything fails the
+// transaction isn't committed and the file system changes are undone.
+async fn with_rollback<T>(
+    pool: &SqlitePool,
+    operation: impl AsyncFnOnce(&mut Transaction<'_, Sqlite>, &mut Journal) -> Result<T, sqlx::Error>,
+) -> Result<T, sqlx::Error> {
+    let mut journal = Journal::default();
+    let mut tx = pool.begin().await?;
+    let result = match operation(&mut tx, &mut journal).await {
+        Ok(value) => tx.commit().await.map(|_| value),
+        Err(err) => Err(err),
+    };
+    match result {
+        Err(err) => match journal.rollback() {
+            Ok(()) => Err(err),
+            Err(rollback_err) => Err(io::Error::other(format!(
+                "{err}, and undoing the changes on disk failed: {rollback_err}"
+            ))
+            .into()),
+        },
+        ok => ok,
+    }
+}
+
+struct ImageFileRow {
+    library_file_id: i64,
+    path: PathBuf,
+    role: String,
+}
+
+async fn get_files<'a>(
+    tx: &mut Transaction<'a, Sqlite>,
+    image_id: i64,
+) -> Result<Vec<ImageFileRow>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"select f.library_file_id, f.role, lf.path from image_file f
+        join library_file lf on lf.id = f.library_file_id
+        where f.image_id=?"#,
+    )
+    .bind(image_id)
+    .fetch_all(&mut **tx)
+    .await?;
+    if rows.is_empty() {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    Ok(rows
+        .iter()
+        .map(|row| ImageFileRow {
+            library_file_id: row.get("library_file_id"),
+            path: PathBuf::from(row.get::<String, _>("path")),
+            role: row.get("role"),
+        })
+        .collect())
+}
+
+// The columns of library_file which follow from its path
+fn path_columns(path: &Path) -> [(&'static str, String); 4] {
+    let name = |part: Option<&std::ffi::OsStr>| {
+        part.map(|part| part.to_string_lossy().to_string())
+            .unwrap_or_default()
+    };
+    [
+        ("path", path.to_string_lossy().to_string()),
+        (
+            "parent_path",
+            name(path.parent().map(|parent| parent.as_os_str())),
+        ),
+        ("original_file_name", name(path.file_name())),
+        ("base_name", name(path.file_stem())),
+    ]
+}
+
+/// Points a library_file row at a new location, keeping everything else about it
+async fn set_library_file_path<'a>(
+    tx: &mut Transaction<'a, Sqlite>,
+    library_file_id: i64,
+    path: &Path,
+) -> Result<(), sqlx::Error> {
+    let mut query_builder = sqlx::QueryBuilder::new("UPDATE library_file set ");
+    let mut separated = query_builder.separated(", ");
+    for (column, value) in path_columns(path) {
+        separated.push(format!("{column}="));
+        separated.push_bind_unseparated(value);
+    }
+    query_builder.push(" where id=");
+    query_builder.push_bind(library_file_id);
+    query_builder.build().execute(&mut **tx).await?;
+    root_folders::assign_root_folder(tx, library_file_id).await?;
+    Ok(())
+}
+
+/// Moves the row of a file, and the sync state which refers to it by path
+pub(crate) async fn move_library_file<'a>(
+    tx: &mut Transaction<'a, Sqlite>,
+    library_file_id: i64,
+    old_path: &Path,
+    new_path: &Path,
+) -> Result<(), sqlx::Error> {
+    set_library_file_path(tx, library_file_id, new_path).await?;
+    sqlx::query("UPDATE xmp_sync set path=? where path=?")
+        .bind(new_path.to_string_lossy().to_string())
+        .bind(old_path.to_string_lossy().to_string())
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+/// Moves the images, with all their files (RAW+JPEG, sidecars, Live Photo videos), to
+/// another folder. Returns the number of files moved.
+pub async fn move_images(
+    pool: &SqlitePool,
+    image_ids: &[i64],
+    destination: &Path,
+) -> Result<u64, sqlx::Error> {
+    with_rollback(pool, async |tx, journal| {
+        let mut moved = 0;
+        for image_id in image_ids {
+            for file in get_files(tx, *image_id).await? {
+                let Some(name) = file.path.file_name() else {
+                    continue;
+                };
+                let new_path = destination.join(name);
+                journal.rename(&file.path, &new_path)?;
+                move_library_file(tx, file.library_file_id, &file.path, &new_path).await?;
+                moved += 1;
+            }
+        }
+        Ok(moved)
+    })
+    .await
+}
+
+/// Copies the images to another folder. The copies are new images in the catalog with
+/// the same metadata, keywords and files as the originals. Returns their ids.
+pub async fn copy_images(
+    pool: &SqlitePool,
+    image_ids: &[i64],
+    destination: &Path,
+) -> Result<Vec<i64>, sqlx::Error> {
+    with_rollback(pool, async |tx, journal| {
+        let mut copies = vec![];
+        for image_id in image_ids {
+            copies.push(copy_image(tx, journal, *image_id, destination).await?);
+        }
+        Ok(copies)
+    })
+    .await
+}
+
+async fn copy_image<'a>(
+    tx: &mut Transaction<'a, Sqlite>,
+    journal: &mut Journal,
+    image_id: i64,
+    destination: &Path,
+) -> Result<i64, sqlx::Error> {
+    let files = get_files(tx, image_id).await?;
+    let new_image_id = copy_row(tx, "image", "id", image_id, &[]).await?;
+    let mut primary_path = None;
+    for file in &files {
+        let Some(name) = file.path.file_name() else {
+            continue;
+        };
+        let new_path = destination.join(name);
+        journal.copy(&file.path, &new_path)?;
+        let library_file_id = copy_row(
+            tx,
+            "library_file",
+            "id",
+            file.library_file_id,
+            &path_columns(&new_path),
+        )
+        .await?;
+        root_folders::assign_root_folder(tx, library_file_id).await?;
+        sqlx::query("INSERT INTO image_file (image_id, library_file_id, role) values (?, ?, ?)")
+            .bind(new_image_id)
+            .bind(library_file_id)
+            .bind(&file.role)
+            .execute(&mut **tx)
+            .await?;
+        if file.role == "primary" {
+            sqlx::query("UPDATE image set library_file_id=?, stack_id=null where id=?")
+                .bind(library_file_id)
+                .bind(new_image_id)
+                .execute(&mut **tx)
+                .await?;
+            primary_path = Some(new_path.clone());
+        }
+        sqlx::query(
+            r#"INSERT INTO motion_component (image_id, kind, library_file_id, video_offset, video_length, content_identifier)
+            select ?, kind, ?, video_offset, video_length, content_identifier from motion_component
+            where image_id=? and library_file_id=?"#,
+        )
+        .bind(new_image_id)
+        .bind(library_file_id)
+        .bind(image_id)
+        .bind(file.library_file_id)
+        .execute(&mut **tx)
+        .await?;
+    }
+    for table in IMAGE_DATA_TABLES {
+        copy_row(
+            tx,
+            table,
+            "image_id",
+            image_id,
+            &[("image_id", new_image_id.to_string())],
+        )
+        .await?;
+    }
+    if let Some(primary_path) = primary_path {
+        xmp::record_sync_state(
+            &mut **tx,
+            new_image_id,
+            &xmp::metadata_source(&primary_path),
+        )
+        .await?;
+    }
+    Ok(new_image_id)
+}
+
+// Copies the rows of a table matching the key. The id column gets a new value, and
+// the columns in changes get the given values. Returns the id of the last copied row.
+async fn copy_row<'a>(
+    tx: &mut Transaction<'a, Sqlite>,
+    table: &str,
+    key_column: &str,
+    key: i64,
+    changes: &[(&str, String)],
+) -> Result<i64, sqlx::Error> {
+    let columns: Vec<String> =
+        sqlx::query("select name from pragma_table_info(?) where name != 'id'")
+            .bind(table)
+            .fetch_all(&mut **tx)
+            .await?
+            .iter()
+            .map(|row| row.get::<String, _>("name"))
+            .collect();
+
+    let mut query_builder = sqlx::QueryBuilder::new(format!(
+        "INSERT INTO {table} ({}) select ",
+        columns.join(", ")
+    ));
+    let mut separated = query_builder.separated(", ");
+    for column in &columns {
+        match changes.iter().find(|(name, _)| name == column) {
+            Some((_, value)) => separated.push_bind(value.clone()),
+            None => separated.push(column),
+        };
+    }
+    query_builder.push(format!(" from {table} where {key_column}="));
+    query_builder.push_bind(key);
+    let result = query_builder.build().execute(&mut **tx).await?;
+    Ok(result.last_insert_rowid())
+}
+
+/// Gives the files of an image a new base name. Extensions are kept, and sidecars
+/// named after the whole file name (IMG_1.jpg.xmp) are renamed along.
+pub async fn rename_image(
+    pool: &SqlitePool,
+    image_id: i64,
+    new_name: &str,
+) -> Result<(), sqlx::Error> {
+    rename_images(pool, &[(image_id, new_name.to_string())]).await
+}
+
+/// Renames several images at once. Either all of them are renamed or none.
+pub async fn rename_images(
+    pool: &SqlitePool,
+    renames: &[(i64, String)],
+) -> Result<(), sqlx::Error> {
+    if let Some((_, new_name)) = renames
+        .iter()
+        .find(|(_, new_name)| new_name.is_empty() || new_name.contains(['/', '\\']))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{new_name:?} is not a valid file name"),
+        )
+        .into());
+    }
+    with_rollback(pool, async |tx, journal| {
+        for (image_id, new_name) in renames {
+            let files = get_files(tx, *image_id).await?;
+            let Some(primary) = files.iter().find(|file| file.role == "primary") else {
+                return Err(sqlx::Error::RowNotFound);
+            };
+            let old_name = file_stem(&primary.path);
+            for file in &files {
+                let Some(new_path) = renamed_path(&file.path, &old_name, new_name) else {
+                    continue;
+                };
+                journal.rename(&file.path, &new_path)?;
+                move_library_file(tx, file.library_file_id, &file.path, &new_path).await?;
+            }
+        }
+        Ok(())
+    })
+    .await
+}
+
+pub(crate) fn file_stem(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Where a file of an image goes when the image gets a new base name. Files of an
+/// image share the base name of the primary file, anything else keeps its name.
+pub(crate) fn renamed_path(path: &Path, old_name: &str, new_name: &str) -> Option<PathBuf> {
+    let file_name = path.file_name()?.to_string_lossy();
+    let rest = file_name.strip_prefix(old_name)?;
+    Some(path.with_file_name(format!("{new_name}{rest}")))
+}
+
+/// Renames or moves a whole folder. The paths of all files in it and in its subfolders
+/// are rewritten. Returns the number of files whose path changed.
+pub async fn rename_folder(pool: &SqlitePool, from: &Path, to: &Path) -> Result<u64, sqlx::Error> {
+    refuse_existing(to)?;
+    fs::rename(from, to)?;
+
+    let result = rewrite_folder_paths(pool, from, to).await;
+    if result.is_err() {
+        let _ = fs::rename(to, from);
+    }
+    result
+}
+
+async fn rewrite_folder_paths(
+    pool: &SqlitePool,
+    from: &Path,
+    to: &Path,
+) -> Result<u64, sqlx::Error> {
+    let from = from.to_string_lossy().to_string();
+    let to = to.to_string_lossy().to_string();
+    // The separator keeps /photos/trip from matching /photos/trip-2
+    let from_prefix = format!("{from}{MAIN_SEPARATOR_STR}");
+
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query(
+        r#"UPDATE library_file set path = ? || substr(path, length(?) + 1),
+        parent_path = ? || substr(parent_path, length(?) + 1)
+        where parent_path = ? or substr(parent_path, 1, length(?)) = ?"#,
+    )
+    .bind(&to)
+    .bind(&from)
+    .bind(&to)
+    .bind(&from)
+    .bind(&from)
+    .bind(&from_prefix)
+    .bind(&from_prefix)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        "UPDATE xmp_sync set path = ? || substr(path, length(?) + 1) where substr(path, 1, length(?)) = ?",
+    )
+    .bind(&to)
+    .bind(&from)
+    .bind(&from_prefix)
+    .bind(&from_prefix)
+    .execute(&mut *tx)
+    .await?;
+    root_folders::assign_root_folders(&mut tx).await?;
+    tx.commit().await?;
+    Ok(result.rows_affected())
+}
+
+/// Where deleted files go. None means a .trash folder next to each deleted file.
+pub async fn get_trash_folder(pool: &SqlitePool) -> Result<Option<PathBuf>, sqlx::Error> {
+    Ok(db::get_setting(pool, TRASH_FOLDER_SETTING)
+        .await?
+        .map(PathBuf::from))
+}
+
+pub async fn set_trash_folder(pool: &SqlitePool, folder: &Path) -> Result<(), sqlx::Error> {
+    db::set_setting(pool, TRASH_FOLDER_SETTING, &folder.to_string_lossy()).await
+}
+
+/// Moves the files of the images to the trash and removes the images from the catalog.
+/// Returns where the files ended up.
+pub async fn delete_images(
+    pool: &SqlitePool,
+    image_ids: &[i64],
+) -> Result<Vec<PathBuf>, sqlx::Error> {
+    let trash_folder = get_trash_folder(pool).await?;
+    with_rollback(pool, async |tx, journal| {
+        let mut trashed = vec![];
+        for image_id in image_ids {
+            for file in get_files(tx, *image_id).await? {
+                let (Some(parent), Some(name)) = (file.path.parent(), file.path.file_name()) else {
+                    continue;
+                };
+                let trash = trash_folder
+                    .clone()
+                    .unwrap_or_else(|| parent.join(DEFAULT_TRASH_FOLDER_NAME));
+                fs::create_dir_all(&trash)?;
+                let destination = free_path(&trash.join(name));
+                journal.rename(&file.path, &destination)?;
+                trashed.push(destination);
+            }
+            remove_image_from_catalog(tx, *image_id).await?;
+        }
+        Ok(trashed)
+    })
+    .await
+}
+
+/// Removes the images from the catalog and leaves their files where they are
+pub async fn remove_images(pool: &SqlitePool, image_ids: &[i64]) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    for image_id in image_ids {
+        remove_image_from_catalog(&mut tx, *image_id).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+// The trash can already have a file with the same name, e.g. an earlier IMG_0001.jpg
+fn free_path(path: &Path) -> PathBuf {
+    numbered_paths(path)
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
+
+// The path itself, then IMG_0001 (1).jpg, IMG_0001 (2).jpg and so on
+pub(crate) fn numbered_paths(path: &Path) -> impl Iterator<Item = PathBuf> + '_ {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    std::iter::once(path.to_path_buf()).chain(
+        (1..).map(move |counter| path.with_file_name(format!("{stem} ({counter}){extension}"))),
+    )
+}
+
+async fn remove_image_from_catalog<'a>(
+    tx: &mut Transaction<'a, Sqlite>,
+    image_id: i64,
+) -> Result<(), sqlx::Error> {
+    // Out of its stack first, the stack can't keep a deleted image as its top
+    sqlx::query("UPDATE image set stack_id=null where id=?")
+        .bind(image_id)
+        .execute(&mut **tx)
+        .await?;
+    stacks::remove_empty_stacks(tx).await?;
+
+    let library_file_ids: Vec<i64> =
+        sqlx::query("select library_file_id from image_file where image_id=?")
+            .bind(image_id)
+            .fetch_all(&mut **tx)
+            .await?
+            .iter()
+            .map(|row| row.get("library_file_id"))
+            .collect();
+    for table in [
+        "exif",
+        "iptc",
+        "tag",
+        "thumbnail",
+        "xmp_sync",
+        "pending_write_back",
+        "motion_component",
+        "image_file",
+    ] {
+        sqlx::query(&format!("DELETE from {table} where image_id=?"))
+            .bind(image_id)
+            .execute(&mut **tx)
+            .await?;
+    }
+    sqlx::query("DELETE from image where id=?")
+        .bind(image_id)
+        .execute(&mut **tx)
+        .await?;
+    for library_file_id in library_file_ids {
+        sqlx::query("DELETE from library_file where id=?")
+            .bind(library_file_id)
+            .execute(&mut **tx)
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_groups;
+
+    async fn image_paths(pool: &SqlitePool) -> Result<Vec<String>, sqlx::Error> {
+        Ok(sqlx::query("select path from library_file order by path")
+            .fetch_all(pool)
+            .await?
+            .iter()
+            .map(|row| row.get::<String, _>("path"))
+            .collect())
+    }
+
+    async fn image_id(pool: &SqlitePool, name: &str) -> Result<i64, sqlx::Error> {
+        let row = sqlx::query("select i.id from image i join library_file lf on lf.id = i.library_file_id where lf.original_file_name=?")
+            .bind(name)
+            .fetch_one(pool)
+            .await?;
+        Ok(row.get("id"))
+    }
+
+    #[sqlx::test]
+    async fn test_move_copy_and_rename_images(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let source = dir.path().join("source");
+        let destination = dir.path().join("destination");
+        fs::create_dir(&source)?;
+        fs::create_dir(&destination)?;
+        fs::write(source.join("DSCF0001.RAF"), b"raw")?;
+        fs::write(source.join("DSCF0001.JPG"), b"jpeg")?;
+        fs::write(source.join("DSCF0001.xmp"), "<rdf:Description/>")?;
+        fs::write(source.join("DSCF0002.jpg"), b"second")?;
+        db::insert_images(&pool, &source.to_string_lossy()).await?;
+        db::add_keyword(
+            &pool,
+            &source.join("DSCF0002.jpg").to_string_lossy(),
+            "trip",
+        )
+        .await?;
+
+        let raw_id = image_id(&pool, "DSCF0001.RAF").await?;
+        let jpeg_id = image_id(&pool, "DSCF0002.jpg").await?;
+        assert_eq!(move_images(&pool, &[raw_id], &destination).await?, 3);
+        assert!(destination.join("DSCF0001.RAF").is_file());
+        assert!(!source.join("DSCF0001.xmp").exists());
+        let files = file_groups::get_image_files(&pool, raw_id).await?;
+        assert!(files
+            .iter()
+            .all(|file| Path::new(&file.path).parent() == Some(destination.as_path())));
+
+        rename_image(&pool, raw_id, "Lake").await?;
+        let files = file_groups::get_image_files(&pool, raw_id).await?;
+        let names: Vec<&str> = files
+            .iter()
+            .map(|file| Path::new(&file.path).file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["Lake.RAF", "Lake.JPG", "Lake.xmp"]);
+
+        let copies = copy_images(&pool, &[jpeg_id], &destination).await?;
+        assert_eq!(copies.len(), 1);
+        assert!(source.join("DSCF0002.jpg").is_file());
+        assert!(destination.join("DSCF0002.jpg").is_file());
+        let row = sqlx::query("select tag_name from tag where image_id=?")
+            .bind(copies[0])
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(row.get::<String, _>("tag_name"), "trip");
+        let files = file_groups::get_image_files(&pool, copies[0]).await?;
+        assert_eq!(
+            files[0].path,
+            destination.join("DSCF0002.jpg").to_string_lossy()
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_failed_move_is_rolled_back(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let destination = dir.path().join("destination");
+        fs::create_dir(&destination)?;
+        fs::write(dir.path().join("DSCF0001.jpg"), b"first")?;
+        fs::write(dir.path().join("DSCF0002.jpg"), b"second")?;
+        db::insert_images(&pool, &dir.path().to_string_lossy()).await?;
+        let before = image_paths(&pool).await?;
+        let ids = [
+            image_id(&pool, "DSCF0001.jpg").await?,
+            image_id(&pool, "DSCF0002.jpg").await?,
+        ];
+
+        // The second file would overwrite an existing one
+        fs::write(destination.join("DSCF0002.jpg"), b"somebody else")?;
+        assert!(move_images(&pool, &ids, &destination).await.is_err());
+        assert!(dir.path().join("DSCF0001.jpg").is_file());
+        assert!(!destination.join("DSCF0001.jpg").exists());
+        assert_eq!(
+            fs::read(destination.join("DSCF0002.jpg"))?,
+            b"somebody else"
+        );
+        assert_eq!(image_paths(&pool).await?, before);
+        Ok(())
+    }
+
+    #[test]
+    fn test_journal_rollback() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, second) = (dir.path().join("a.jpg"), dir.path().join("b.jpg"));
+        fs::write(&first, b"a").unwrap();
+        fs::write(&second, b"b").unwrap();
+        let mut journal = Journal::default();
+        journal.rename(&first, &dir.path().join("c.jpg")).unwrap();
+        journal.copy(&second, &dir.path().join("d.jpg")).unwrap();
+        journal.rollback().unwrap();
+        assert!(first.is_file());
+        assert!(!dir.path().join("c.jpg").exists());
+        assert!(!dir.path().join("d.jpg").exists());
+
+        // Something new took the place of the moved file, which is kept
+        let mut journal = Journal::default();
+        journal.rename(&first, &dir.path().join("c.jpg")).unwrap();
+        journal.copy(&second, &dir.path().join("d.jpg")).unwrap();
+        fs::write(&first, b"new").unwrap();
+        let err = journal.rollback().unwrap_err();
+        assert!(err.to_string().contains("could not be moved back"));
+        assert_eq!(fs::read(&first).unwrap(), b"new");
+        assert!(dir.path().join("c.jpg").is_file());
+        assert!(!dir.path().join("d.jpg").exists());
+    }
+
+    #[sqlx::test]
+    async fn test_rename_folder(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let folder = dir.path().join("trip");
+        fs::create_dir_all(folder.join("day 1"))?;
+        fs::create_dir(dir.path().join("trip-2"))?;
+        fs::write(folder.join("DSCF0001.jpg"), b"first")?;
+        fs::write(folder.join("day 1").join("DSCF0002.jpg"), b"second")?;
+        fs::write(dir.path().join("trip-2").join("DSCF0003.jpg"), b"third")?;
+        for path in [
+            folder.clone(),
+            folder.join("day 1"),
+            dir.path().join("trip-2"),
+        ] {
+            db::insert_images(&pool, &path.to_string_lossy()).await?;
+        }
+
+        let renamed = dir.path().join("2024 Iceland");
+        assert_eq!(rename_folder(&pool, &folder, &renamed).await?, 2);
+        assert!(renamed.join("day 1").join("DSCF0002.jpg").is_file());
+        assert_eq!(
+            image_paths(&pool).await?,
+            vec![
+                renamed.join("DSCF0001.jpg").to_string_lossy().to_string(),
+                renamed
+                    .join("day 1")
+                    .join("DSCF0002.jpg")
+                    .to_string_lossy()
+                    .to_string(),
+                dir.path()
+                    .join("trip-2")
+                    .join("DSCF0003.jpg")
+                    .to_string_lossy()
+                    .to_string(),
+            ]
+        );
+        assert!(db::has_images_for_path(&pool, &renamed.join("day 1").to_string_lossy()).await?);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_delete_images(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let trash = dir.path().join("trash");
+        fs::write(dir.path().join("DSCF0001.jpg"), b"first")?;
+        fs::write(dir.path().join("DSCF0001.xmp"), "<rdf:Description/>")?;
+        fs::write(dir.path().join("DSCF0002.jpg"), b"second")?;
+        fs::write(dir.path().join("DSCF0003.jpg"), b"third")?;
+        db::insert_images(&pool, &dir.path().to_string_lossy()).await?;
+        let first = image_id(&pool, "DSCF0001.jpg").await?;
+        let second = image_id(&pool, "DSCF0002.jpg").await?;
+        stacks::create_stack(&pool, &[first, second], Some(first)).await?;
+
+        set_trash_folder(&pool, &trash).await?;
+        fs::create_dir(&trash)?;
+        fs::write(trash.join("DSCF0001.jpg"), b"deleted earlier")?;
+        let trashed = delete_images(&pool, &[first]).await?;
+        assert_eq!(
+            trashed,
+            vec![trash.join("DSCF0001 (1).jpg"), trash.join("DSCF0001.xmp")]
+        );
+        assert!(!dir.path().join("DSCF0001.jpg").exists());
+        assert_eq!(image_paths(&pool).await?.len(), 2);
+        // The stack is down to one image and gone
+        let row = sqlx::query("select count(*) as count from stack")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(row.get::<i64, _>("count"), 0);
+        Ok(())
+    }
+}
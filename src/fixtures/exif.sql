@@ -1,4 +1,4 @@
-INSERT INTO exif (image_id, camera_make, camera_model, f_number, iso_speed, shutter_speed, lens_make, lens_model, focal_length)
+INSERT INTO exif (image_id, camera_make, camera_model, f_number, iso_speed, shutter_speed_value, lens_make, lens_model, focal_length)
 values
 (1, "Fujifilm", "x-pro2", 2.0, 400.0, 5.121, "Fujifilm", "85mm f/1.8", 85.0),
 (2, "Fujifilm", "x-pro2", 2.0, 400.0, 5.121, "Fujifilm", "85mm f/1.8", 85.0),
@@ -0,0 +1,5 @@
+update exif set latitude = 12.9716, longitude = 77.5946, altitude = 920.0 where image_id in (1, 2, 3);
+update exif set latitude = 13.0827, longitude = 80.2707, altitude = 6.0 where image_id = 4;
+update exif set latitude = 48.8566, longitude = 2.3522 where image_id = 5;
+update exif set latitude = -33.8688, longitude = 151.2093, altitude = 58.0 where image_id = 6;
+update exif set latitude = 64.8378, longitude = -147.7164 where image_id = 7;
@@ -0,0 +1,227 @@
+use crate::{db::DirContent, image_helpers};
+use sqlx::{Row, SqlitePool};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderNode {
+    pub name: String,
+    pub path: PathBuf,
+    // Images right in this folder
+    pub image_count: i64,
+    // Images in this folder and all the folders below it
+    pub total_image_count: i64,
+    pub children: Vec<FolderNode>,
+}
+
+/// The folders of the catalog as a tree. Folders without images of their own show up
+/// when they connect folders with images, e.g. the year folder above the day folders.
+pub async fn get_folder_tree(pool: &SqlitePool) -> Result<Vec<FolderNode>, sqlx::Error> {
+    let direct: BTreeMap<PathBuf, i64> = sqlx::query(
+        "select lf.parent_path, count(*) as count from image i join library_file lf on lf.id = i.library_file_id group by lf.parent_path",
+    )
+    .fetch_all(pool)
+    .await?
+    .iter()
+    .map(|row| (PathBuf::from(row.get::<String, _>("parent_path")), row.get("count")))
+    .collect();
+
+    let mut folders: BTreeSet<PathBuf> = direct.keys().cloned().collect();
+    for folder in direct.keys() {
+        let ancestors: Vec<&Path> = folder.ancestors().skip(1).collect();
+        if let Some(position) = ancestors
+            .iter()
+            .position(|ancestor| direct.contains_key(*ancestor))
+        {
+            folders.extend(ancestors[..position].iter().map(|path| path.to_path_buf()));
+        }
+    }
+
+    let mut children: BTreeMap<Option<PathBuf>, Vec<PathBuf>> = BTreeMap::new();
+    for folder in &folders {
+        let parent = folder
+            .parent()
+            .filter(|parent| folders.contains(*parent))
+            .map(Path::to_path_buf);
+        children.entry(parent).or_default().push(folder.clone());
+    }
+    Ok(children
+        .get(&None)
+        .map(|roots| {
+            roots
+                .iter()
+                .map(|root| folder_node(root, &direct, &children))
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+fn folder_node(
+    path: &Path,
+    direct: &BTreeMap<PathBuf, i64>,
+    children: &BTreeMap<Option<PathBuf>, Vec<PathBuf>>,
+) -> FolderNode {
+    let child_nodes: Vec<FolderNode> = children
+        .get(&Some(path.to_path_buf()))
+        .map(|paths| {
+            paths
+                .iter()
+                .map(|child| folder_node(child, direct, children))
+                .collect()
+        })
+        .unwrap_or_default();
+    let image_count = direct.get(path).copied().unwrap_or(0);
+    FolderNode {
+        // The root of the file system has no name
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.to_string_lossy().to_string()),
+        path: path.to_path_buf(),
+        image_count,
+        total_image_count: image_count
+            + child_nodes
+                .iter()
+                .map(|child| child.total_image_count)
+                .sum::<i64>(),
+        children: child_nodes,
+    }
+}
+
+/// Lists the folders and importable files in a folder of the file system, folders first.
+/// Hidden entries are left out.
+pub async fn browse_folder(pool: &SqlitePool, path: &Path) -> Result<Vec<DirContent>, sqlx::Error> {
+    let catalog_folders: Vec<PathBuf> =
+        sqlx::query("select distinct parent_path from library_file")
+            .fetch_all(pool)
+            .await?
+            .iter()
+            .map(|row| PathBuf::from(row.get::<String, _>("parent_path")))
+            .collect();
+    let catalog_files: HashSet<String> =
+        sqlx::query("select path from library_file where parent_path=?")
+            .bind(path.to_string_lossy().to_string())
+            .fetch_all(pool)
+            .await?
+            .iter()
+            .map(|row| row.get("path"))
+            .collect();
+
+    let mut contents = vec![];
+    for entry in fs::read_dir(path)? {
+        let entry_path = entry?.path();
+        let name = match entry_path.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => continue,
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        let is_directory = entry_path.is_dir();
+        if !is_directory && !image_helpers::is_importable_file(&entry_path) {
+            continue;
+        }
+        let in_catalog = if is_directory {
+            catalog_folders
+                .iter()
+                .any(|folder| folder.starts_with(&entry_path))
+        } else {
+            catalog_files.contains(entry_path.to_string_lossy().as_ref())
+        };
+        contents.push(DirContent {
+            name,
+            parent_path: path.to_string_lossy().to_string(),
+            extension: entry_path
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            is_directory,
+            in_catalog,
+            path: entry_path,
+        });
+    }
+    contents.sort_by(|a, b| {
+        b.is_directory
+            .cmp(&a.is_directory)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[sqlx::test]
+    async fn test_folder_tree(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let year = dir.path().join("2024");
+        for (folder, names) in [
+            (year.clone(), vec!["cover.jpg"]),
+            (year.join("03").join("24"), vec!["a.jpg", "b.jpg"]),
+            (year.join("04"), vec!["c.jpg"]),
+        ] {
+            fs::create_dir_all(&folder)?;
+            for name in names {
+                fs::write(folder.join(name), name)?;
+            }
+            db::insert_images(&pool, &folder.to_string_lossy()).await?;
+        }
+
+        let tree = get_folder_tree(&pool).await?;
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree[0].path, year);
+        assert_eq!(tree[0].image_count, 1);
+        assert_eq!(tree[0].total_image_count, 4);
+        let months: Vec<(&str, i64, i64)> = tree[0]
+            .children
+            .iter()
+            .map(|node| (node.name.as_str(), node.image_count, node.total_image_count))
+            .collect();
+        // 03 has no images of its own but leads to 24
+        assert_eq!(months, vec![("03", 0, 2), ("04", 1, 1)]);
+        assert_eq!(tree[0].children[0].children[0].name, "24");
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_browse_folder(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir_all(dir.path().join("imported").join("day"))?;
+        fs::create_dir(dir.path().join("new"))?;
+        fs::create_dir(dir.path().join(".trash"))?;
+        fs::write(dir.path().join("imported").join("day").join("a.jpg"), b"a")?;
+        fs::write(dir.path().join("b.jpg"), b"b")?;
+        fs::write(dir.path().join("c.jpg"), b"c")?;
+        fs::write(dir.path().join("notes.txt"), b"notes")?;
+        db::insert_images(
+            &pool,
+            &dir.path().join("imported").join("day").to_string_lossy(),
+        )
+        .await?;
+        db::insert_images(&pool, &dir.path().to_string_lossy()).await?;
+        fs::write(dir.path().join("d.jpg"), b"d")?;
+
+        let contents: Vec<(String, bool, bool)> = browse_folder(&pool, dir.path())
+            .await?
+            .into_iter()
+            .map(|content| (content.name, content.is_directory, content.in_catalog))
+            .collect();
+        assert_eq!(
+            contents,
+            vec![
+                ("imported".to_string(), true, true),
+                ("new".to_string(), true, false),
+                ("b.jpg".to_string(), false, true),
+                ("c.jpg".to_string(), false, true),
+                ("d.jpg".to_string(), false, false),
+            ]
+        );
+        Ok(())
+    }
+}
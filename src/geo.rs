@@ -0,0 +1,101 @@
+const EARTH_RADIUS_KM: f64 = 6371.0088;
+
+/// A latitude/longitude rectangle in decimal degrees. If min_longitude is bigger than
+/// max_longitude the box is taken to cross the antimeridian (e.g. 170 to -170).
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BoundingBox {
+    pub min_latitude: f64,
+    pub min_longitude: f64,
+    pub max_latitude: f64,
+    pub max_longitude: f64,
+}
+
+impl BoundingBox {
+    pub fn crosses_antimeridian(&self) -> bool {
+        self.min_longitude > self.max_longitude
+    }
+
+    /// The smallest box which contains the circle of radius_km around the given point.
+    /// Used to narrow down radius searches with an indexed query before doing the exact
+    /// distance check.
+    pub fn around(latitude: f64, longitude: f64, radius_km: f64) -> BoundingBox {
+        let latitude_delta = (radius_km / EARTH_RADIUS_KM).to_degrees();
+        let min_latitude = latitude - latitude_delta;
+        let max_latitude = latitude + latitude_delta;
+
+        // Close to the poles a circle covers every longitude
+        if min_latitude <= -90.0 || max_latitude >= 90.0 {
+            return BoundingBox {
+                min_latitude: min_latitude.max(-90.0),
+                min_longitude: -180.0,
+                max_latitude: max_latitude.min(90.0),
+                max_longitude: 180.0,
+            };
+        }
+
+        let longitude_delta = (radius_km / (EARTH_RADIUS_KM * latitude.to_radians().cos()))
+            .to_degrees()
+            .min(180.0);
+        BoundingBox {
+            min_latitude,
+            min_longitude: normalize_longitude(longitude - longitude_delta),
+            max_latitude,
+            max_longitude: normalize_longitude(longitude + longitude_delta),
+        }
+    }
+}
+
+fn normalize_longitude(longitude: f64) -> f64 {
+    if longitude > 180.0 {
+        longitude - 360.0
+    } else if longitude < -180.0 {
+        longitude + 360.0
+    } else {
+        longitude
+    }
+}
+
+/// Great circle distance between two points using the haversine formula
+pub fn haversine_distance_km(
+    latitude_a: f64,
+    longitude_a: f64,
+    latitude_b: f64,
+    longitude_b: f64,
+) -> f64 {
+    let delta_latitude = (latitude_b - latitude_a).to_radians();
+    let delta_longitude = (longitude_b - longitude_a).to_radians();
+    let a = (delta_latitude / 2.0).sin().powi(2)
+        + latitude_a.to_radians().cos()
+            * latitude_b.to_radians().cos()
+            * (delta_longitude / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_haversine_distance() {
+        // Bengaluru to Chennai is roughly 290km as the crow flies
+        let distance = haversine_distance_km(12.9716, 77.5946, 13.0827, 80.2707);
+        assert!((distance - 290.0).abs() < 5.0);
+        assert_eq!(haversine_distance_km(10.0, 10.0, 10.0, 10.0), 0.0);
+    }
+
+    #[test]
+    fn test_bounding_box_around() {
+        let bbox = BoundingBox::around(12.9716, 77.5946, 10.0);
+        assert!(bbox.min_latitude < 12.9716 && bbox.max_latitude > 12.9716);
+        assert!(bbox.min_longitude < 77.5946 && bbox.max_longitude > 77.5946);
+        assert!(!bbox.crosses_antimeridian());
+
+        let bbox = BoundingBox::around(0.0, 179.99, 50.0);
+        assert!(bbox.crosses_antimeridian());
+
+        let bbox = BoundingBox::around(89.9, 0.0, 50.0);
+        assert_eq!(bbox.min_longitude, -180.0);
+        assert_eq!(bbox.max_longitude, 180.0);
+    }
+}
@@ -0,0 +1,286 @@
+use crate::geo;
+use sqlx::{Row, SqlitePool};
+use std::{collections::HashMap, fs, path::Path};
+
+// A small list of cities so that reverse geocoding works without downloading anything.
+// Both files use the GeoNames layouts, so the full dumps can be loaded instead.
+const BUNDLED_CITIES: &str = include_str!("../data/cities.txt");
+const BUNDLED_COUNTRIES: &str = include_str!("../data/countries.txt");
+
+// Roughly the length of one degree of latitude
+const KM_PER_DEGREE: f64 = 111.2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Place {
+    pub name: String,
+    pub country_iso_code: String,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// Offline lookup of the nearest populated place for a coordinate
+#[derive(Debug, Default)]
+pub struct PlaceIndex {
+    // Sorted by latitude so that a lookup only has to look at a band of places
+    places: Vec<Place>,
+    country_names: HashMap<String, String>,
+}
+
+impl PlaceIndex {
+    pub fn bundled() -> PlaceIndex {
+        PlaceIndex::from_geonames(BUNDLED_CITIES, BUNDLED_COUNTRIES)
+    }
+
+    /// Builds the index from the contents of a GeoNames cities file (cities500.txt,
+    /// cities15000.txt etc.) and a countryInfo.txt file
+    pub fn from_geonames(cities: &str, countries: &str) -> PlaceIndex {
+        let mut places: Vec<Place> = geonames_rows(cities)
+            .filter_map(|columns| {
+                Some(Place {
+                    name: columns.get(1)?.to_string(),
+                    latitude: columns.get(4)?.parse().ok()?,
+                    longitude: columns.get(5)?.parse().ok()?,
+                    country_iso_code: columns.get(8)?.to_string(),
+                })
+            })
+            .collect();
+        places.sort_by(|a, b| a.latitude.total_cmp(&b.latitude));
+
+        let country_names = geonames_rows(countries)
+            .filter_map(|columns| Some((columns.first()?.to_string(), columns.get(4)?.to_string())))
+            .collect();
+
+        PlaceIndex {
+            places,
+            country_names,
+        }
+    }
+
+    /// Loads a GeoNames cities file from disk. Country names come from the given
+    /// countryInfo.txt or, if there is none, from the bundled list.
+    pub fn from_geonames_files(
+        cities_path: &Path,
+        countries_path: Option<&Path>,
+    ) -> Result<PlaceIndex, std::io::Error> {
+        let cities = fs::read_to_string(cities_path)?;
+        let countries = match countries_path {
+            Some(countries_path) => fs::read_to_string(countries_path)?,
+            None => BUNDLED_COUNTRIES.to_string(),
+        };
+        Ok(PlaceIndex::from_geonames(&cities, &countries))
+    }
+
+    pub fn country_name(&self, country_iso_code: &str) -> Option<&str> {
+        self.country_names
+            .get(country_iso_code)
+            .map(|name| name.as_str())
+    }
+
+    /// Returns the closest place and its distance in km, if there is one within
+    /// max_distance_km
+    pub fn nearest(
+        &self,
+        latitude: f64,
+        longitude: f64,
+        max_distance_km: f64,
+    ) -> Option<(&Place, f64)> {
+        let band = max_distance_km / KM_PER_DEGREE;
+        let start = self
+            .places
+            .partition_point(|place| place.latitude < latitude - band);
+
+        self.places[start..]
+            .iter()
+            .take_while(|place| place.latitude <= latitude + band)
+            .map(|place| {
+                let distance = geo::haversine_distance_km(
+                    latitude,
+                    longitude,
+                    place.latitude,
+                    place.longitude,
+                );
+                (place, distance)
+            })
+            .filter(|(_, distance)| *distance <= max_distance_km)
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+}
+
+fn geonames_rows(content: &str) -> impl Iterator<Item = Vec<&str>> {
+    content
+        .lines()
+        .filter(|line| !line.starts_with('#') && !line.trim().is_empty())
+        .map(|line| line.split('\t').collect())
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReverseGeocodeOptions {
+    // When false, city and country values which are already set (e.g. written by the
+    // camera or by the user) are kept and only the empty ones are filled
+    pub overwrite_existing: bool,
+    // Photos further than this from any known place don't get a city
+    pub max_distance_km: f64,
+}
+
+impl Default for ReverseGeocodeOptions {
+    fn default() -> Self {
+        ReverseGeocodeOptions {
+            overwrite_existing: false,
+            max_distance_km: 50.0,
+        }
+    }
+}
+
+/// Fills the iptc city, country_name and country_iso_code of every image with gps
+/// coordinates from the nearest place in the index. Returns the number of images
+/// which were matched to a place.
+pub async fn reverse_geocode_images(
+    pool: &SqlitePool,
+    places: &PlaceIndex,
+    options: &ReverseGeocodeOptions,
+) -> Result<u64, sqlx::Error> {
+    let rows = sqlx::query(
+        "select image_id, latitude, longitude from exif where latitude is not null and longitude is not null group by image_id",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut tx = pool.begin().await?;
+    let mut matched = 0;
+    for row in rows {
+        let image_id = row.get::<i64, _>("image_id");
+        let latitude = row.get::<f64, _>("latitude");
+        let longitude = row.get::<f64, _>("longitude");
+        let Some((place, _distance)) = places.nearest(latitude, longitude, options.max_distance_km)
+        else {
+            continue;
+        };
+        let country_name = places.country_name(&place.country_iso_code);
+
+        let iptc_row = sqlx::query("select id from iptc where image_id=?")
+            .bind(image_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        if iptc_row.is_none() {
+            sqlx::query(
+                "INSERT INTO iptc (image_id, city, country_name, country_iso_code) values (?, ?, ?, ?)",
+            )
+            .bind(image_id)
+            .bind(&place.name)
+            .bind(country_name)
+            .bind(&place.country_iso_code)
+            .execute(&mut *tx)
+            .await?;
+        } else {
+            sqlx::query(
+                r#"UPDATE iptc set
+                city = CASE WHEN ? OR coalesce(city, '') = '' THEN ? ELSE city END,
+                country_name = CASE WHEN ? OR coalesce(country_name, '') = '' THEN ? ELSE country_name END,
+                country_iso_code = CASE WHEN ? OR coalesce(country_iso_code, '') = '' THEN ? ELSE country_iso_code END
+                where image_id = ?"#,
+            )
+            .bind(options.overwrite_existing)
+            .bind(&place.name)
+            .bind(options.overwrite_existing)
+            .bind(country_name)
+            .bind(options.overwrite_existing)
+            .bind(&place.country_iso_code)
+            .bind(image_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+        matched += 1;
+    }
+    tx.commit().await?;
+    Ok(matched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nearest_place() {
+        let places = PlaceIndex::bundled();
+        let (place, distance) = places.nearest(12.9716, 77.5946, 50.0).unwrap();
+        assert_eq!(place.name, "Bengaluru");
+        assert!(distance < 1.0);
+        assert_eq!(places.country_name(&place.country_iso_code), Some("India"));
+
+        // Middle of the Indian ocean
+        assert_eq!(places.nearest(-20.0, 80.0, 50.0), None);
+    }
+
+    #[test]
+    fn test_load_geonames_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let cities = dir.path().join("cities15000.txt");
+        let countries = dir.path().join("countryInfo.txt");
+        // Rows as they are in the GeoNames dumps
+        std::fs::write(
+            &cities,
+            "1262321\tMysuru\tMysuru\tMaisur,Mysore\t12.29791\t76.63925\tP\tPPLA2\tIN\t\t19\t577\t\t\t920550\t\t770\tAsia/Kolkata\t2023-10-05\n\
+             2988507\tParis\tParis\tLutetia\t48.85341\t2.3488\tP\tPPLC\tFR\t\t11\t75\t751\t75056\t2138551\t\t42\tEurope/Paris\t2024-01-11\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &countries,
+            "#ISO\tISO3\tISO-Numeric\tfips\tCountry\tCapital\n\
+             IN\tIND\t356\tIN\tRepublic of India\tNew Delhi\t3287590\t1352617328\tAS\t.in\tINR\tRupee\t91\t\t\ten-IN,hi\t1269750\tCN,NP\t\n",
+        )
+        .unwrap();
+
+        let places = PlaceIndex::from_geonames_files(&cities, Some(&countries)).unwrap();
+        let (place, _) = places.nearest(12.30, 76.64, 10.0).unwrap();
+        assert_eq!(place.name, "Mysuru");
+        assert_eq!(places.country_name("IN"), Some("Republic of India"));
+        // Only the countries of the given file
+        assert_eq!(places.country_name("FR"), None);
+
+        // Without a countries file the bundled names are used
+        let places = PlaceIndex::from_geonames_files(&cities, None).unwrap();
+        assert_eq!(places.nearest(48.85, 2.35, 10.0).unwrap().0.name, "Paris");
+        assert_eq!(places.country_name("IN"), Some("India"));
+
+        assert!(PlaceIndex::from_geonames_files(&dir.path().join("missing.txt"), None).is_err());
+    }
+
+    #[sqlx::test(fixtures("library_file", "image", "exif", "iptc", "gps"))]
+    async fn test_reverse_geocode_images(pool: SqlitePool) -> sqlx::Result<()> {
+        sqlx::query("UPDATE iptc set city='Bangalore' where image_id=1")
+            .execute(&pool)
+            .await?;
+        let places = PlaceIndex::bundled();
+
+        let matched =
+            reverse_geocode_images(&pool, &places, &ReverseGeocodeOptions::default()).await?;
+        assert_eq!(matched, 7);
+
+        let row =
+            sqlx::query("select city, country_name, country_iso_code from iptc where image_id=4")
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(row.get::<String, _>("city"), "Chennai");
+        assert_eq!(row.get::<String, _>("country_name"), "India");
+        assert_eq!(row.get::<String, _>("country_iso_code"), "IN");
+
+        // Existing values are kept, empty ones filled
+        let row = sqlx::query("select city, country_iso_code from iptc where image_id=1")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(row.get::<String, _>("city"), "Bangalore");
+        assert_eq!(row.get::<String, _>("country_iso_code"), "IN");
+
+        let options = ReverseGeocodeOptions {
+            overwrite_existing: true,
+            ..Default::default()
+        };
+        reverse_geocode_images(&pool, &places, &options).await?;
+        let row = sqlx::query("select city from iptc where image_id=1")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(row.get::<String, _>("city"), "Bengaluru");
+        Ok(())
+    }
+}
@@ -0,0 +1,391 @@
+use crate::{image_helpers, xml};
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{Row, SqlitePool};
+use std::{fs, path::Path};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackPoint {
+    pub time: DateTime<Utc>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub elevation: Option<f64>,
+}
+
+/// Position of the tracker at some point in time, either an actual track point or
+/// interpolated between the two points around it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackPosition {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub elevation: Option<f64>,
+    // Seconds between the requested time and the closest real track point
+    pub gap_seconds: i64,
+}
+
+/// All the track points from one or more gpx files, sorted by time
+#[derive(Debug, Default)]
+pub struct Track {
+    points: Vec<TrackPoint>,
+}
+
+impl Track {
+    pub fn from_gpx_str(content: &str) -> Track {
+        let mut points = parse_gpx_points(content);
+        points.sort_by_key(|point| point.time);
+        Track { points }
+    }
+
+    pub fn from_gpx_file(path: &Path) -> Result<Track, std::io::Error> {
+        let content = fs::read_to_string(path)?;
+        Ok(Track::from_gpx_str(&content))
+    }
+
+    /// Phone trackers usually write one file per day or per recording session, so we
+    /// let callers combine them into a single track
+    pub fn merge(&mut self, other: Track) {
+        self.points.extend(other.points);
+        self.points.sort_by_key(|point| point.time);
+    }
+
+    pub fn points(&self) -> &[TrackPoint] {
+        &self.points
+    }
+
+    /// Finds where we were at the given time. If the surrounding track points are no
+    /// more than max_gap apart we interpolate linearly between them, otherwise we fall
+    /// back to the nearest point as long as it is within max_gap of the time.
+    pub fn position_at(&self, time: DateTime<Utc>, max_gap: Duration) -> Option<TrackPosition> {
+        let index = self.points.partition_point(|point| point.time <= time);
+        let previous = index.checked_sub(1).map(|i| &self.points[i]);
+        let next = self.points.get(index);
+
+        if let (Some(previous), Some(next)) = (previous, next) {
+            if next.time - previous.time <= max_gap {
+                let span = (next.time - previous.time).num_milliseconds() as f64;
+                let ratio = (time - previous.time).num_milliseconds() as f64 / span;
+                let mut longitude_delta = next.longitude - previous.longitude;
+                // Take the short way round when the track crosses the antimeridian
+                if longitude_delta > 180.0 {
+                    longitude_delta -= 360.0;
+                } else if longitude_delta < -180.0 {
+                    longitude_delta += 360.0;
+                }
+                let mut longitude = previous.longitude + longitude_delta * ratio;
+                if longitude > 180.0 {
+                    longitude -= 360.0;
+                } else if longitude < -180.0 {
+                    longitude += 360.0;
+                }
+                let elevation = match (previous.elevation, next.elevation) {
+                    (Some(a), Some(b)) => Some(a + (b - a) * ratio),
+                    (a, b) => a.or(b),
+                };
+                let gap = (time - previous.time).min(next.time - time);
+                return Some(TrackPosition {
+                    latitude: previous.latitude + (next.latitude - previous.latitude) * ratio,
+                    longitude,
+                    elevation,
+                    gap_seconds: gap.num_seconds(),
+                });
+            }
+        }
+
+        [previous, next]
+            .into_iter()
+            .flatten()
+            .map(|point| (point, (point.time - time).abs()))
+            .filter(|(_, gap)| *gap <= max_gap)
+            .min_by_key(|(_, gap)| *gap)
+            .map(|(point, gap)| TrackPosition {
+                latitude: point.latitude,
+                longitude: point.longitude,
+                elevation: point.elevation,
+                gap_seconds: gap.num_seconds(),
+            })
+    }
+}
+
+/// Reads the trkpt elements out of a gpx document. We only need the lat/lon attributes
+/// and the ele and time children, so the scanners of the xml module are enough here
+/// instead of a full xml parser. Points without a time are useless for matching and are skipped.
+fn parse_gpx_points(content: &str) -> Vec<TrackPoint> {
+    let mut points = vec![];
+    let mut rest = content;
+
+    while let Some(start) = rest.find("<trkpt") {
+        rest = &rest[start + "<trkpt".len()..];
+        let Some(tag_end) = rest.find('>') else {
+            break;
+        };
+        let attributes = &rest[..tag_end];
+        let body = if attributes.ends_with('/') {
+            ""
+        } else {
+            match rest.find("</trkpt>") {
+                Some(end) => &rest[tag_end + 1..end],
+                None => &rest[tag_end + 1..],
+            }
+        };
+
+        let latitude = xml::attribute(attributes, "lat").and_then(|v| v.parse::<f64>().ok());
+        let longitude = xml::attribute(attributes, "lon").and_then(|v| v.parse::<f64>().ok());
+        let time = xml::element_text(body, "time")
+            .and_then(|v| DateTime::parse_from_rfc3339(&v).ok())
+            .map(|time| time.with_timezone(&Utc));
+        let elevation = xml::element_text(body, "ele").and_then(|v| v.parse::<f64>().ok());
+
+        if let (Some(latitude), Some(longitude), Some(time)) = (latitude, longitude, time) {
+            points.push(TrackPoint {
+                time,
+                latitude,
+                longitude,
+                elevation,
+            });
+        }
+        rest = &rest[tag_end..];
+    }
+    points
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GeotagOptions {
+    // Added to the normalized capture time before matching. Use it for a camera clock
+    // which is a few minutes off, or for a camera set to local time which doesn't
+    // write OffsetTimeOriginal (e.g. -19800 for a camera set to IST).
+    pub camera_offset_seconds: i64,
+    // Photos further away in time than this from the track are left alone
+    pub max_gap_seconds: i64,
+    // By default images which already have coordinates are not touched
+    pub overwrite_existing: bool,
+}
+
+impl Default for GeotagOptions {
+    fn default() -> Self {
+        GeotagOptions {
+            camera_offset_seconds: 0,
+            max_gap_seconds: 300,
+            overwrite_existing: false,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GeotagMatch {
+    pub image_id: i64,
+    pub path: String,
+    // Normalized (utc) capture time after applying the camera offset
+    pub capture_time: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f64>,
+    pub gap_seconds: i64,
+}
+
+/// Matches the images in the given folder against the track and returns the proposed
+/// coordinates without changing anything, so that they can be shown to the user first.
+/// Pass the result (or the part of it the user accepted) to apply_geotags.
+pub async fn preview_geotags(
+    pool: &SqlitePool,
+    folder_path: &str,
+    track: &Track,
+    options: &GeotagOptions,
+) -> Result<Vec<GeotagMatch>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"select i.id, lf.path, i.capture_time, e.datetime_original, e.offset_time_original, e.latitude
+        from image as i join library_file as lf on lf.id = i.library_file_id
+        left join exif as e on e.image_id = i.id
+        where lf.parent_path = ?
+        group by i.id"#,
+    )
+    .bind(folder_path)
+    .fetch_all(pool)
+    .await?;
+
+    let max_gap = Duration::seconds(options.max_gap_seconds);
+    let camera_offset = Duration::seconds(options.camera_offset_seconds);
+    let mut matches = vec![];
+    for row in rows {
+        if !options.overwrite_existing && row.get::<Option<f64>, _>("latitude").is_some() {
+            continue;
+        }
+        // Prefer the time the shutter was pressed, capture_time is the exif DateTime
+        // (last modified) or the file creation time
+        let offset = row.get::<Option<String>, _>("offset_time_original");
+        let capture_time = row
+            .get::<Option<String>, _>("datetime_original")
+            .and_then(|datetime| image_helpers::parse_capture_time(&datetime, offset.as_deref()))
+            .or_else(|| {
+                row.get::<Option<String>, _>("capture_time")
+                    .and_then(|datetime| image_helpers::parse_capture_time(&datetime, None))
+            });
+        let Some(capture_time) = capture_time else {
+            continue;
+        };
+        let capture_time = capture_time + camera_offset;
+
+        if let Some(position) = track.position_at(capture_time, max_gap) {
+            matches.push(GeotagMatch {
+                image_id: row.get::<i64, _>("id"),
+                path: row.get::<String, _>("path"),
+                capture_time: capture_time.to_rfc3339(),
+                latitude: position.latitude,
+                longitude: position.longitude,
+                altitude: position.elevation,
+                gap_seconds: position.gap_seconds,
+            });
+        }
+    }
+    Ok(matches)
+}
+
+/// Writes the matched coordinates into the exif gps columns of the catalog
+pub async fn apply_geotags(pool: &SqlitePool, matches: &[GeotagMatch]) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let mut updated = 0;
+    for geotag in matches {
+        let query_result = sqlx::query(
+            "UPDATE exif set latitude=?, longitude=?, altitude=?, modified_at=CURRENT_TIMESTAMP where image_id=?",
+        )
+        .bind(geotag.latitude)
+        .bind(geotag.longitude)
+        .bind(geotag.altitude)
+        .bind(geotag.image_id)
+        .execute(&mut *tx)
+        .await?;
+        updated += query_result.rows_affected();
+    }
+    tx.commit().await?;
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    const GPX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gpx version="1.1" creator="tracker">
+  <trk><name>Walk</name><trkseg>
+    <trkpt lat="12.9700" lon="77.5900"><ele>900</ele><time>2024-03-24T06:20:00Z</time></trkpt>
+    <trkpt lat="12.9800" lon="77.6000"><ele>920</ele><time>2024-03-24T06:22:00Z</time></trkpt>
+    <trkpt lon="77.6100" lat='12.9900'><time>2024-03-24T06:40:00Z</time></trkpt>
+    <trkpt lat="13.0000" lon="77.7000"/>
+  </trkseg></trk>
+</gpx>"#;
+
+    fn time(value: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(value)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_parse_gpx() {
+        let track = Track::from_gpx_str(GPX);
+        assert_eq!(track.points().len(), 3);
+        assert_eq!(track.points()[0].elevation, Some(900.0));
+        assert_eq!(track.points()[2].latitude, 12.99);
+        assert_eq!(track.points()[2].elevation, None);
+    }
+
+    #[test]
+    fn test_load_and_merge_tracks() {
+        let dir = tempfile::tempdir().unwrap();
+        let morning = dir.path().join("morning.gpx");
+        let earlier = dir.path().join("earlier.gpx");
+        fs::write(&morning, GPX).unwrap();
+        fs::write(
+            &earlier,
+            r#"<gpx><trk><trkseg>
+    <trkpt lat="12.9600" lon="77.5800"><time>2024-03-24T06:10:00Z</time></trkpt>
+    <trkpt lat="12.9650" lon="77.5850"><time>2024-03-24T06:15:00Z</time></trkpt>
+  </trkseg></trk></gpx>"#,
+        )
+        .unwrap();
+
+        let mut track = Track::from_gpx_file(&morning).unwrap();
+        track.merge(Track::from_gpx_file(&earlier).unwrap());
+        let times: Vec<String> = track
+            .points()
+            .iter()
+            .map(|point| point.time.format("%H:%M").to_string())
+            .collect();
+        assert_eq!(times, vec!["06:10", "06:15", "06:20", "06:22", "06:40"]);
+        // The gap between the two files is bridged like any other
+        let position = track
+            .position_at(time("2024-03-24T06:17:30Z"), Duration::seconds(300))
+            .unwrap();
+        assert!((position.latitude - 12.9675).abs() < 1e-9);
+
+        assert!(Track::from_gpx_file(&dir.path().join("missing.gpx")).is_err());
+    }
+
+    #[test]
+    fn test_position_at() {
+        let track = Track::from_gpx_str(GPX);
+        let max_gap = Duration::seconds(300);
+
+        let position = track
+            .position_at(time("2024-03-24T06:21:00Z"), max_gap)
+            .unwrap();
+        assert!((position.latitude - 12.975).abs() < 1e-9);
+        assert!((position.longitude - 77.595).abs() < 1e-9);
+        assert_eq!(position.elevation, Some(910.0));
+        assert_eq!(position.gap_seconds, 60);
+
+        // The last two points are 18 minutes apart, so we only snap to the nearest one
+        let position = track
+            .position_at(time("2024-03-24T06:24:00Z"), max_gap)
+            .unwrap();
+        assert_eq!(position.latitude, 12.98);
+        assert_eq!(position.gap_seconds, 120);
+        assert_eq!(
+            track.position_at(time("2024-03-24T06:30:00Z"), max_gap),
+            None
+        );
+        assert_eq!(
+            track.position_at(time("2024-03-24T05:00:00Z"), max_gap),
+            None
+        );
+    }
+
+    #[sqlx::test(fixtures("library_file", "image", "exif", "iptc"))]
+    async fn test_preview_and_apply_geotags(pool: SqlitePool) -> sqlx::Result<()> {
+        // Camera set to IST, without OffsetTimeOriginal
+        sqlx::query(
+            "UPDATE exif set datetime_original='2024:03:24 11:51:00' where image_id in (1, 2)",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query("UPDATE exif set datetime_original='2024:03:24 13:00:00' where image_id=3")
+            .execute(&pool)
+            .await?;
+        let track = Track::from_gpx_str(GPX);
+
+        let options = GeotagOptions::default();
+        let matches = preview_geotags(&pool, "/Users/fancy-name/Desktop", &track, &options).await?;
+        assert_eq!(matches.len(), 0);
+
+        let options = GeotagOptions {
+            camera_offset_seconds: -19800,
+            ..Default::default()
+        };
+        let matches = preview_geotags(&pool, "/Users/fancy-name/Desktop", &track, &options).await?;
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].capture_time, "2024-03-24T06:21:00+00:00");
+
+        // Nothing is written until the matches are applied
+        let located = db::get_images_within_radius(&pool, 12.975, 77.595, 1.0).await?;
+        assert_eq!(located.len(), 0);
+        assert_eq!(apply_geotags(&pool, &matches).await?, 2);
+        let located = db::get_images_within_radius(&pool, 12.975, 77.595, 1.0).await?;
+        assert_eq!(located.len(), 2);
+
+        // Already geotagged images are skipped unless asked otherwise
+        let matches = preview_geotags(&pool, "/Users/fancy-name/Desktop", &track, &options).await?;
+        assert_eq!(matches.len(), 0);
+        Ok(())
+    }
+}
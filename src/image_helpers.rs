@@ -1,4 +1,12 @@
-use std::path::Path;
+use crate::media;
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone, Utc};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
 
 // TODO: What if instead we represented exif as vector of tuples like this Vec<(exif::Tag, ValueTypeEnum)>?
 // That will make it easier to construct from the exif.fields() from exif crate
@@ -21,7 +29,7 @@ pub struct Exif {
     pub exposure_time: Option<String>,
     pub f_number: Option<String>,
     pub exposure_program: Option<String>,
-    pub photographic_sensitivty: Option<String>,
+    pub photographic_sensitivity: Option<String>,
     pub sensitivity_type: Option<String>,
     pub iso_speed: Option<String>,
     pub exif_version: Option<String>,
@@ -63,10 +71,358 @@ pub struct Exif {
     pub gps_timestamp: Option<String>,
     pub gps_status: Option<String>,
     pub orientation: Option<String>,
-    // pub title: Option<String>,
     pub description: Option<String>,
     pub user_comment: Option<String>,
     pub artist: Option<String>,
+    pub exposure_bias: Option<String>,
+    // Computed from the gps tags, see EXIF_DERIVED_COLUMNS
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub altitude: Option<f64>,
+    // Typed values and display strings computed from the exposure tags
+    pub exposure_seconds: Option<f64>,
+    pub f_stop: Option<f64>,
+    pub iso: Option<u32>,
+    pub focal_length_mm: Option<f64>,
+    pub focal_length_35mm: Option<f64>,
+    pub exposure_display: Option<String>,
+    pub f_stop_display: Option<String>,
+    pub iso_display: Option<String>,
+    pub focal_length_display: Option<String>,
+}
+
+/// Maps an exif table column to the exiv2 tag it is read from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MetadataField {
+    pub column: &'static str,
+    pub tag: &'static str,
+}
+
+const fn field(column: &'static str, tag: &'static str) -> MetadataField {
+    MetadataField { column, tag }
+}
+
+/// Every exif value we store. The insert code, the exif table (see the migrations) and
+/// the Exif struct all follow this list, and the tests make sure they don't drift apart.
+pub const EXIF_FIELDS: &[MetadataField] = &[
+    field("camera_make", "Exif.Image.Make"),
+    field("camera_model", "Exif.Image.Model"),
+    field("x_resolution", "Exif.Image.XResolution"),
+    field("y_resolution", "Exif.Image.YResolution"),
+    field("resolution_unit", "Exif.Image.ResolutionUnit"),
+    field("software", "Exif.Image.Software"),
+    field("copyright", "Exif.Image.Copyright"),
+    field("exposure_time", "Exif.Photo.ExposureTime"),
+    field("f_number", "Exif.Photo.FNumber"),
+    field("exposure_program", "Exif.Photo.ExposureProgram"),
+    field("photographic_sensitivity", "Exif.Photo.ISOSpeedRatings"),
+    field("sensitivity_type", "Exif.Photo.SensitivityType"),
+    field("iso_speed", "Exif.Photo.ISOSpeed"),
+    field("exif_version", "Exif.Photo.ExifVersion"),
+    field("datetime", "Exif.Image.DateTime"),
+    field("offset_time", "Exif.Photo.OffsetTime"),
+    field("datetime_original", "Exif.Photo.DateTimeOriginal"),
+    field("offset_time_original", "Exif.Photo.OffsetTimeOriginal"),
+    field("shutter_speed_value", "Exif.Photo.ShutterSpeedValue"),
+    field("aperture_value", "Exif.Photo.ApertureValue"),
+    field("brightness_value", "Exif.Photo.BrightnessValue"),
+    field("max_aperture_value", "Exif.Photo.MaxApertureValue"),
+    field("metering_mode", "Exif.Photo.MeteringMode"),
+    field("light_source", "Exif.Photo.LightSource"),
+    field("flash", "Exif.Photo.Flash"),
+    field("focal_length", "Exif.Photo.FocalLength"),
+    field("pixel_x_dimension", "Exif.Photo.PixelXDimension"),
+    field("pixel_y_dimension", "Exif.Photo.PixelYDimension"),
+    field("sensing_method", "Exif.Photo.SensingMethod"),
+    field("file_source", "Exif.Photo.FileSource"),
+    field("scene_type", "Exif.Photo.SceneType"),
+    field("custom_rendered", "Exif.Photo.CustomRendered"),
+    field("exposure_mode", "Exif.Photo.ExposureMode"),
+    field("white_balance", "Exif.Photo.WhiteBalance"),
+    field(
+        "focal_length_in_35mm_film",
+        "Exif.Photo.FocalLengthIn35mmFilm",
+    ),
+    field("scene_capture_type", "Exif.Photo.SceneCaptureType"),
+    field("sharpness", "Exif.Photo.Sharpness"),
+    field("subject_distance", "Exif.Photo.SubjectDistance"),
+    field("subject_distance_range", "Exif.Photo.SubjectDistanceRange"),
+    field("lens_specification", "Exif.Photo.LensSpecification"),
+    field("lens_make", "Exif.Photo.LensMake"),
+    field("lens_model", "Exif.Photo.LensModel"),
+    field("compression", "Exif.Image.Compression"),
+    field("body_serial_number", "Exif.Photo.BodySerialNumber"),
+    field("saturation", "Exif.Photo.Saturation"),
+    field("contrast", "Exif.Photo.Contrast"),
+    field("gps_latitude", "Exif.GPSInfo.GPSLatitude"),
+    field("gps_longitude", "Exif.GPSInfo.GPSLongitude"),
+    field("gps_altitude", "Exif.GPSInfo.GPSAltitude"),
+    field("gps_timestamp", "Exif.GPSInfo.GPSTimeStamp"),
+    field("gps_status", "Exif.GPSInfo.GPSStatus"),
+    field("orientation", "Exif.Image.Orientation"),
+    field("description", "Exif.Image.ImageDescription"),
+    field("user_comment", "Exif.Photo.UserComment"),
+    field("artist", "Exif.Image.Artist"),
+    field("exposure_bias", "Exif.Photo.ExposureBiasValue"),
+];
+
+#[derive(sqlx::FromRow, serde::Serialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Iptc {
+    pub image_id: i64,
+    pub copyright: Option<String>,
+    pub city: Option<String>,
+    pub creator: Option<String>,
+    pub country_iso_code: Option<String>,
+    pub country_name: Option<String>,
+    pub description: Option<String>,
+    pub title: Option<String>,
+    pub headline: Option<String>,
+    pub sublocation: Option<String>,
+    pub province_state: Option<String>,
+    pub credit: Option<String>,
+    pub source: Option<String>,
+    pub usage_terms: Option<String>,
+    pub creator_job_title: Option<String>,
+    pub description_writer: Option<String>,
+    pub instructions: Option<String>,
+    pub job_id: Option<String>,
+    pub date_created: Option<String>,
+    pub intellectual_genre: Option<String>,
+    pub subject_codes: Option<String>,
+    pub scene_codes: Option<String>,
+    pub contact_address: Option<String>,
+    pub contact_city: Option<String>,
+    pub contact_region: Option<String>,
+    pub contact_postal_code: Option<String>,
+    pub contact_country: Option<String>,
+    pub contact_email: Option<String>,
+    pub contact_phone: Option<String>,
+    pub contact_url: Option<String>,
+    pub event: Option<String>,
+    pub persons_shown: Option<String>,
+    pub organisations_shown: Option<String>,
+    pub digital_source_type: Option<String>,
+}
+
+/// Maps an iptc table column to the IIM dataset and the xmp property it can be read
+/// from. Fields added by IPTC Core and Extension only exist in xmp.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IptcField {
+    pub column: &'static str,
+    pub iim: Option<&'static str>,
+    pub xmp: &'static str,
+}
+
+const fn iptc_field(
+    column: &'static str,
+    iim: Option<&'static str>,
+    xmp: &'static str,
+) -> IptcField {
+    IptcField { column, iim, xmp }
+}
+
+/// Fields with more than one value (creators, persons shown etc.) are stored joined
+/// with this
+pub const IPTC_LIST_SEPARATOR: &str = "; ";
+
+/// Every iptc value we store, following the IPTC Core and Extension schemas. Like
+/// EXIF_FIELDS, the tests make sure this matches the iptc table and the Iptc struct.
+pub const IPTC_FIELDS: &[IptcField] = &[
+    iptc_field(
+        "copyright",
+        Some("Iptc.Application2.Copyright"),
+        "Xmp.dc.rights",
+    ),
+    iptc_field("city", Some("Iptc.Application2.City"), "Xmp.photoshop.City"),
+    iptc_field(
+        "creator",
+        Some("Iptc.Application2.Byline"),
+        "Xmp.dc.creator",
+    ),
+    iptc_field(
+        "country_iso_code",
+        Some("Iptc.Application2.CountryCode"),
+        "Xmp.iptc.CountryCode",
+    ),
+    iptc_field(
+        "country_name",
+        Some("Iptc.Application2.CountryName"),
+        "Xmp.photoshop.Country",
+    ),
+    iptc_field(
+        "description",
+        Some("Iptc.Application2.Caption"),
+        "Xmp.dc.description",
+    ),
+    iptc_field(
+        "title",
+        Some("Iptc.Application2.ObjectName"),
+        "Xmp.dc.title",
+    ),
+    iptc_field(
+        "headline",
+        Some("Iptc.Application2.Headline"),
+        "Xmp.photoshop.Headline",
+    ),
+    iptc_field(
+        "sublocation",
+        Some("Iptc.Application2.SubLocation"),
+        "Xmp.iptc.Location",
+    ),
+    iptc_field(
+        "province_state",
+        Some("Iptc.Application2.ProvinceState"),
+        "Xmp.photoshop.State",
+    ),
+    iptc_field(
+        "credit",
+        Some("Iptc.Application2.Credit"),
+        "Xmp.photoshop.Credit",
+    ),
+    iptc_field(
+        "source",
+        Some("Iptc.Application2.Source"),
+        "Xmp.photoshop.Source",
+    ),
+    iptc_field("usage_terms", None, "Xmp.xmpRights.UsageTerms"),
+    iptc_field(
+        "creator_job_title",
+        Some("Iptc.Application2.BylineTitle"),
+        "Xmp.photoshop.AuthorsPosition",
+    ),
+    iptc_field(
+        "description_writer",
+        Some("Iptc.Application2.Writer"),
+        "Xmp.photoshop.CaptionWriter",
+    ),
+    iptc_field(
+        "instructions",
+        Some("Iptc.Application2.SpecialInstructions"),
+        "Xmp.photoshop.Instructions",
+    ),
+    iptc_field(
+        "job_id",
+        Some("Iptc.Application2.TransmissionReference"),
+        "Xmp.photoshop.TransmissionReference",
+    ),
+    iptc_field(
+        "date_created",
+        Some("Iptc.Application2.DateCreated"),
+        "Xmp.photoshop.DateCreated",
+    ),
+    iptc_field(
+        "intellectual_genre",
+        Some("Iptc.Application2.ObjectAttribute"),
+        "Xmp.iptc.IntellectualGenre",
+    ),
+    iptc_field(
+        "subject_codes",
+        Some("Iptc.Application2.Subject"),
+        "Xmp.iptc.SubjectCode",
+    ),
+    iptc_field("scene_codes", None, "Xmp.iptc.Scene"),
+    iptc_field(
+        "contact_address",
+        None,
+        "Xmp.iptc.CreatorContactInfo/Iptc4xmpCore:CiAdrExtadr",
+    ),
+    iptc_field(
+        "contact_city",
+        None,
+        "Xmp.iptc.CreatorContactInfo/Iptc4xmpCore:CiAdrCity",
+    ),
+    iptc_field(
+        "contact_region",
+        None,
+        "Xmp.iptc.CreatorContactInfo/Iptc4xmpCore:CiAdrRegion",
+    ),
+    iptc_field(
+        "contact_postal_code",
+        None,
+        "Xmp.iptc.CreatorContactInfo/Iptc4xmpCore:CiAdrPcode",
+    ),
+    iptc_field(
+        "contact_country",
+        None,
+        "Xmp.iptc.CreatorContactInfo/Iptc4xmpCore:CiAdrCtry",
+    ),
+    iptc_field(
+        "contact_email",
+        None,
+        "Xmp.iptc.CreatorContactInfo/Iptc4xmpCore:CiEmailWork",
+    ),
+    iptc_field(
+        "contact_phone",
+        None,
+        "Xmp.iptc.CreatorContactInfo/Iptc4xmpCore:CiTelWork",
+    ),
+    iptc_field(
+        "contact_url",
+        None,
+        "Xmp.iptc.CreatorContactInfo/Iptc4xmpCore:CiUrlWork",
+    ),
+    iptc_field("event", None, "Xmp.iptcExt.Event"),
+    iptc_field("persons_shown", None, "Xmp.iptcExt.PersonInImage"),
+    iptc_field(
+        "organisations_shown",
+        None,
+        "Xmp.iptcExt.OrganisationInImageName",
+    ),
+    iptc_field("digital_source_type", None, "Xmp.iptcExt.DigitalSourceType"),
+];
+
+pub fn iptc_field_for_column(column: &str) -> Option<&'static IptcField> {
+    IPTC_FIELDS.iter().find(|field| field.column == column)
+}
+
+/// exif columns which aren't read from a single tag but computed while importing
+pub const EXIF_DERIVED_COLUMNS: &[&str] = &[
+    "latitude",
+    "longitude",
+    "altitude",
+    "exposure_seconds",
+    "f_stop",
+    "iso",
+    "focal_length_mm",
+    "focal_length_35mm",
+    "exposure_display",
+    "f_stop_display",
+    "iso_display",
+    "focal_length_display",
+];
+
+/// The same as file_hash, for a file which was read already
+pub fn hash_bytes(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// sha256 of the file content as a hex string. Used to tell whether a file really
+/// changed when its modified time did.
+pub fn file_hash(path: &Path) -> Result<String, std::io::Error> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Modified time in the same format as library_file.file_modified_time
+pub fn file_modified_time(path: &Path) -> Result<String, std::io::Error> {
+    let modified_time: DateTime<Utc> = std::fs::metadata(path)?.modified()?.into();
+    Ok(modified_time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string())
+}
+
+/// Whether a file is picked up by the import. The extension is only a hint, a misnamed
+/// or extensionless file is imported when its content is something we know.
+pub fn is_importable_file(path: &Path) -> bool {
+    is_image_file(path) || media::is_video_file(path) || sniff_format(path).is_some()
 }
 
 pub fn is_image_file(path: &Path) -> bool {
@@ -74,7 +430,8 @@ pub fn is_image_file(path: &Path) -> bool {
 }
 pub fn is_regular_image(path: &Path) -> bool {
     let image_extensions = [
-        "jpg", "png", "tif", "jpeg", "jpe", "gif", "bmp", "webp", "tiff",
+        "jpg", "png", "tif", "jpeg", "jpe", "gif", "bmp", "webp", "tiff", "heic", "heif", "avif",
+        "jxl",
     ];
     if let Some(extension) = path.extension() {
         let extension = extension.to_ascii_lowercase();
@@ -87,7 +444,7 @@ pub fn is_regular_image(path: &Path) -> bool {
 pub fn is_raw_image(path: &Path) -> bool {
     let raw_image_extensions = [
         "raf", "cr2", "mrw", "arw", "srf", "sr2", "mef", "orf", "srw", "erf", "kdc", "dcs", "rw2",
-        "dcr", "dng", "pef", "crw", "raw", "iiq", "3rf", "nrw", "nef", "mos", "ari",
+        "dcr", "dng", "pef", "crw", "raw", "iiq", "3rf", "nrw", "nef", "mos", "ari", "cr3",
     ];
     if let Some(extension) = path.extension() {
         let extension = extension.to_ascii_lowercase();
@@ -97,3 +454,729 @@ pub fn is_raw_image(path: &Path) -> bool {
         return false;
     }
 }
+
+/// Parses an exif rational like "28/10" into a float. Plain numbers ("400") are
+/// accepted too since some tags are stored that way.
+pub fn parse_rational(value: &str) -> Option<f64> {
+    let value = value.trim();
+    match value.split_once('/') {
+        Some((numerator, denominator)) => {
+            let numerator = numerator.trim().parse::<f64>().ok()?;
+            let denominator = denominator.trim().parse::<f64>().ok()?;
+            if denominator == 0.0 {
+                return None;
+            }
+            Some(numerator / denominator)
+        }
+        None => value.parse::<f64>().ok(),
+    }
+}
+
+/// Converts the raw GPSLatitude/GPSLongitude value exiv2 gives us, which is three
+/// rationals for degrees, minutes and seconds ("12/1 58/1 1776/100"), into signed
+/// decimal degrees. The reference is the matching GPSLatitudeRef/GPSLongitudeRef
+/// tag and makes the value negative for the southern and western hemispheres.
+pub fn gps_to_decimal(value: &str, reference: &str) -> Option<f64> {
+    let mut parts = value.split_whitespace().map(parse_rational);
+    let degrees = parts.next()??;
+    let minutes = parts.next().unwrap_or(Some(0.0))?;
+    let seconds = parts.next().unwrap_or(Some(0.0))?;
+    let decimal = degrees + minutes / 60.0 + seconds / 3600.0;
+
+    match reference.trim().to_ascii_uppercase().as_str() {
+        "S" | "W" => Some(-decimal),
+        _ => Some(decimal),
+    }
+}
+
+/// GPSAltitude is an unsigned rational in meters, GPSAltitudeRef "1" means it is
+/// below sea level
+pub fn gps_altitude_to_meters(value: &str, reference: Option<&str>) -> Option<f64> {
+    let altitude = parse_rational(value)?;
+    match reference.map(str::trim) {
+        Some("1") => Some(-altitude),
+        _ => Some(altitude),
+    }
+}
+
+/// Turns an exif date ("2024:03:24 11:51:54") into a UTC timestamp. Exif dates have no
+/// timezone, so the matching OffsetTime* value ("+05:30") is used when we have it,
+/// otherwise the time is taken as UTC. Dates we wrote ourselves from file times are
+/// RFC 3339 strings and are accepted too.
+pub fn parse_capture_time(datetime: &str, offset: Option<&str>) -> Option<DateTime<Utc>> {
+    let datetime = datetime.trim();
+    if let Ok(parsed) = DateTime::parse_from_rfc3339(datetime) {
+        return Some(parsed.with_timezone(&Utc));
+    }
+    let naive = NaiveDateTime::parse_from_str(datetime, "%Y:%m:%d %H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(datetime, "%Y-%m-%d %H:%M:%S"))
+        .ok()?;
+    match offset.and_then(parse_utc_offset) {
+        Some(offset) => offset
+            .from_local_datetime(&naive)
+            .single()
+            .map(|parsed| parsed.with_timezone(&Utc)),
+        None => Some(Utc.from_utc_datetime(&naive)),
+    }
+}
+
+/// Parses exif OffsetTime values like "+05:30" or "-08:00"
+pub fn parse_utc_offset(offset: &str) -> Option<FixedOffset> {
+    let offset = offset.trim();
+    let sign = match offset.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+    let (hours, minutes) = offset[1..].split_once(':')?;
+    let seconds = hours.parse::<i32>().ok()? * 3600 + minutes.parse::<i32>().ok()? * 60;
+    FixedOffset::east_opt(sign * seconds)
+}
+
+/// Numeric versions of the exposure tags, which are stored as raw strings like "10/2500"
+/// and can't be compared in sql
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ExposureValues {
+    pub exposure_seconds: Option<f64>,
+    pub f_stop: Option<f64>,
+    pub iso: Option<u32>,
+    pub focal_length_mm: Option<f64>,
+    pub focal_length_35mm: Option<f64>,
+}
+
+impl ExposureValues {
+    /// `value` returns the raw string of an exif column (see EXIF_FIELDS), so this works
+    /// both while importing and for rows which are already in the catalog
+    pub fn from_exif_columns<F: Fn(&str) -> Option<String>>(value: F) -> ExposureValues {
+        let number = |column: &str| value(column).and_then(|value| parse_rational(&value));
+
+        // ShutterSpeedValue and ApertureValue are APEX values, which we only use when the
+        // camera didn't write ExposureTime or FNumber
+        let exposure_seconds = number("exposure_time")
+            .or_else(|| number("shutter_speed_value").map(|tv| 2f64.powf(-tv)))
+            .filter(|seconds| *seconds > 0.0);
+        let f_stop = number("f_number")
+            .or_else(|| number("aperture_value").map(|av| 2f64.powf(av / 2.0)))
+            .filter(|f_stop| *f_stop > 0.0);
+        // ISOSpeedRatings can hold more than one value, the first one is the one we want
+        let iso = value("photographic_sensitivity")
+            .or_else(|| value("iso_speed"))
+            .and_then(|iso| iso.split_whitespace().next().and_then(parse_rational))
+            .filter(|iso| *iso > 0.0)
+            .map(|iso| iso.round() as u32);
+        let focal_length_mm = number("focal_length").filter(|mm| *mm > 0.0);
+        // 0 means the camera doesn't know the equivalent focal length
+        let focal_length_35mm = number("focal_length_in_35mm_film").filter(|mm| *mm > 0.0);
+
+        ExposureValues {
+            exposure_seconds,
+            f_stop,
+            iso,
+            focal_length_mm,
+            focal_length_35mm,
+        }
+    }
+
+    /// "1/250 s" for fast shutter speeds, "2 s" or "1.3 s" for long exposures
+    pub fn exposure_display(&self) -> Option<String> {
+        let seconds = self.exposure_seconds?;
+        if seconds >= 1.0 {
+            Some(format!("{} s", format_decimal(seconds)))
+        } else {
+            Some(format!("1/{} s", (1.0 / seconds).round()))
+        }
+    }
+
+    pub fn f_stop_display(&self) -> Option<String> {
+        self.f_stop
+            .map(|f_stop| format!("f/{}", format_decimal(f_stop)))
+    }
+
+    pub fn iso_display(&self) -> Option<String> {
+        self.iso.map(|iso| format!("ISO {}", iso))
+    }
+
+    pub fn focal_length_display(&self) -> Option<String> {
+        self.focal_length_mm
+            .map(|mm| format!("{} mm", format_decimal(mm)))
+    }
+}
+
+// One decimal at most and no trailing ".0", the way cameras show these values
+fn format_decimal(value: f64) -> String {
+    let formatted = format!("{:.1}", value);
+    formatted
+        .strip_suffix(".0")
+        .map(|value| value.to_string())
+        .unwrap_or(formatted)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GpsLocation {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f64>,
+}
+
+/// Reads the GPS position of an image as decimal degrees. Returns None when the
+/// image has no latitude or longitude.
+pub fn read_gps_location(meta: &rexiv2::Metadata) -> Option<GpsLocation> {
+    let latitude_ref = meta
+        .get_tag_string("Exif.GPSInfo.GPSLatitudeRef")
+        .unwrap_or_default();
+    let longitude_ref = meta
+        .get_tag_string("Exif.GPSInfo.GPSLongitudeRef")
+        .unwrap_or_default();
+    let latitude = gps_to_decimal(
+        &meta.get_tag_string("Exif.GPSInfo.GPSLatitude").ok()?,
+        &latitude_ref,
+    )?;
+    let longitude = gps_to_decimal(
+        &meta.get_tag_string("Exif.GPSInfo.GPSLongitude").ok()?,
+        &longitude_ref,
+    )?;
+    let altitude_ref = meta.get_tag_string("Exif.GPSInfo.GPSAltitudeRef").ok();
+    let altitude = meta
+        .get_tag_string("Exif.GPSInfo.GPSAltitude")
+        .ok()
+        .and_then(|altitude| gps_altitude_to_meters(&altitude, altitude_ref.as_deref()));
+
+    Some(GpsLocation {
+        latitude,
+        longitude,
+        altitude,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageHeader {
+    pub format: &'static str,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Reads the format and pixel dimensions straight from the image header. Used when
+/// exiv2 can't read the file, so that we still know what kind of image it is.
+pub fn read_image_header(path: &Path) -> Option<ImageHeader> {
+    let file = File::open(path).ok()?;
+    parse_image_header(&mut BufReader::new(file))
+}
+
+/// Works out what a file really is from its first bytes, whatever its extension says.
+/// Unlike read_image_header this doesn't need to understand the rest of the header,
+/// so it also recognises raw files and videos.
+pub fn sniff_format(path: &Path) -> Option<&'static str> {
+    let mut file = File::open(path).ok()?;
+    let mut start = [0u8; 32];
+    let read = read_up_to(&mut file, &mut start)?;
+    detect_format(&start[..read])
+}
+
+pub fn detect_format(start: &[u8]) -> Option<&'static str> {
+    if start.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return Some("jpeg");
+    }
+    if start.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+        return Some("png");
+    }
+    if start.starts_with(b"GIF87a") || start.starts_with(b"GIF89a") {
+        return Some("gif");
+    }
+    if start.starts_with(b"RIFF") && start.get(8..12) == Some(&b"WEBP"[..]) {
+        return Some("webp");
+    }
+    // Only the file header and the dib header size, "BM" alone is too likely in text
+    if start.starts_with(b"BM")
+        && start.len() >= 18
+        && [12, 40, 52, 56, 64, 108, 124]
+            .contains(&u32::from_le_bytes(start[14..18].try_into().ok()?))
+    {
+        return Some("bmp");
+    }
+    if start.starts_with(b"FUJIFILMCCD-RAW") {
+        return Some("raf");
+    }
+    if start.starts_with(b"\0MRM") {
+        return Some("mrw");
+    }
+    // Raw formats which are tiff with their own magic number
+    if start.starts_with(b"IIRO") || start.starts_with(b"IIRS") || start.starts_with(b"MMOR") {
+        return Some("orf");
+    }
+    if start.starts_with(b"IIU\0") {
+        return Some("rw2");
+    }
+    if start.starts_with(b"II\x1A\0") && start.get(6..14) == Some(&b"HEAPCCDR"[..]) {
+        return Some("crw");
+    }
+    if start.starts_with(b"II*\0") || start.starts_with(b"MM\0*") {
+        // Most raw formats (dng, nef, arw, ...) are plain tiff at this level, only
+        // cr2 marks itself
+        if start.get(8..10) == Some(&b"CR"[..]) {
+            return Some("cr2");
+        }
+        return Some("tiff");
+    }
+    if start.get(4..8) == Some(&b"ftyp"[..]) {
+        let size = u32::from_be_bytes(start[0..4].try_into().ok()?) as usize;
+        return media::bmff_format(start.get(8..size.min(start.len()))?);
+    }
+    if start.starts_with(&[0xFF, 0x0A]) || start.starts_with(b"\0\0\0\x0CJXL \r\n\x87\n") {
+        return Some("jxl");
+    }
+    None
+}
+
+/// Extensions a file of the given sniffed format can have. Tiff covers the raw
+/// formats which can't be told apart from tiff by their magic number.
+pub fn format_extensions(format: &str) -> &'static [&'static str] {
+    match format {
+        "jpeg" => &["jpg", "jpeg", "jpe", "jfif"],
+        "png" => &["png"],
+        "gif" => &["gif"],
+        "webp" => &["webp"],
+        "bmp" => &["bmp"],
+        "tiff" => &[
+            "tif", "tiff", "dng", "nef", "nrw", "arw", "srf", "sr2", "pef", "srw", "erf", "kdc",
+            "dcr", "dcs", "mef", "mos", "iiq", "3fr", "ari", "raw",
+        ],
+        "raf" => &["raf"],
+        "mrw" => &["mrw"],
+        "orf" => &["orf"],
+        "rw2" => &["rw2", "raw"],
+        "crw" => &["crw"],
+        "cr2" => &["cr2"],
+        "cr3" => &["cr3"],
+        "heic" => &["heic", "heif", "hif"],
+        "avif" => &["avif"],
+        "jxl" => &["jxl"],
+        "mp4" => &["mp4", "m4v", "3gp"],
+        "mov" => &["mov", "qt"],
+        _ => &[],
+    }
+}
+
+/// Whether the extension of the file fits what it contains. A file without an
+/// extension never does.
+pub fn extension_matches_format(path: &Path, format: &str) -> bool {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .is_some_and(|extension| format_extensions(format).contains(&extension.as_str()))
+}
+
+pub fn parse_image_header<R: Read + Seek>(reader: &mut R) -> Option<ImageHeader> {
+    let mut start = [0u8; 32];
+    let read = read_up_to(reader, &mut start)?;
+    let start = &start[..read];
+
+    if start.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) && read >= 24 {
+        // The IHDR chunk always comes first
+        return Some(ImageHeader {
+            format: "png",
+            width: u32::from_be_bytes(start[16..20].try_into().ok()?),
+            height: u32::from_be_bytes(start[20..24].try_into().ok()?),
+        });
+    }
+    if (start.starts_with(b"GIF87a") || start.starts_with(b"GIF89a")) && read >= 10 {
+        return Some(ImageHeader {
+            format: "gif",
+            width: u16::from_le_bytes([start[6], start[7]]) as u32,
+            height: u16::from_le_bytes([start[8], start[9]]) as u32,
+        });
+    }
+    if start.starts_with(b"BM") && read >= 26 {
+        // Height is negative for top-down bitmaps
+        return Some(ImageHeader {
+            format: "bmp",
+            width: i32::from_le_bytes(start[18..22].try_into().ok()?).unsigned_abs(),
+            height: i32::from_le_bytes(start[22..26].try_into().ok()?).unsigned_abs(),
+        });
+    }
+    if start.starts_with(b"RIFF") && read >= 30 && &start[8..12] == b"WEBP" {
+        return parse_webp_header(start);
+    }
+    if start.starts_with(&[0xFF, 0xD8]) {
+        return parse_jpeg_header(reader);
+    }
+    if start.starts_with(b"II*\0") || start.starts_with(b"MM\0*") {
+        return parse_tiff_header(reader, start.starts_with(b"II"));
+    }
+    // HEIC, AVIF and videos all start with an ftyp box
+    if read >= 12 && &start[4..8] == b"ftyp" {
+        return media::parse_bmff_header(reader);
+    }
+    if start.starts_with(&[0xFF, 0x0A]) || start.starts_with(b"\0\0\0\x0CJXL \r\n\x87\n") {
+        return media::parse_jxl_header(reader);
+    }
+    None
+}
+
+fn read_up_to<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Option<usize> {
+    let mut total = 0;
+    while total < buffer.len() {
+        match reader.read(&mut buffer[total..]) {
+            Ok(0) => break,
+            Ok(read) => total += read,
+            Err(_) => return None,
+        }
+    }
+    Some(total)
+}
+
+fn parse_webp_header(start: &[u8]) -> Option<ImageHeader> {
+    let (width, height) = match &start[12..16] {
+        // Lossy, 14 bit dimensions after the key frame start code
+        b"VP8 " => (
+            (u16::from_le_bytes([start[26], start[27]]) & 0x3FFF) as u32,
+            (u16::from_le_bytes([start[28], start[29]]) & 0x3FFF) as u32,
+        ),
+        // Lossless, width-1 and height-1 packed in 14 bits each after the signature byte
+        b"VP8L" => {
+            let bits = u32::from_le_bytes(start[21..25].try_into().ok()?);
+            ((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1)
+        }
+        // Extended, 24 bit canvas width-1 and height-1
+        b"VP8X" => (
+            u32::from_le_bytes([start[24], start[25], start[26], 0]) + 1,
+            u32::from_le_bytes([start[27], start[28], start[29], 0]) + 1,
+        ),
+        _ => return None,
+    };
+    Some(ImageHeader {
+        format: "webp",
+        width,
+        height,
+    })
+}
+
+fn parse_jpeg_header<R: Read + Seek>(reader: &mut R) -> Option<ImageHeader> {
+    // Walk the segments until we find a start of frame marker, skipping the (possibly
+    // large) exif and other application segments on the way
+    reader.seek(SeekFrom::Start(2)).ok()?;
+    loop {
+        let mut marker = [0u8; 2];
+        reader.read_exact(&mut marker).ok()?;
+        if marker[0] != 0xFF {
+            return None;
+        }
+        // Fill bytes
+        if marker[1] == 0xFF {
+            reader.seek(SeekFrom::Current(-1)).ok()?;
+            continue;
+        }
+        let mut length = [0u8; 2];
+        reader.read_exact(&mut length).ok()?;
+        let length = u16::from_be_bytes(length) as i64;
+        let is_start_of_frame =
+            (0xC0..=0xCF).contains(&marker[1]) && ![0xC4, 0xC8, 0xCC].contains(&marker[1]);
+        if is_start_of_frame {
+            let mut frame = [0u8; 5];
+            reader.read_exact(&mut frame).ok()?;
+            return Some(ImageHeader {
+                format: "jpeg",
+                height: u16::from_be_bytes([frame[1], frame[2]]) as u32,
+                width: u16::from_be_bytes([frame[3], frame[4]]) as u32,
+            });
+        }
+        reader.seek(SeekFrom::Current(length - 2)).ok()?;
+    }
+}
+
+// Raw files based on tiff (nef, arw, dng, ...) keep a small preview in IFD0 and the
+// sensor data in a SubIFD or a later IFD, so every IFD is looked at. The largest image
+// marked as full resolution (NewSubfileType 0) wins.
+fn parse_tiff_header<R: Read + Seek>(reader: &mut R, little_endian: bool) -> Option<ImageHeader> {
+    let read_u16 = |bytes: [u8; 2]| {
+        if little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        }
+    };
+    let read_u32 = |bytes: [u8; 4]| {
+        if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    };
+    // Broken or crafted files can point IFDs at each other
+    const MAX_IFDS: usize = 32;
+
+    reader.seek(SeekFrom::Start(4)).ok()?;
+    let mut offset = [0u8; 4];
+    reader.read_exact(&mut offset).ok()?;
+    let mut pending = vec![read_u32(offset)];
+    let mut visited = HashSet::new();
+    // (full resolution, width, height)
+    let mut images = vec![];
+    while let Some(ifd_offset) = pending.pop() {
+        if ifd_offset == 0 || !visited.insert(ifd_offset) || visited.len() > MAX_IFDS {
+            continue;
+        }
+        if reader.seek(SeekFrom::Start(ifd_offset as u64)).is_err() {
+            continue;
+        }
+        let mut count = [0u8; 2];
+        if reader.read_exact(&mut count).is_err() {
+            continue;
+        }
+
+        let (mut width, mut height, mut subfile_type) = (None, None, 0);
+        let mut sub_ifds = vec![];
+        let mut complete = true;
+        for _ in 0..read_u16(count) {
+            let mut entry = [0u8; 12];
+            if reader.read_exact(&mut entry).is_err() {
+                complete = false;
+                break;
+            }
+            let tag = read_u16([entry[0], entry[1]]);
+            let field_type = read_u16([entry[2], entry[3]]);
+            let value_count = read_u32([entry[4], entry[5], entry[6], entry[7]]);
+            // SHORT values sit in the first two bytes of the value field, LONG use all four
+            let value = if field_type == 3 {
+                read_u16([entry[8], entry[9]]) as u32
+            } else {
+                read_u32([entry[8], entry[9], entry[10], entry[11]])
+            };
+            match tag {
+                254 => subfile_type = value,
+                256 => width = Some(value),
+                257 => height = Some(value),
+                330 => sub_ifds.push((value_count, value)),
+                _ => {}
+            }
+        }
+        if complete {
+            let mut next = [0u8; 4];
+            if reader.read_exact(&mut next).is_ok() {
+                pending.push(read_u32(next));
+            }
+        }
+        for (count, value) in sub_ifds {
+            // One offset fits in the entry, more are stored elsewhere
+            if count == 1 {
+                pending.push(value);
+                continue;
+            }
+            if reader.seek(SeekFrom::Start(value as u64)).is_err() {
+                continue;
+            }
+            for _ in 0..count.min(MAX_IFDS as u32) {
+                let mut item = [0u8; 4];
+                if reader.read_exact(&mut item).is_err() {
+                    break;
+                }
+                pending.push(read_u32(item));
+            }
+        }
+        if let (Some(width), Some(height)) = (width, height) {
+            images.push((subfile_type & 1 == 0, width, height));
+        }
+    }
+    let (_, width, height) =
+        images
+            .into_iter()
+            .max_by_key(|(full_resolution, width, height)| {
+                (*full_resolution, *width as u64 * *height as u64)
+            })?;
+    Some(ImageHeader {
+        format: "tiff",
+        width,
+        height,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_parse_image_header() {
+        let png = [
+            0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 13, b'I', b'H', b'D', b'R', 0,
+            0, 0x02, 0x80, 0, 0, 0x01, 0xE0, 8, 2, 0, 0, 0,
+        ];
+        let header = parse_image_header(&mut Cursor::new(png)).unwrap();
+        assert_eq!(header.format, "png");
+        assert_eq!((header.width, header.height), (640, 480));
+
+        // SOI, an APP0 segment and then the baseline frame header
+        let jpeg = [
+            0xFF, 0xD8, 0xFF, 0xE0, 0, 6, b'J', b'F', b'I', b'F', 0xFF, 0xC0, 0, 17, 8, 0x0F, 0xA0,
+            0x17, 0x70, 3,
+        ];
+        let header = parse_image_header(&mut Cursor::new(jpeg)).unwrap();
+        assert_eq!(header.format, "jpeg");
+        assert_eq!((header.width, header.height), (6000, 4000));
+
+        let gif = *b"GIF89a\x10\x00\x20\x00";
+        let header = parse_image_header(&mut Cursor::new(gif)).unwrap();
+        assert_eq!((header.width, header.height), (16, 32));
+
+        assert_eq!(parse_image_header(&mut Cursor::new(b"not an image")), None);
+    }
+
+    // Little endian tiff laid out like a nef: a thumbnail in IFD0, and a preview and the
+    // sensor data in two SubIFDs
+    fn raw_tiff() -> Vec<u8> {
+        let entry = |tag: u16, field_type: u16, count: u32, value: u32| {
+            let mut entry = vec![];
+            entry.extend(tag.to_le_bytes());
+            entry.extend(field_type.to_le_bytes());
+            entry.extend(count.to_le_bytes());
+            entry.extend(value.to_le_bytes());
+            entry
+        };
+        let ifd = |entries: Vec<Vec<u8>>| {
+            let mut ifd = (entries.len() as u16).to_le_bytes().to_vec();
+            ifd.extend(entries.concat());
+            ifd.extend(0u32.to_le_bytes());
+            ifd
+        };
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend(8u32.to_le_bytes());
+        // IFD0 is 54 bytes, the list of SubIFD offsets follows it
+        tiff.extend(ifd(vec![
+            entry(254, 4, 1, 1),
+            entry(256, 3, 1, 160),
+            entry(257, 3, 1, 120),
+            entry(330, 4, 2, 62),
+        ]));
+        tiff.extend(70u32.to_le_bytes());
+        tiff.extend(112u32.to_le_bytes());
+        tiff.extend(ifd(vec![
+            entry(254, 4, 1, 1),
+            entry(256, 4, 1, 1620),
+            entry(257, 4, 1, 1080),
+        ]));
+        tiff.extend(ifd(vec![
+            entry(254, 4, 1, 0),
+            entry(256, 4, 1, 6048),
+            entry(257, 4, 1, 4024),
+        ]));
+        tiff
+    }
+
+    #[test]
+    fn test_parse_raw_tiff_header() {
+        let header = parse_image_header(&mut Cursor::new(raw_tiff())).unwrap();
+        assert_eq!(header.format, "tiff");
+        assert_eq!((header.width, header.height), (6048, 4024));
+
+        // IFD0 pointing at itself
+        let mut looping = raw_tiff();
+        looping[58..62].copy_from_slice(&8u32.to_le_bytes());
+        let header = parse_image_header(&mut Cursor::new(looping)).unwrap();
+        assert_eq!((header.width, header.height), (6048, 4024));
+    }
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(detect_format(&[0xFF, 0xD8, 0xFF, 0xE1, 0, 0]), Some("jpeg"));
+        assert_eq!(detect_format(b"II*\0\x10\0\0\0CR\x02\0"), Some("cr2"));
+        assert_eq!(detect_format(b"MM\0*\0\0\0\x08"), Some("tiff"));
+        assert_eq!(detect_format(b"FUJIFILMCCD-RAW 0201"), Some("raf"));
+        assert_eq!(
+            detect_format(b"\0\0\0\x18ftypheic\0\0\0\0mif1heic"),
+            Some("heic")
+        );
+        assert_eq!(
+            detect_format(b"\0\0\0\x14ftypqt  \0\0\0\0qt  "),
+            Some("mov")
+        );
+        assert_eq!(
+            detect_format(b"\0\0\0\x1CftypM4A \0\0\0\0M4A mp42isom"),
+            None
+        );
+        assert_eq!(detect_format(b"BMW is not a bitmap"), None);
+        assert_eq!(detect_format(b""), None);
+
+        assert!(extension_matches_format(
+            Path::new("a/IMG_0001.JPG"),
+            "jpeg"
+        ));
+        assert!(extension_matches_format(
+            Path::new("a/DSC_0001.NEF"),
+            "tiff"
+        ));
+        assert!(!extension_matches_format(
+            Path::new("a/image (10).png"),
+            "jpeg"
+        ));
+        assert!(!extension_matches_format(Path::new("a/IMG-WA0001"), "jpeg"));
+    }
+
+    #[test]
+    fn test_exposure_values() {
+        let raw = |column: &str| {
+            match column {
+                "exposure_time" => Some("10/2500"),
+                "f_number" => Some("28/10"),
+                "photographic_sensitivity" => Some("3200 3200"),
+                "focal_length" => Some("85/1"),
+                "focal_length_in_35mm_film" => Some("0"),
+                _ => None,
+            }
+            .map(|value| value.to_string())
+        };
+        let values = ExposureValues::from_exif_columns(raw);
+        assert_eq!(values.exposure_seconds, Some(0.004));
+        assert_eq!(values.f_stop, Some(2.8));
+        assert_eq!(values.iso, Some(3200));
+        assert_eq!(values.focal_length_35mm, None);
+        assert_eq!(values.exposure_display().as_deref(), Some("1/250 s"));
+        assert_eq!(values.f_stop_display().as_deref(), Some("f/2.8"));
+        assert_eq!(values.iso_display().as_deref(), Some("ISO 3200"));
+        assert_eq!(values.focal_length_display().as_deref(), Some("85 mm"));
+
+        // APEX values only
+        let raw = |column: &str| match column {
+            "shutter_speed_value" => Some("-1/1".to_string()),
+            "aperture_value" => Some("6/1".to_string()),
+            _ => None,
+        };
+        let values = ExposureValues::from_exif_columns(raw);
+        assert_eq!(values.exposure_display().as_deref(), Some("2 s"));
+        assert_eq!(values.f_stop_display().as_deref(), Some("f/8"));
+    }
+
+    #[test]
+    fn test_parse_rational() {
+        assert_eq!(parse_rational("28/10"), Some(2.8));
+        assert_eq!(parse_rational("400"), Some(400.0));
+        assert_eq!(parse_rational("1/0"), None);
+        assert_eq!(parse_rational("abc"), None);
+    }
+
+    #[test]
+    fn test_gps_to_decimal() {
+        let latitude = gps_to_decimal("12/1 58/1 1776/100", "N").unwrap();
+        assert!((latitude - 12.971_6).abs() < 0.0001);
+        let longitude = gps_to_decimal("73/1 59/1 0/1", "W").unwrap();
+        assert!((longitude + 73.983_333).abs() < 0.0001);
+        let latitude = gps_to_decimal("33/1 52/1 769/100", "S").unwrap();
+        assert!(latitude < 0.0);
+        assert_eq!(gps_to_decimal("", "N"), None);
+    }
+
+    #[test]
+    fn test_parse_capture_time() {
+        let time = parse_capture_time("2024:03:24 11:51:54", Some("+05:30")).unwrap();
+        assert_eq!(time.to_rfc3339(), "2024-03-24T06:21:54+00:00");
+        let time = parse_capture_time("2024:03:24 11:51:54", None).unwrap();
+        assert_eq!(time.to_rfc3339(), "2024-03-24T11:51:54+00:00");
+        let time = parse_capture_time("2024-03-24T06:21:54.000Z", None).unwrap();
+        assert_eq!(time.to_rfc3339(), "2024-03-24T06:21:54+00:00");
+        assert_eq!(parse_capture_time("12122023 10:00:00", None), None);
+    }
+
+    #[test]
+    fn test_gps_altitude_to_meters() {
+        assert_eq!(gps_altitude_to_meters("920/1", Some("0")), Some(920.0));
+        assert_eq!(gps_altitude_to_meters("15/2", Some("1")), Some(-7.5));
+        assert_eq!(gps_altitude_to_meters("100/1", None), Some(100.0));
+    }
+}
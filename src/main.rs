@@ -1,5 +1,24 @@
+mod batch_rename;
+mod copy_import;
+mod culling;
 mod db;
+mod file_groups;
+mod file_operations;
+mod folders;
+mod geo;
+mod geocode;
+mod gpx;
 mod image_helpers;
+mod media;
+mod metadata_sync;
+mod missing_files;
+mod motion;
+mod presets;
+mod root_folders;
+mod stacks;
+mod watched_folders;
+mod xml;
+mod xmp;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
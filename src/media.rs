@@ -0,0 +1,520 @@
+use crate::image_helpers::{self, GpsLocation, ImageHeader};
+use chrono::{DateTime, TimeZone, Utc};
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+// Seconds between 1904-01-01, where QuickTime and mp4 times start, and 1970-01-01
+const MAC_EPOCH_OFFSET: i64 = 2_082_844_800;
+// The moov box is read into memory. Anything bigger than this is not a sane video.
+const MAX_MOOV_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum MediaType {
+    Image,
+    Video,
+}
+
+impl MediaType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaType::Image => "image",
+            MediaType::Video => "video",
+        }
+    }
+}
+
+pub fn is_video_file(path: &Path) -> bool {
+    let video_extensions = ["mp4", "mov", "m4v", "3gp"];
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .is_some_and(|extension| video_extensions.contains(&extension.as_str()))
+}
+
+pub fn media_type(path: &Path) -> MediaType {
+    // What the file contains wins, the extension only decides for files we can't sniff
+    match image_helpers::sniff_format(path) {
+        Some("mp4" | "mov") => MediaType::Video,
+        Some(_) => MediaType::Image,
+        None if is_video_file(path) => MediaType::Video,
+        None => MediaType::Image,
+    }
+}
+
+/// What we can read from a video without exiv2
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct VideoInfo {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub duration_seconds: Option<f64>,
+    pub creation_time: Option<DateTime<Utc>>,
+    pub location: Option<GpsLocation>,
+}
+
+pub fn read_video_info(path: &Path) -> Option<VideoInfo> {
+    let file = File::open(path).ok()?;
+    parse_video_info(&mut BufReader::new(file))
+}
+
+/// The identifier iPhones write into both parts of a Live Photo
+pub fn read_content_identifier(path: &Path) -> Option<String> {
+    let file = File::open(path).ok()?;
+    let moov = read_top_level_box(&mut BufReader::new(file), b"moov")?;
+    let (_, meta) = boxes(&moov).find(|(box_type, _)| box_type == b"meta")?;
+    quicktime_metadata(meta, "com.apple.quicktime.content.identifier")
+}
+
+pub fn parse_video_info<R: Read + Seek>(reader: &mut R) -> Option<VideoInfo> {
+    let moov = read_top_level_box(reader, b"moov")?;
+    let mut info = VideoInfo::default();
+
+    for (box_type, content) in boxes(&moov) {
+        match &box_type {
+            b"mvhd" => {
+                let (creation, timescale, duration) = match *content.first()? {
+                    1 => (
+                        u64_at(content, 4)? as i64,
+                        u32_at(content, 20)?,
+                        u64_at(content, 24)? as f64,
+                    ),
+                    _ => (
+                        u32_at(content, 4)? as i64,
+                        u32_at(content, 12)?,
+                        u32_at(content, 16)? as f64,
+                    ),
+                };
+                // Cameras without a clock write 0
+                if creation > 0 {
+                    info.creation_time = Utc.timestamp_opt(creation - MAC_EPOCH_OFFSET, 0).single();
+                }
+                if timescale > 0 {
+                    info.duration_seconds = Some(duration / timescale as f64);
+                }
+            }
+            b"trak" if info.width.is_none() => {
+                let Some((_, tkhd)) = boxes(content).find(|(box_type, _)| box_type == b"tkhd")
+                else {
+                    continue;
+                };
+                // Width and height are 16.16 fixed point at the end of the box
+                let offset = if tkhd.first() == Some(&1) { 88 } else { 76 };
+                let width = u32_at(tkhd, offset)? >> 16;
+                let height = u32_at(tkhd, offset + 4)? >> 16;
+                // Audio tracks have no size
+                if width > 0 && height > 0 {
+                    info.width = Some(width);
+                    info.height = Some(height);
+                }
+            }
+            b"udta" => {
+                // ©xyz holds a 16 bit length and language followed by the location
+                if let Some((_, xyz)) = boxes(content).find(|(box_type, _)| box_type == b"\xA9xyz")
+                {
+                    let text = String::from_utf8_lossy(xyz.get(4..)?);
+                    info.location = info.location.or(parse_iso6709(&text));
+                }
+            }
+            b"meta" => {
+                if let Some(text) =
+                    quicktime_metadata(content, "com.apple.quicktime.location.ISO6709")
+                {
+                    info.location = info.location.or(parse_iso6709(&text));
+                }
+                if info.creation_time.is_none() {
+                    info.creation_time =
+                        quicktime_metadata(content, "com.apple.quicktime.creationdate")
+                            .and_then(|date| {
+                                DateTime::parse_from_str(&date, "%Y-%m-%dT%H:%M:%S%z").ok()
+                            })
+                            .map(|date| date.with_timezone(&Utc));
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(info)
+}
+
+/// Format and dimensions of HEIC/HEIF and AVIF images and of mp4/mov videos, which all
+/// use the ISO base media file format
+pub fn parse_bmff_header<R: Read + Seek>(reader: &mut R) -> Option<ImageHeader> {
+    reader.seek(SeekFrom::Start(0)).ok()?;
+    let ftyp = read_top_level_box(reader, b"ftyp")?;
+    let format = bmff_format(&ftyp)?;
+
+    if format == "mp4" || format == "mov" {
+        reader.seek(SeekFrom::Start(0)).ok()?;
+        let info = parse_video_info(reader)?;
+        return Some(ImageHeader {
+            format,
+            width: info.width?,
+            height: info.height?,
+        });
+    }
+
+    // Image sizes are ispe properties in meta/iprp/ipco. There is one for every item
+    // including thumbnails and grid tiles, the biggest one is the image itself.
+    reader.seek(SeekFrom::Start(0)).ok()?;
+    let meta = read_top_level_box(reader, b"meta")?;
+    let (_, iprp) = boxes(meta.get(4..)?).find(|(box_type, _)| box_type == b"iprp")?;
+    let (_, ipco) = boxes(iprp).find(|(box_type, _)| box_type == b"ipco")?;
+    let (width, height) = boxes(ipco)
+        .filter(|(box_type, _)| box_type == b"ispe")
+        .filter_map(|(_, ispe)| Some((u32_at(ispe, 4)?, u32_at(ispe, 8)?)))
+        .max_by_key(|(width, height)| *width as u64 * *height as u64)?;
+    Some(ImageHeader {
+        format,
+        width,
+        height,
+    })
+}
+
+/// Major brands of mp4 videos, from cameras and phones. Audio (M4A) and other ISO BMFF
+/// files list isom as compatible too, so only the major brand tells.
+pub const MP4_BRANDS: [&[u8; 4]; 16] = [
+    b"isom", b"iso2", b"iso4", b"iso5", b"iso6", b"mp41", b"mp42", b"avc1", b"M4V ", b"M4VH",
+    b"3gp4", b"3gp5", b"3gp6", b"3g2a", b"MSNV", b"XAVC",
+];
+
+/// The format of an ISO BMFF file from the contents of its ftyp box, the major brand
+/// followed by the minor version and the compatible brands. None for brands we don't
+/// import.
+pub fn bmff_format(ftyp: &[u8]) -> Option<&'static str> {
+    let major_brand: [u8; 4] = ftyp.get(0..4)?.try_into().ok()?;
+    let compatible_brands: Vec<&[u8]> = ftyp.get(8..).unwrap_or_default().chunks(4).collect();
+    let has_brand = |brand: &[u8]| major_brand == brand || compatible_brands.contains(&brand);
+
+    let format = if has_brand(b"avif") || has_brand(b"avis") {
+        "avif"
+    } else if [
+        "heic", "heix", "heim", "heis", "hevc", "hevx", "mif1", "msf1",
+    ]
+    .iter()
+    .any(|brand| major_brand == brand.as_bytes())
+    {
+        "heic"
+    } else if &major_brand == b"crx " {
+        "cr3"
+    } else if &major_brand == b"qt  " {
+        "mov"
+    } else if MP4_BRANDS.contains(&&major_brand) {
+        "mp4"
+    } else {
+        return None;
+    };
+    Some(format)
+}
+
+/// JPEG XL, either a bare codestream or the ISO BMFF based container
+pub fn parse_jxl_header<R: Read + Seek>(reader: &mut R) -> Option<ImageHeader> {
+    reader.seek(SeekFrom::Start(0)).ok()?;
+    let mut signature = [0u8; 2];
+    reader.read_exact(&mut signature).ok()?;
+    let codestream = if signature == [0xFF, 0x0A] {
+        let mut header = vec![0u8; 16];
+        let read = reader.read(&mut header).ok()?;
+        header.truncate(read);
+        header
+    } else {
+        reader.seek(SeekFrom::Start(0)).ok()?;
+        match read_top_level_box(reader, b"jxlc") {
+            Some(jxlc) => jxlc.get(2..)?.to_vec(),
+            None => {
+                // Split codestreams start with a 4 byte index
+                reader.seek(SeekFrom::Start(0)).ok()?;
+                read_top_level_box(reader, b"jxlp")?.get(6..)?.to_vec()
+            }
+        }
+    };
+
+    let mut bits = BitReader::new(&codestream);
+    let small = bits.read(1)? == 1;
+    let read_size = |bits: &mut BitReader| -> Option<u32> {
+        if small {
+            return Some((bits.read(5)? + 1) * 8);
+        }
+        let size_bits = [9, 13, 18, 30][bits.read(2)? as usize];
+        Some(bits.read(size_bits)? + 1)
+    };
+    let height = read_size(&mut bits)?;
+    let ratio = bits.read(3)?;
+    let width = match ratio {
+        0 => read_size(&mut bits)?,
+        _ => {
+            let (numerator, denominator) =
+                [(1, 1), (12, 10), (4, 3), (3, 2), (16, 9), (5, 4), (2, 1)][ratio as usize - 1];
+            (height as u64 * numerator / denominator) as u32
+        }
+    };
+    Some(ImageHeader {
+        format: "jxl",
+        width,
+        height,
+    })
+}
+
+// JPEG XL packs its header fields least significant bit first
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        BitReader { bytes, position: 0 }
+    }
+
+    fn read(&mut self, count: usize) -> Option<u32> {
+        let mut value = 0u32;
+        for bit in 0..count {
+            let byte = self.bytes.get(self.position / 8)?;
+            value |= (((byte >> (self.position % 8)) & 1) as u32) << bit;
+            self.position += 1;
+        }
+        Some(value)
+    }
+}
+
+// Finds a box at the top level of the file and returns its content. Boxes in between,
+// like the media data of a video, are skipped without reading them.
+fn read_top_level_box<R: Read + Seek>(reader: &mut R, wanted: &[u8; 4]) -> Option<Vec<u8>> {
+    loop {
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header).ok()?;
+        let box_type = &header[4..8];
+        let (size, header_size) = match u32::from_be_bytes(header[0..4].try_into().ok()?) {
+            1 => {
+                let mut large_size = [0u8; 8];
+                reader.read_exact(&mut large_size).ok()?;
+                (u64::from_be_bytes(large_size), 16)
+            }
+            // The box runs to the end of the file
+            0 => {
+                let position = reader.stream_position().ok()?;
+                let end = reader.seek(SeekFrom::End(0)).ok()?;
+                reader.seek(SeekFrom::Start(position)).ok()?;
+                (end - position + 8, 8)
+            }
+            size => (size as u64, 8),
+        };
+        let content_size = size.checked_sub(header_size)?;
+        if box_type == wanted {
+            if content_size > MAX_MOOV_SIZE {
+                return None;
+            }
+            let mut content = vec![0u8; content_size as usize];
+            reader.read_exact(&mut content).ok()?;
+            return Some(content);
+        }
+        // A size past i64::MAX would wrap and seek backwards, over the same boxes again
+        reader
+            .seek(SeekFrom::Current(i64::try_from(content_size).ok()?))
+            .ok()?;
+    }
+}
+
+// Iterates over the boxes inside the content of another box
+fn boxes(mut content: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let size = u32_at(content, 0)? as usize;
+        let box_type: [u8; 4] = content.get(4..8)?.try_into().ok()?;
+        let (header_size, size) = match size {
+            1 => (16, u64_at(content, 8)? as usize),
+            0 => (8, content.len()),
+            size => (8, size),
+        };
+        let inner = content.get(header_size..size)?;
+        content = &content[size..];
+        Some((box_type, inner))
+    })
+}
+
+// Reads a value from the QuickTime metadata of a meta box, which lists the key names
+// in a keys box and the values in an ilst box, indexed by their position in keys
+fn quicktime_metadata(meta: &[u8], key: &str) -> Option<String> {
+    // In mp4 files meta is a full box with four bytes of version and flags first
+    let meta = if u32_at(meta, 0) == Some(0) {
+        meta.get(4..)?
+    } else {
+        meta
+    };
+    let (_, keys) = boxes(meta).find(|(box_type, _)| box_type == b"keys")?;
+    let (_, ilst) = boxes(meta).find(|(box_type, _)| box_type == b"ilst")?;
+
+    let mut offset = 8;
+    let mut index = None;
+    for position in 1..=u32_at(keys, 4)? {
+        let size = u32_at(keys, offset)? as usize;
+        if keys.get(offset + 8..offset + size)? == key.as_bytes() {
+            index = Some(position);
+            break;
+        }
+        offset += size;
+    }
+    let index = index?.to_be_bytes();
+
+    let (_, item) = boxes(ilst).find(|(box_type, _)| *box_type == index)?;
+    let (_, data) = boxes(item).find(|(box_type, _)| box_type == b"data")?;
+    // Four bytes of type and four of locale before the value
+    Some(String::from_utf8_lossy(data.get(8..)?).to_string())
+}
+
+/// Parses ISO 6709 locations like "+12.9716+077.5946+920.000/"
+pub fn parse_iso6709(value: &str) -> Option<GpsLocation> {
+    let value = value.trim().trim_end_matches(['/', '\0']);
+    let starts: Vec<usize> = value
+        .char_indices()
+        .filter(|(_, c)| *c == '+' || *c == '-')
+        .map(|(index, _)| index)
+        .collect();
+    let number = |index: usize| -> Option<f64> {
+        let start = *starts.get(index)?;
+        let end = starts.get(index + 1).copied().unwrap_or(value.len());
+        value[start..end].parse().ok()
+    };
+    let latitude = number(0)?;
+    let longitude = number(1)?;
+    if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+        return None;
+    }
+    Some(GpsLocation {
+        latitude,
+        longitude,
+        altitude: number(2),
+    })
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(
+        bytes.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::image_helpers::parse_image_header;
+    use std::io::Cursor;
+
+    pub(crate) fn make_box(box_type: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let mut bytes = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(box_type);
+        bytes.extend_from_slice(content);
+        bytes
+    }
+
+    /// A small mp4 with a 1920x1080 video track, 12.5 seconds long, recorded on
+    /// 2024-01-11 at 10:00:00 UTC in Bengaluru
+    pub(crate) fn sample_mp4(major_brand: &[u8; 4]) -> Vec<u8> {
+        let mut ftyp = major_brand.to_vec();
+        ftyp.extend_from_slice(&[0, 0, 0, 0]);
+        ftyp.extend_from_slice(b"isommp42");
+
+        let mut mvhd = vec![0u8; 100];
+        let creation = (1_704_967_200 + MAC_EPOCH_OFFSET) as u32;
+        mvhd[4..8].copy_from_slice(&creation.to_be_bytes());
+        mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+        mvhd[16..20].copy_from_slice(&12500u32.to_be_bytes());
+
+        let mut tkhd = vec![0u8; 84];
+        tkhd[76..80].copy_from_slice(&(1920u32 << 16).to_be_bytes());
+        tkhd[80..84].copy_from_slice(&(1080u32 << 16).to_be_bytes());
+        // An audio track first, which has no size
+        let audio = make_box(b"trak", &make_box(b"tkhd", &[0u8; 84]));
+        let video = make_box(b"trak", &make_box(b"tkhd", &tkhd));
+
+        let mut xyz = vec![0, 26, 0x15, 0xC7];
+        xyz.extend_from_slice(b"+12.9716+077.5946+920.000/");
+        let udta = make_box(b"udta", &make_box(b"\xA9xyz", &xyz));
+
+        let moov = [make_box(b"mvhd", &mvhd), audio, video, udta].concat();
+        [
+            make_box(b"ftyp", &ftyp),
+            make_box(b"mdat", &[0u8; 64]),
+            make_box(b"moov", &moov),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_parse_video_info() {
+        let info = parse_video_info(&mut Cursor::new(sample_mp4(b"isom"))).unwrap();
+        assert_eq!((info.width, info.height), (Some(1920), Some(1080)));
+        assert_eq!(info.duration_seconds, Some(12.5));
+        assert_eq!(
+            info.creation_time.unwrap().to_rfc3339(),
+            "2024-01-11T10:00:00+00:00"
+        );
+        let location = info.location.unwrap();
+        assert_eq!((location.latitude, location.longitude), (12.9716, 77.5946));
+        assert_eq!(location.altitude, Some(920.0));
+
+        let header = parse_image_header(&mut Cursor::new(sample_mp4(b"qt  "))).unwrap();
+        assert_eq!(header.format, "mov");
+        assert_eq!((header.width, header.height), (1920, 1080));
+    }
+
+    #[test]
+    fn test_read_top_level_box_large_size() {
+        // A 64 bit size of u64::MAX, which must not seek back to the start
+        let mut bytes = 1u32.to_be_bytes().to_vec();
+        bytes.extend_from_slice(b"free");
+        bytes.extend_from_slice(&u64::MAX.to_be_bytes());
+        bytes.extend(make_box(b"moov", &[0u8; 8]));
+        assert_eq!(read_top_level_box(&mut Cursor::new(bytes), b"moov"), None);
+    }
+
+    #[test]
+    fn test_parse_heic_and_avif_header() {
+        let ispe = |width: u32, height: u32| {
+            let mut content = vec![0u8; 4];
+            content.extend_from_slice(&width.to_be_bytes());
+            content.extend_from_slice(&height.to_be_bytes());
+            make_box(b"ispe", &content)
+        };
+        let ipco = make_box(b"ipco", &[ispe(512, 384), ispe(4032, 3024)].concat());
+        let meta = make_box(b"meta", &[vec![0u8; 4], make_box(b"iprp", &ipco)].concat());
+
+        for (brand, format) in [(b"heic", "heic"), (b"avif", "avif")] {
+            let mut ftyp = brand.to_vec();
+            ftyp.extend_from_slice(&[0, 0, 0, 0]);
+            ftyp.extend_from_slice(b"mif1");
+            let file = [make_box(b"ftyp", &ftyp), meta.clone()].concat();
+            let header = parse_image_header(&mut Cursor::new(file)).unwrap();
+            assert_eq!(header.format, format);
+            assert_eq!((header.width, header.height), (4032, 3024));
+        }
+    }
+
+    #[test]
+    fn test_parse_jxl_header() {
+        // Not small, 9 bit height of 400 (399 stored), 2:1 ratio
+        let mut value: u32 = 0;
+        value |= 399 << 3;
+        value |= 7 << 12;
+        let codestream = [0xFF, 0x0A, value as u8, (value >> 8) as u8, 0];
+        let header = parse_image_header(&mut Cursor::new(codestream)).unwrap();
+        assert_eq!(header.format, "jxl");
+        assert_eq!((header.width, header.height), (800, 400));
+    }
+
+    #[test]
+    fn test_parse_iso6709() {
+        let location = parse_iso6709("-33.8688+151.2093/").unwrap();
+        assert_eq!(
+            (location.latitude, location.longitude),
+            (-33.8688, 151.2093)
+        );
+        assert_eq!(location.altitude, None);
+        assert_eq!(parse_iso6709("nonsense"), None);
+    }
+}
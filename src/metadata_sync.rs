@@ -0,0 +1,307 @@
+use crate::{
+    image_helpers,
+    xmp::{self, XmpData, XmpWriteBack},
+};
+use sqlx::{Row, SqlitePool};
+use std::path::{Path, PathBuf};
+
+/// A field whose value in the catalog differs from the one in the image's xmp
+#[derive(serde::Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldDifference {
+    pub field: &'static str,
+    pub catalog: Option<String>,
+    pub file: Option<String>,
+}
+
+#[derive(serde::Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OutOfSyncImage {
+    pub image_id: i64,
+    pub path: String,
+    // The sidecar, or the image itself for embedded xmp
+    pub metadata_path: PathBuf,
+    pub differences: Vec<FieldDifference>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum SyncDirection {
+    // Take the values from the file into the catalog
+    ReadFromFile,
+    // Overwrite the file with the values from the catalog
+    WriteToFile,
+}
+
+/// Images whose metadata file was changed by another program since we last read or
+/// wrote it, and now has values which differ from the catalog. A file that changed
+/// without touching any field we keep (e.g. pixel edits) isn't reported.
+pub async fn get_out_of_sync_images(pool: &SqlitePool) -> Result<Vec<OutOfSyncImage>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"select i.id, lf.path, s.path as synced_path, s.file_modified_time, s.hash from image i
+        join library_file lf on lf.id = i.library_file_id
+        join xmp_sync s on s.image_id = i.id"#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut out_of_sync = vec![];
+    for row in rows {
+        let path = row.get::<String, _>("path");
+        // Missing files are somebody else's problem
+        if !Path::new(&path).is_file() {
+            continue;
+        }
+        let metadata_path = xmp::metadata_source(Path::new(&path));
+        let changed = metadata_path.to_string_lossy() != row.get::<String, _>("synced_path")
+            || (image_helpers::file_modified_time(&metadata_path)?
+                != row.get::<String, _>("file_modified_time")
+                && image_helpers::file_hash(&metadata_path)? != row.get::<String, _>("hash"));
+        if !changed {
+            continue;
+        }
+
+        let image_id = row.get::<i64, _>("id");
+        let differences = diff_image_metadata(pool, image_id).await?;
+        if !differences.is_empty() {
+            out_of_sync.push(OutOfSyncImage {
+                image_id,
+                path,
+                metadata_path,
+                differences,
+            });
+        }
+    }
+    Ok(out_of_sync)
+}
+
+/// Compares the catalog values of an image with its xmp, field by field. Fields the
+/// file doesn't have at all are not compared, reading from the file leaves those alone.
+pub async fn diff_image_metadata(
+    pool: &SqlitePool,
+    image_id: i64,
+) -> Result<Vec<FieldDifference>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"select lf.path, i.rating, i.flag, i.color_label, iptc.title, iptc.description from image i
+        join library_file lf on lf.id = i.library_file_id
+        left join iptc on iptc.image_id = i.id
+        where i.id=? limit 1"#,
+    )
+    .bind(image_id)
+    .fetch_one(pool)
+    .await?;
+    let file = xmp::read_xmp(Path::new(&row.get::<String, _>("path")));
+
+    let mut differences = vec![];
+    let mut compare = |field: &'static str, catalog: Option<String>, file: Option<String>| {
+        if file.is_some() && catalog != file {
+            differences.push(FieldDifference {
+                field,
+                catalog,
+                file,
+            });
+        }
+    };
+    compare(
+        "rating",
+        row.get::<Option<i32>, _>("rating")
+            .map(|rating| rating.to_string()),
+        file.rating.map(|rating| rating.clamp(0, 5).to_string()),
+    );
+    compare(
+        "flag",
+        row.get::<Option<String>, _>("flag"),
+        file.flag().map(|flag| flag.to_string()),
+    );
+    compare(
+        "colorLabel",
+        row.get::<Option<String>, _>("color_label"),
+        file.color_label(),
+    );
+    compare(
+        "title",
+        row.get::<Option<String>, _>("title"),
+        file.title.clone(),
+    );
+    compare(
+        "description",
+        row.get::<Option<String>, _>("description"),
+        file.description.clone(),
+    );
+
+    let mut file_keywords = file.catalog_keywords();
+    if !file_keywords.is_empty() {
+        let mut catalog_keywords: Vec<String> =
+            sqlx::query("select tag_name from tag where image_id=?")
+                .bind(image_id)
+                .fetch_all(pool)
+                .await?
+                .iter()
+                .map(|row| row.get::<String, _>("tag_name"))
+                .collect();
+        catalog_keywords.sort();
+        file_keywords.sort();
+        compare(
+            "keywords",
+            Some(catalog_keywords.join(", ")),
+            Some(file_keywords.join(", ")),
+        );
+    }
+    Ok(differences)
+}
+
+/// Brings the catalog and the metadata files of the given images back in step, in the
+/// given direction. Returns the number of images which were synced.
+pub async fn resync_images(
+    pool: &SqlitePool,
+    image_ids: &[i64],
+    direction: SyncDirection,
+) -> Result<u64, sqlx::Error> {
+    let mut synced = 0;
+    for image_id in image_ids {
+        match direction {
+            SyncDirection::ReadFromFile => read_from_file(pool, *image_id).await?,
+            SyncDirection::WriteToFile => {
+                // Writing is an explicit request here, so it also works with write back
+                // turned off and overwrites whatever the file has
+                let mode = match xmp::get_xmp_write_back(pool).await? {
+                    XmpWriteBack::Off => XmpWriteBack::Sidecar,
+                    mode => mode,
+                };
+                xmp::write_back_image(pool, *image_id, mode, true).await?;
+            }
+        }
+        synced += 1;
+    }
+    Ok(synced)
+}
+
+async fn read_from_file(pool: &SqlitePool, image_id: i64) -> Result<(), sqlx::Error> {
+    let row = sqlx::query(
+        "select lf.path from image i join library_file lf on lf.id = i.library_file_id where i.id=?",
+    )
+    .bind(image_id)
+    .fetch_one(pool)
+    .await?;
+    let path = PathBuf::from(row.get::<String, _>("path"));
+    let file: XmpData = xmp::read_xmp(&path);
+
+    let mut tx = pool.begin().await?;
+    xmp::write_xmp_to_db(&mut tx, image_id, &file).await?;
+    // Unlike at import the file wins, so values we already had are replaced too
+    let keywords = file.catalog_keywords();
+    if !keywords.is_empty() {
+        let mut query_builder = sqlx::QueryBuilder::new("DELETE from tag where image_id=");
+        query_builder.push_bind(image_id);
+        query_builder.push(" and tag_name not in (");
+        let mut separated = query_builder.separated(", ");
+        for keyword in &keywords {
+            separated.push_bind(keyword);
+        }
+        separated.push_unseparated(")");
+        query_builder.build().execute(&mut *tx).await?;
+    }
+    sqlx::query(
+        "UPDATE iptc set title = coalesce(?, title), description = coalesce(?, description) where image_id=?",
+    )
+    .bind(&file.title)
+    .bind(&file.description)
+    .bind(image_id)
+    .execute(&mut *tx)
+    .await?;
+    xmp::record_sync_state(&mut *tx, image_id, &xmp::metadata_source(&path)).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use std::{
+        fs,
+        time::{Duration, SystemTime},
+    };
+
+    const SIDECAR: &str = r#"<rdf:Description rdf:about="" xmp:Rating="4" xmp:Label="Green">
+ <dc:subject><rdf:Bag><rdf:li>nature</rdf:li></rdf:Bag></dc:subject>
+</rdf:Description>"#;
+
+    // Writes the file and moves its modified time, so the change is seen even when the
+    // test runs within the timestamp resolution
+    fn write_later(path: &Path, content: &str, seconds: u64) -> std::io::Result<()> {
+        fs::write(path, content)?;
+        fs::File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(SystemTime::now() + Duration::from_secs(seconds))
+    }
+
+    #[sqlx::test]
+    async fn test_out_of_sync_images(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let image_path = dir.path().join("DSCF0001.jpg");
+        let sidecar = dir.path().join("DSCF0001.xmp");
+        fs::write(&image_path, b"not a real jpeg")?;
+        fs::write(&sidecar, SIDECAR)?;
+        fs::write(dir.path().join("DSCF0002.jpg"), b"not a real jpeg")?;
+        db::insert_images(&pool, &dir.path().to_string_lossy()).await?;
+        assert!(get_out_of_sync_images(&pool).await?.is_empty());
+
+        // Changed contents without any metadata differences aren't reported
+        write_later(&dir.path().join("DSCF0002.jpg"), "edited pixels", 10)?;
+        assert!(get_out_of_sync_images(&pool).await?.is_empty());
+
+        write_later(
+            &sidecar,
+            &SIDECAR.replace(
+                "<rdf:li>nature</rdf:li>",
+                "<rdf:li>nature</rdf:li><rdf:li>trees</rdf:li>",
+            ),
+            10,
+        )?;
+        let out_of_sync = get_out_of_sync_images(&pool).await?;
+        assert_eq!(out_of_sync.len(), 1);
+        assert_eq!(out_of_sync[0].metadata_path, sidecar);
+        assert_eq!(
+            out_of_sync[0].differences,
+            vec![FieldDifference {
+                field: "keywords",
+                catalog: Some("nature".to_string()),
+                file: Some("nature, trees".to_string()),
+            }]
+        );
+
+        let image_id = out_of_sync[0].image_id;
+        resync_images(&pool, &[image_id], SyncDirection::ReadFromFile).await?;
+        assert!(get_out_of_sync_images(&pool).await?.is_empty());
+        assert!(diff_image_metadata(&pool, image_id).await?.is_empty());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_resync_write_to_file(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let image_path = dir.path().join("DSCF0001.jpg");
+        let sidecar = dir.path().join("DSCF0001.xmp");
+        fs::write(&image_path, b"not a real jpeg")?;
+        fs::write(&sidecar, SIDECAR)?;
+        db::insert_images(&pool, &dir.path().to_string_lossy()).await?;
+
+        // Write back is off, so the sidecar keeps the old rating
+        db::update_image_rating(&pool, &image_path.to_string_lossy(), 2).await?;
+        db::update_color_label(&pool, &image_path.to_string_lossy(), "red").await?;
+        let differences = diff_image_metadata(&pool, 1).await?;
+        let fields: Vec<&str> = differences.iter().map(|diff| diff.field).collect();
+        assert_eq!(fields, vec!["rating", "colorLabel"]);
+        assert_eq!(differences[0].catalog.as_deref(), Some("2"));
+        assert_eq!(differences[0].file.as_deref(), Some("4"));
+
+        resync_images(&pool, &[1], SyncDirection::WriteToFile).await?;
+        assert!(diff_image_metadata(&pool, 1).await?.is_empty());
+        let xmp = XmpData::from_packet(&fs::read_to_string(&sidecar)?);
+        assert_eq!(xmp.rating, Some(2));
+        assert_eq!(xmp.color_label().as_deref(), Some("red"));
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+use crate::{file_operations, image_helpers};
+use sqlx::{Row, SqlitePool};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MissingFile {
+    pub library_file_id: i64,
+    // Files which don't belong to an image, e.g. sidecars of deleted images, have none
+    pub image_id: Option<i64>,
+    pub path: String,
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RelocatedFile {
+    pub library_file_id: i64,
+    pub old_path: String,
+    pub new_path: PathBuf,
+}
+
+#[derive(serde::Serialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RelocationResult {
+    pub relocated: Vec<RelocatedFile>,
+    // Still missing after the search
+    pub not_found: Vec<MissingFile>,
+}
+
+/// Checks every file of the catalog and marks the ones which are gone, e.g. because
+/// their folder was renamed or moved outside the app. Files which are back are
+/// unmarked, and files on offline roots are left as they were. Returns the missing
+/// files.
+pub async fn check_missing_files(pool: &SqlitePool) -> Result<Vec<MissingFile>, sqlx::Error> {
+    // Files of an offline root aren't missing, the whole drive is
+    let rows = sqlx::query(
+        r#"select lf.id, lf.path, lf.missing from library_file lf
+        where not exists (select 1 from root_folder r where r.id = lf.root_folder_id and r.online = 0)"#,
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut tx = pool.begin().await?;
+    for row in rows {
+        let missing = !Path::new(&row.get::<String, _>("path")).is_file();
+        if missing != row.get::<bool, _>("missing") {
+            sqlx::query("UPDATE library_file set missing=? where id=?")
+                .bind(missing)
+                .bind(row.get::<i64, _>("id"))
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+    tx.commit().await?;
+    get_missing_files(pool).await
+}
+
+/// The files marked missing by the last check
+pub async fn get_missing_files(pool: &SqlitePool) -> Result<Vec<MissingFile>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"select lf.id, f.image_id, lf.path from library_file lf
+        left join image_file f on f.library_file_id = lf.id
+        where lf.missing = 1
+        order by lf.path"#,
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .iter()
+        .map(|row| MissingFile {
+            library_file_id: row.get("id"),
+            image_id: row.get("image_id"),
+            path: row.get("path"),
+        })
+        .collect())
+}
+
+/// Searches the folder and everything below it for the missing files and points the
+/// catalog at the copies it finds. A file matches when its name and size are the same
+/// and, if we know the hash of the original, its hash too. Files whose size we don't
+/// know could be any file of that name, they are left in not_found.
+pub async fn relocate_missing_files(
+    pool: &SqlitePool,
+    root: &Path,
+) -> Result<RelocationResult, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"select lf.id, f.image_id, lf.path, lf.original_file_name, lf.file_size, lf.hash
+        from library_file lf left join image_file f on f.library_file_id = lf.id
+        where lf.missing = 1
+        order by lf.path"#,
+    )
+    .fetch_all(pool)
+    .await?;
+    if rows.is_empty() {
+        return Ok(RelocationResult::default());
+    }
+
+    let mut by_name: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    for file in files_under(root) {
+        if let Some(name) = file.file_name() {
+            by_name
+                .entry(name.to_string_lossy().to_string())
+                .or_default()
+                .push(file);
+        }
+    }
+    // Files already in the catalog are other images, never the missing ones
+    let mut taken: HashSet<PathBuf> = sqlx::query("select path from library_file")
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| PathBuf::from(row.get::<String, _>("path")))
+        .collect();
+
+    let mut result = RelocationResult::default();
+    let mut tx = pool.begin().await?;
+    for row in rows {
+        let size = row.get::<Option<i64>, _>("file_size");
+        let hash = row.get::<Option<String>, _>("hash");
+        let candidates = by_name
+            .get(&row.get::<String, _>("original_file_name"))
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let found = candidates.iter().find(|candidate| {
+            !taken.contains(*candidate)
+                && size.is_some_and(|size| {
+                    fs::metadata(candidate).is_ok_and(|metadata| metadata.len() as i64 == size)
+                })
+                && hash.as_ref().is_none_or(|hash| {
+                    image_helpers::file_hash(candidate).is_ok_and(|found| &found == hash)
+                })
+        });
+
+        let missing = MissingFile {
+            library_file_id: row.get("id"),
+            image_id: row.get("image_id"),
+            path: row.get("path"),
+        };
+        let Some(found) = found else {
+            result.not_found.push(missing);
+            continue;
+        };
+        file_operations::move_library_file(
+            &mut tx,
+            missing.library_file_id,
+            Path::new(&missing.path),
+            found,
+        )
+        .await?;
+        sqlx::query("UPDATE library_file set missing=0 where id=?")
+            .bind(missing.library_file_id)
+            .execute(&mut *tx)
+            .await?;
+        taken.insert(found.clone());
+        result.relocated.push(RelocatedFile {
+            library_file_id: missing.library_file_id,
+            old_path: missing.path,
+            new_path: found.clone(),
+        });
+    }
+    tx.commit().await?;
+    Ok(result)
+}
+
+// Every file in the folder and its subfolders. Folders we can't read are skipped.
+pub(crate) fn files_under(root: &Path) -> Vec<PathBuf> {
+    let mut files = vec![];
+    let mut folders = vec![root.to_path_buf()];
+    while let Some(folder) = folders.pop() {
+        let Ok(entries) = fs::read_dir(&folder) else {
+            continue;
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => folders.push(path),
+                Ok(_) => files.push(path),
+                Err(_) => {}
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[sqlx::test]
+    async fn test_relocate_missing_files(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let old_folder = dir.path().join("2024-01 Trip");
+        fs::create_dir(&old_folder)?;
+        fs::write(old_folder.join("DSCF0001.jpg"), b"first image")?;
+        fs::write(old_folder.join("DSCF0002.jpg"), b"second image")?;
+        fs::write(old_folder.join("DSCF0002.xmp"), "<rdf:Description/>")?;
+        db::insert_images(&pool, &old_folder.to_string_lossy()).await?;
+        assert!(check_missing_files(&pool).await?.is_empty());
+
+        // Renamed outside the app, and a different picture with the same name and size
+        // somewhere else
+        let archive = dir.path().join("archive");
+        let new_folder = archive.join("2024").join("Trip");
+        fs::create_dir_all(&new_folder)?;
+        fs::rename(&old_folder, &new_folder)?;
+        fs::create_dir(archive.join("other"))?;
+        fs::write(archive.join("other").join("DSCF0001.jpg"), b"other image")?;
+
+        let missing = check_missing_files(&pool).await?;
+        assert_eq!(missing.len(), 3);
+        assert!(missing.iter().all(|file| file.image_id.is_some()));
+
+        let result = relocate_missing_files(&pool, &archive).await?;
+        assert_eq!(result.relocated.len(), 3);
+        assert!(result.not_found.is_empty());
+        assert!(result
+            .relocated
+            .iter()
+            .all(|file| file.new_path.parent() == Some(new_folder.as_path())));
+        assert!(check_missing_files(&pool).await?.is_empty());
+        assert!(db::has_images_for_path(&pool, &new_folder.to_string_lossy()).await?);
+
+        // Gone for good
+        fs::remove_file(new_folder.join("DSCF0001.jpg"))?;
+        assert_eq!(check_missing_files(&pool).await?.len(), 1);
+        let result = relocate_missing_files(&pool, &archive).await?;
+        assert!(result.relocated.is_empty());
+        assert_eq!(result.not_found.len(), 1);
+
+        // Catalogued before sizes were recorded, the other file with that name isn't
+        // taken for it
+        sqlx::query("UPDATE library_file set file_size=null, hash=null where missing=1")
+            .execute(&pool)
+            .await?;
+        let result = relocate_missing_files(&pool, &archive).await?;
+        assert!(result.relocated.is_empty());
+        assert_eq!(result.not_found.len(), 1);
+        Ok(())
+    }
+}
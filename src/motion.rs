@@ -0,0 +1,335 @@
+use crate::{file_groups::FileGroup, image_helpers, media, xml};
+use sqlx::{Row, Sqlite, SqlitePool, Transaction};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+// The xmp of a jpeg is near its start, no need to search the whole file for it
+const XMP_SEARCH_LIMIT: usize = 256 * 1024;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum MotionKind {
+    // iPhone Live Photos, a still and a mov with the same name
+    LivePhoto,
+    // Google and Samsung motion photos, an mp4 appended to the jpeg
+    MotionPhoto,
+}
+
+impl MotionKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MotionKind::LivePhoto => "livePhoto",
+            MotionKind::MotionPhoto => "motionPhoto",
+        }
+    }
+
+    fn from_db(value: &str) -> MotionKind {
+        match value {
+            "livePhoto" => MotionKind::LivePhoto,
+            _ => MotionKind::MotionPhoto,
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MotionComponent {
+    pub image_id: i64,
+    pub kind: MotionKind,
+    // The mov of a Live Photo, or the image itself for motion photos
+    pub path: String,
+    pub offset: u64,
+    pub length: u64,
+    pub content_identifier: Option<String>,
+}
+
+/// Finds the videos of Live Photos among the imported files and attaches each to its
+/// still. A video pairs with a still of the same name in the same folder, unless both
+/// carry a content identifier and the identifiers differ.
+pub fn pair_live_photos<T>(
+    groups: Vec<FileGroup<T>>,
+    path: impl Fn(&T) -> &Path,
+) -> Vec<(FileGroup<T>, Option<T>)> {
+    let key = |path: &Path| {
+        (
+            path.parent().map(|parent| parent.to_path_buf()),
+            path.file_stem().map(|stem| stem.to_os_string()),
+        )
+    };
+    let mut stills = HashMap::new();
+    for (index, group) in groups.iter().enumerate() {
+        let still = path(&group.primary);
+        if is_live_photo_still(still) {
+            stills.insert(key(still), index);
+        }
+    }
+    // Index of the video group and of the still group it belongs to
+    let pairs: Vec<(usize, usize)> = groups
+        .iter()
+        .enumerate()
+        .filter(|(_, group)| group.secondary.is_empty())
+        .filter_map(|(index, group)| {
+            let video = path(&group.primary);
+            let still = *stills.get(&key(video))?;
+            is_live_photo_video(path(&groups[still].primary), video).then_some((index, still))
+        })
+        .collect();
+
+    let mut groups: Vec<Option<FileGroup<T>>> = groups.into_iter().map(Some).collect();
+    let mut videos: Vec<Option<T>> = groups.iter().map(|_| None).collect();
+    for (video, still) in pairs {
+        videos[still] = groups[video].take().map(|group| group.primary);
+    }
+    groups
+        .into_iter()
+        .zip(videos)
+        .filter_map(|(group, video)| Some((group?, video)))
+        .collect()
+}
+
+fn is_live_photo_still(path: &Path) -> bool {
+    matches!(image_helpers::sniff_format(path), Some("heic" | "jpeg"))
+}
+
+fn is_live_photo_video(still: &Path, video: &Path) -> bool {
+    if image_helpers::sniff_format(video) != Some("mov") {
+        return false;
+    }
+    match (
+        still_content_identifier(still),
+        media::read_content_identifier(video),
+    ) {
+        (Some(still), Some(video)) => still == video,
+        _ => true,
+    }
+}
+
+// The identifier is in the Apple maker note, which older exiv2 versions don't name
+fn still_content_identifier(path: &Path) -> Option<String> {
+    let meta = rexiv2::Metadata::new_from_path(path).ok()?;
+    ["Exif.Apple.ContentIdentifier", "Exif.Apple.0x0011"]
+        .iter()
+        .find_map(|tag| meta.get_tag_string(tag).ok())
+}
+
+/// The hash of the file and the offset and length of the video embedded in it, when it
+/// is a motion photo. Those have to be read in full to find the video, so the hash is
+/// worked out from the same read.
+pub fn hash_and_find_embedded_video(
+    path: &Path,
+    format: Option<&str>,
+) -> (Option<String>, Option<(u64, u64)>) {
+    if !matches!(format, Some("jpeg" | "heic")) {
+        return (image_helpers::file_hash(path).ok(), None);
+    }
+    match fs::read(path) {
+        Ok(data) => (
+            Some(image_helpers::hash_bytes(&data)),
+            parse_embedded_video(&data),
+        ),
+        Err(_) => (None, None),
+    }
+}
+
+pub fn parse_embedded_video(data: &[u8]) -> Option<(u64, u64)> {
+    // Google's xmp says how far from the end of the file the video starts
+    let offset = xmp_video_length(data)
+        .and_then(|length| data.len().checked_sub(length))
+        .filter(|&offset| is_video_at(data, offset))
+        .or_else(|| {
+            // Without it (Samsung, older phones) the video is found by its ftyp box
+            data.windows(4)
+                .enumerate()
+                .skip(4)
+                .filter(|(_, window)| *window == b"ftyp")
+                .map(|(position, _)| position - 4)
+                .find(|&offset| is_video_at(data, offset))
+        })?;
+    Some((offset as u64, (data.len() - offset) as u64))
+}
+
+// The still itself can be a heic starting with an ftyp box, so offset 0 never counts.
+// "ftyp" can turn up in compressed image data by chance, so the box has to look like
+// the start of a video: a plausible size and a video brand.
+fn is_video_at(data: &[u8], offset: usize) -> bool {
+    let Some(size) = data.get(offset..offset + 4) else {
+        return false;
+    };
+    let size = u32::from_be_bytes(size.try_into().unwrap()) as usize;
+    let Some(major_brand) = data.get(offset + 8..offset + 12) else {
+        return false;
+    };
+    offset > 0
+        && (16..=256).contains(&size)
+        && size.is_multiple_of(4)
+        && data.get(offset + 4..offset + 8) == Some(&b"ftyp"[..])
+        && (major_brand == b"qt  "
+            || media::MP4_BRANDS
+                .iter()
+                .any(|brand| &brand[..] == major_brand))
+}
+
+fn xmp_video_length(data: &[u8]) -> Option<usize> {
+    let text = String::from_utf8_lossy(&data[..data.len().min(XMP_SEARCH_LIMIT)]);
+    let length = xml::attribute(&text, "GCamera:MicroVideoOffset")
+        .or_else(|| xml::element_text(&text, "GCamera:MicroVideoOffset"))
+        .or_else(|| {
+            // Newer phones list the parts of the file in a container directory
+            xml::start_tags(&text, "Container:Item")
+                .find(|tag| xml::attribute(tag, "Item:Semantic").as_deref() == Some("MotionPhoto"))
+                .and_then(|tag| xml::attribute(tag, "Item:Length"))
+        })?;
+    length.trim().parse().ok()
+}
+
+pub async fn insert_motion_component<'a>(
+    tx: &mut Transaction<'a, Sqlite>,
+    image_id: i64,
+    kind: MotionKind,
+    library_file_id: i64,
+    (offset, length): (u64, u64),
+    content_identifier: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO motion_component (image_id, kind, library_file_id, video_offset, video_length, content_identifier) values (?, ?, ?, ?, ?, ?)",
+    )
+    .bind(image_id)
+    .bind(kind.as_str())
+    .bind(library_file_id)
+    .bind(offset as i64)
+    .bind(length as i64)
+    .bind(content_identifier)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_motion_component(
+    pool: &SqlitePool,
+    image_id: i64,
+) -> Result<Option<MotionComponent>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"select m.image_id, m.kind, lf.path, m.video_offset, m.video_length, m.content_identifier
+        from motion_component m join library_file lf on lf.id = m.library_file_id
+        where m.image_id=?"#,
+    )
+    .bind(image_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|row| MotionComponent {
+        image_id: row.get("image_id"),
+        kind: MotionKind::from_db(&row.get::<String, _>("kind")),
+        path: row.get("path"),
+        offset: row.get::<i64, _>("video_offset") as u64,
+        length: row.get::<i64, _>("video_length") as u64,
+        content_identifier: row.get("content_identifier"),
+    }))
+}
+
+/// The video of a motion component, ready to be played or saved as an mp4/mov
+pub fn read_motion_video(component: &MotionComponent) -> Result<Vec<u8>, std::io::Error> {
+    let mut file = File::open(&component.path)?;
+    file.seek(SeekFrom::Start(component.offset))?;
+    let mut video = vec![0u8; component.length as usize];
+    file.read_exact(&mut video)?;
+    Ok(video)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db,
+        file_groups::{self, FileRole},
+        media::tests::sample_mp4,
+    };
+
+    const JPEG: [u8; 11] = [0xFF, 0xD8, 0xFF, 0xE0, 0, 16, b'J', b'F', b'I', b'F', 0];
+    const HEIC: &[u8] = b"\0\0\0\x18ftypheic\0\0\0\0mif1heic";
+
+    #[test]
+    fn test_parse_embedded_video() {
+        let video = sample_mp4(b"isom");
+        let xmp = format!(
+            "<x:xmpmeta GCamera:MicroVideo=\"1\" GCamera:MicroVideoOffset=\"{}\"/>",
+            video.len()
+        );
+        let data = [&JPEG[..], xmp.as_bytes(), &[0xFF, 0xD9], &video].concat();
+        let offset = data.len() - video.len();
+        assert_eq!(
+            parse_embedded_video(&data),
+            Some((offset as u64, video.len() as u64))
+        );
+
+        // Without xmp the ftyp box gives it away
+        let data = [&JPEG[..], &[0xFF, 0xD9], &video].concat();
+        assert_eq!(parse_embedded_video(&data), Some((13, video.len() as u64)));
+
+        // Image data which happens to contain "ftyp", or music
+        for ftyp in [
+            &b"\0\0\0\x14ftyp\x93\x1fk\xd2\0\0\0\0\xe7\x04\xaa\x10"[..],
+            b"\0\0\0\x1CftypM4A \0\0\0\0M4A mp42isom",
+        ] {
+            let data = [&JPEG[..], &[0xFF, 0xD9], ftyp].concat();
+            assert_eq!(parse_embedded_video(&data), None);
+        }
+
+        assert_eq!(parse_embedded_video(HEIC), None);
+        assert_eq!(parse_embedded_video(&JPEG), None);
+    }
+
+    #[sqlx::test]
+    async fn test_import_motion_components(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mov = sample_mp4(b"qt  ");
+        fs::write(dir.path().join("IMG_0001.HEIC"), HEIC)?;
+        fs::write(dir.path().join("IMG_0001.MOV"), &mov)?;
+        // A video without a still stays a video
+        fs::write(dir.path().join("IMG_0002.MOV"), &mov)?;
+        let mp4 = sample_mp4(b"isom");
+        fs::write(
+            dir.path().join("PXL_0001.MP.jpg"),
+            [&JPEG[..], &[0xFF, 0xD9], &mp4].concat(),
+        )?;
+        db::insert_images(&pool, &dir.path().to_string_lossy()).await?;
+
+        let rows = sqlx::query(
+            "select i.id, lf.original_file_name, i.media_type from image i join library_file lf on lf.id = i.library_file_id order by lf.original_file_name",
+        )
+        .fetch_all(&pool)
+        .await?;
+        let images: Vec<(String, String)> = rows
+            .iter()
+            .map(|row| (row.get("original_file_name"), row.get("media_type")))
+            .collect();
+        assert_eq!(
+            images,
+            vec![
+                ("IMG_0001.HEIC".to_string(), "image".to_string()),
+                ("IMG_0002.MOV".to_string(), "video".to_string()),
+                ("PXL_0001.MP.jpg".to_string(), "image".to_string()),
+            ]
+        );
+
+        let live_photo_id = rows[0].get::<i64, _>("id");
+        let component = get_motion_component(&pool, live_photo_id).await?.unwrap();
+        assert_eq!(component.kind, MotionKind::LivePhoto);
+        assert!(component.path.ends_with("IMG_0001.MOV"));
+        assert_eq!(read_motion_video(&component)?, mov);
+        let files = file_groups::get_image_files(&pool, live_photo_id).await?;
+        assert_eq!(files[1].role, FileRole::Motion);
+
+        let component = get_motion_component(&pool, rows[2].get("id"))
+            .await?
+            .unwrap();
+        assert_eq!(component.kind, MotionKind::MotionPhoto);
+        assert_eq!(read_motion_video(&component)?, mp4);
+
+        assert_eq!(get_motion_component(&pool, rows[1].get("id")).await?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,263 @@
+use crate::{image_helpers, xmp};
+use sqlx::{Row, Sqlite, SqlitePool, Transaction};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum PresetMode {
+    // Replace whatever the image has
+    Overwrite,
+    // Only set the field when the image doesn't have a value for it yet
+    FillEmpty,
+}
+
+impl PresetMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PresetMode::Overwrite => "overwrite",
+            PresetMode::FillEmpty => "fillEmpty",
+        }
+    }
+
+    fn from_db(value: &str) -> PresetMode {
+        match value {
+            "overwrite" => PresetMode::Overwrite,
+            _ => PresetMode::FillEmpty,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PresetField {
+    // An iptc column, see image_helpers::IPTC_FIELDS
+    pub column: String,
+    pub value: String,
+    pub mode: PresetMode,
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MetadataPreset {
+    pub id: i64,
+    pub name: String,
+    pub fields: Vec<PresetField>,
+}
+
+/// Creates a preset, or replaces the fields of the preset with the same name. Returns
+/// the id of the preset.
+pub async fn save_preset(
+    pool: &SqlitePool,
+    name: &str,
+    fields: &[PresetField],
+) -> Result<i64, sqlx::Error> {
+    if let Some(field) = fields
+        .iter()
+        .find(|field| image_helpers::iptc_field_for_column(&field.column).is_none())
+    {
+        return Err(sqlx::Error::ColumnNotFound(field.column.clone()));
+    }
+
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query(
+        "INSERT INTO metadata_preset (name) values (?) ON CONFLICT(name) DO UPDATE SET name=excluded.name returning id",
+    )
+    .bind(name)
+    .fetch_one(&mut *tx)
+    .await?;
+    let preset_id = row.get::<i64, _>("id");
+
+    sqlx::query("DELETE from metadata_preset_field where preset_id=?")
+        .bind(preset_id)
+        .execute(&mut *tx)
+        .await?;
+    for field in fields {
+        sqlx::query(
+            "INSERT INTO metadata_preset_field (preset_id, column_name, value, mode) values (?, ?, ?, ?)",
+        )
+        .bind(preset_id)
+        .bind(&field.column)
+        .bind(&field.value)
+        .bind(field.mode.as_str())
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(preset_id)
+}
+
+pub async fn get_presets(pool: &SqlitePool) -> Result<Vec<MetadataPreset>, sqlx::Error> {
+    let rows = sqlx::query("SELECT id from metadata_preset order by name")
+        .fetch_all(pool)
+        .await?;
+    let mut presets = vec![];
+    for row in rows {
+        presets.push(get_preset(pool, row.get::<i64, _>("id")).await?);
+    }
+    Ok(presets)
+}
+
+pub async fn get_preset(pool: &SqlitePool, preset_id: i64) -> Result<MetadataPreset, sqlx::Error> {
+    let row = sqlx::query("SELECT id, name from metadata_preset where id=?")
+        .bind(preset_id)
+        .fetch_one(pool)
+        .await?;
+    let fields = sqlx::query(
+        "SELECT column_name, value, mode from metadata_preset_field where preset_id=? order by column_name",
+    )
+    .bind(preset_id)
+    .fetch_all(pool)
+    .await?
+    .iter()
+    .map(|field| PresetField {
+        column: field.get("column_name"),
+        value: field.get("value"),
+        mode: PresetMode::from_db(&field.get::<String, _>("mode")),
+    })
+    .collect();
+
+    Ok(MetadataPreset {
+        id: row.get("id"),
+        name: row.get("name"),
+        fields,
+    })
+}
+
+pub async fn delete_preset(pool: &SqlitePool, preset_id: i64) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE from metadata_preset_field where preset_id=?")
+        .bind(preset_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE from metadata_preset where id=?")
+        .bind(preset_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Applies a preset to a selection of images. Returns the number of images updated.
+pub async fn apply_preset(
+    pool: &SqlitePool,
+    preset_id: i64,
+    image_ids: &[i64],
+) -> Result<u64, sqlx::Error> {
+    let preset = get_preset(pool, preset_id).await?;
+    let mut tx = pool.begin().await?;
+    for image_id in image_ids {
+        apply_preset_to_image(&mut tx, &preset, *image_id).await?;
+    }
+    tx.commit().await?;
+
+    for image_id in image_ids {
+        xmp::write_back_if_enabled(pool, *image_id).await?;
+    }
+    Ok(image_ids.len() as u64)
+}
+
+/// Used by the import, after the metadata of the file has been read, so that fill
+/// empty only fills what the file didn't have
+pub async fn apply_preset_to_image<'a>(
+    tx: &mut Transaction<'a, Sqlite>,
+    preset: &MetadataPreset,
+    image_id: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO iptc (image_id) select ? where not exists (select 1 from iptc where image_id=?)",
+    )
+    .bind(image_id)
+    .bind(image_id)
+    .execute(&mut **tx)
+    .await?;
+
+    for field in &preset.fields {
+        // The column names were checked against the registry when the preset was saved
+        let Some(iptc_field) = image_helpers::iptc_field_for_column(&field.column) else {
+            continue;
+        };
+        sqlx::query(&format!(
+            "UPDATE iptc set {column} = CASE WHEN ? OR coalesce({column}, '') = '' THEN ? ELSE {column} END where image_id=?",
+            column = iptc_field.column
+        ))
+        .bind(field.mode == PresetMode::Overwrite)
+        .bind(&field.value)
+        .bind(image_id)
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    fn preset_fields() -> Vec<PresetField> {
+        vec![
+            PresetField {
+                column: "copyright".to_string(),
+                value: "© 2024 Agency".to_string(),
+                mode: PresetMode::Overwrite,
+            },
+            PresetField {
+                column: "creator".to_string(),
+                value: "Staff".to_string(),
+                mode: PresetMode::FillEmpty,
+            },
+            PresetField {
+                column: "usage_terms".to_string(),
+                value: "Editorial use only".to_string(),
+                mode: PresetMode::FillEmpty,
+            },
+        ]
+    }
+
+    #[sqlx::test(fixtures("library_file", "image", "exif", "iptc"))]
+    async fn test_apply_preset(pool: SqlitePool) -> sqlx::Result<()> {
+        let preset_id = save_preset(&pool, "Agency", &preset_fields()).await?;
+        // Saving again under the same name replaces the fields
+        assert_eq!(
+            save_preset(&pool, "Agency", &preset_fields()).await?,
+            preset_id
+        );
+        let presets = get_presets(&pool).await?;
+        assert_eq!(presets.len(), 1);
+        assert_eq!(presets[0].fields.len(), 3);
+
+        apply_preset(&pool, preset_id, &[1, 2]).await?;
+        let iptc = db::get_iptc(&pool, 1).await?;
+        assert_eq!(iptc.copyright.as_deref(), Some("© 2024 Agency"));
+        assert_eq!(iptc.creator.as_deref(), Some("Mukesh Soni"));
+        assert_eq!(iptc.usage_terms.as_deref(), Some("Editorial use only"));
+        let iptc = db::get_iptc(&pool, 2).await?;
+        assert_eq!(iptc.creator.as_deref(), Some("Staff"));
+
+        let fields = vec![PresetField {
+            column: "not_a_column".to_string(),
+            value: "".to_string(),
+            mode: PresetMode::Overwrite,
+        }];
+        assert!(save_preset(&pool, "Broken", &fields).await.is_err());
+
+        delete_preset(&pool, preset_id).await?;
+        assert!(get_presets(&pool).await?.is_empty());
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_apply_preset_at_import(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("DSCF0001.jpg"), b"not a real jpeg")?;
+        let preset_id = save_preset(&pool, "Agency", &preset_fields()).await?;
+
+        let options = db::ImportOptions {
+            preset_id: Some(preset_id),
+        };
+        db::insert_images_with_options(&pool, &dir.path().to_string_lossy(), &options).await?;
+        let iptc = db::get_iptc(&pool, 1).await?;
+        assert_eq!(iptc.copyright.as_deref(), Some("© 2024 Agency"));
+        assert_eq!(iptc.creator.as_deref(), Some("Staff"));
+        Ok(())
+    }
+}
@@ -0,0 +1,467 @@
+use crate::xmp::{self, FlushedWriteBacks};
+use sqlx::{Row, SqliteConnection, SqlitePool};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RootFolder {
+    pub id: i64,
+    pub name: String,
+    pub path: String,
+    pub file_count: i64,
+    // As of the last update_online_state
+    pub online: bool,
+}
+
+// A trailing separator is taken off, except for a drive or the file system root, which
+// keep theirs (E:\, /)
+fn root_path(path: &Path) -> String {
+    path.components()
+        .collect::<PathBuf>()
+        .to_string_lossy()
+        .to_string()
+}
+
+// What every path inside the root starts with
+fn root_prefix(root_path: &str) -> String {
+    if root_path.ends_with(MAIN_SEPARATOR) {
+        root_path.to_string()
+    } else {
+        format!("{root_path}{MAIN_SEPARATOR}")
+    }
+}
+
+// The same in SQL, for root_folder r
+fn root_prefix_sql() -> String {
+    format!("(case when substr(r.path, -1) = '{MAIN_SEPARATOR}' then r.path else r.path || '{MAIN_SEPARATOR}' end)")
+}
+
+// The deepest root the file is in, when roots are nested, and the path of the file
+// inside it. Relative paths are kept with '/' whatever the platform, so that a catalog
+// still finds its files after it was moved between Windows and macOS or Linux.
+fn set_root_folder_sql() -> String {
+    let prefix = root_prefix_sql();
+    format!(
+        r#"UPDATE library_file set root_folder_id = (
+    select r.id from root_folder r
+    where substr(library_file.path, 1, length({prefix})) = {prefix}
+    order by length(r.path) desc limit 1
+)"#
+    )
+}
+
+fn set_relative_path_sql() -> String {
+    let prefix = root_prefix_sql();
+    format!(
+        r#"UPDATE library_file set relative_path = (
+    select replace(substr(library_file.path, length({prefix}) + 1), '{MAIN_SEPARATOR}', '/') from root_folder r
+    where r.id = library_file.root_folder_id
+)"#
+    )
+}
+
+/// Works out the root and relative path of a file which was added, copied or moved
+pub(crate) async fn assign_root_folder(
+    conn: &mut SqliteConnection,
+    library_file_id: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(&format!("{} where id = ?", set_root_folder_sql()))
+        .bind(library_file_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(&format!("{} where id = ?", set_relative_path_sql()))
+        .bind(library_file_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// The same for every file, after roots were added or removed or a whole folder moved
+pub(crate) async fn assign_root_folders(conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+    sqlx::query(&set_root_folder_sql())
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(&set_relative_path_sql())
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Registers a folder, usually a drive or the top folder of a photo collection, as a root.
+/// Files of the catalog in it, already there or imported later, are kept relative to it.
+pub async fn add_root_folder(pool: &SqlitePool, path: &Path) -> Result<i64, sqlx::Error> {
+    let path = root_path(path);
+    let name = Path::new(&path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.clone());
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query("INSERT INTO root_folder (name, path) values (?, ?) returning id")
+        .bind(name)
+        .bind(&path)
+        .fetch_one(&mut *tx)
+        .await?;
+    assign_root_folders(&mut tx).await?;
+    tx.commit().await?;
+    Ok(row.get("id"))
+}
+
+/// Forgets the root. Its files stay in the catalog with their absolute paths.
+pub async fn remove_root_folder(pool: &SqlitePool, id: i64) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("UPDATE library_file set root_folder_id = null where root_folder_id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE from root_folder where id = ?")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    assign_root_folders(&mut tx).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn get_root_folders(pool: &SqlitePool) -> Result<Vec<RootFolder>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"select r.id, r.name, r.path, r.online, count(lf.id) as file_count from root_folder r
+        left join library_file lf on lf.root_folder_id = r.id
+        group by r.id order by r.path"#,
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .iter()
+        .map(|row| RootFolder {
+            id: row.get("id"),
+            name: row.get("name"),
+            path: row.get("path"),
+            file_count: row.get("file_count"),
+            online: row.get("online"),
+        })
+        .collect())
+}
+
+// Looks for every root on disk and records whether it's there
+async fn refresh_online_state(conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+    let rows = sqlx::query("select id, path from root_folder")
+        .fetch_all(&mut *conn)
+        .await?;
+    for row in rows {
+        let online = Path::new(&row.get::<String, _>("path")).is_dir();
+        sqlx::query("UPDATE root_folder set online = ? where id = ?")
+            .bind(online)
+            .bind(row.get::<i64, _>("id"))
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Looks for every root on disk and records whether it's there. Meant for startup and
+/// whenever a drive is mounted or unmounted. Write-backs queued for roots which are back
+/// are done and returned.
+pub async fn update_online_state(pool: &SqlitePool) -> Result<FlushedWriteBacks, sqlx::Error> {
+    refresh_online_state(&mut *pool.acquire().await?).await?;
+    xmp::flush_pending_write_backs(pool).await
+}
+
+/// Points the root at where its files are now, e.g. after the drive was mounted under
+/// another name or the collection was copied to another machine. Roots inside it move
+/// along. Nothing on disk is touched. Write-backs which were waiting for the root are
+/// done and returned, the ones which fail stay queued.
+pub async fn set_root_folder_path(
+    pool: &SqlitePool,
+    id: i64,
+    path: &Path,
+) -> Result<FlushedWriteBacks, sqlx::Error> {
+    let new_path = root_path(path);
+    let new_prefix = root_prefix(&new_path);
+    let mut tx = pool.begin().await?;
+    let old_path: String = sqlx::query("select path from root_folder where id = ?")
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?
+        .get("path");
+    let old_prefix = root_prefix(&old_path);
+    // The root itself, and other places which remember paths inside it
+    for table in ["root_folder", "xmp_sync", "watched_folder"] {
+        sqlx::query(&format!(
+            r#"UPDATE {table} set path = case when path = ? then ? else ? || substr(path, length(?) + 1) end
+            where path = ? or substr(path, 1, length(?)) = ?"#
+        ))
+        .bind(&old_path)
+        .bind(&new_path)
+        .bind(&new_prefix)
+        .bind(&old_prefix)
+        .bind(&old_path)
+        .bind(&old_prefix)
+        .bind(&old_prefix)
+        .execute(&mut *tx)
+        .await?;
+    }
+    // The relative paths are what the files are known by, the absolute ones follow from
+    // where their roots are now
+    let prefix = root_prefix_sql();
+    sqlx::query(&format!(
+        r#"UPDATE library_file set
+            path = (
+                select {prefix} from root_folder r where r.id = library_file.root_folder_id
+            ) || replace(relative_path, '/', '{MAIN_SEPARATOR}'),
+            parent_path = (
+                select case when instr(relative_path, '/') = 0 then r.path
+                else {prefix} || replace(substr(relative_path, 1, length(relative_path) - length(original_file_name) - 1), '/', '{MAIN_SEPARATOR}') end
+                from root_folder r where r.id = library_file.root_folder_id
+            )
+        where root_folder_id in (
+            select r.id from root_folder r where r.path = ? or substr(r.path, 1, length(?)) = ?
+        )"#
+    ))
+    .bind(&new_path)
+    .bind(&new_prefix)
+    .bind(&new_prefix)
+    .execute(&mut *tx)
+    .await?;
+    refresh_online_state(&mut tx).await?;
+    tx.commit().await?;
+    xmp::flush_pending_write_backs(pool).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db, xmp::WriteBackOutcome};
+    use std::fs;
+
+    async fn file_paths(pool: &SqlitePool) -> sqlx::Result<Vec<(String, String, Option<String>)>> {
+        Ok(sqlx::query(
+            "select path, parent_path, relative_path from library_file order by original_file_name, path",
+        )
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| {
+            (
+                row.get("path"),
+                row.get("parent_path"),
+                row.get("relative_path"),
+            )
+        })
+        .collect())
+    }
+
+    #[sqlx::test]
+    async fn test_root_folders(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let drive = dir.path().join("drive");
+        let day = drive.join("2024").join("03-24");
+        fs::create_dir_all(&day)?;
+        fs::write(day.join("a.jpg"), b"a")?;
+        fs::write(day.join("b.jpg"), b"b")?;
+        db::insert_images(&pool, &day.to_string_lossy()).await?;
+
+        let root_id = add_root_folder(&pool, &drive.join("")).await?;
+        let roots = get_root_folders(&pool).await?;
+        assert_eq!(roots[0].name, "drive");
+        assert_eq!(roots[0].path, drive.to_string_lossy());
+        assert_eq!(roots[0].file_count, 2);
+        assert_eq!(
+            file_paths(&pool).await?[0].2.as_deref(),
+            Some("2024/03-24/a.jpg")
+        );
+
+        // Files imported later are placed in the root too
+        let other_day = drive.join("2024").join("03-25");
+        fs::create_dir_all(&other_day)?;
+        fs::write(other_day.join("c.jpg"), b"c")?;
+        db::insert_images(&pool, &other_day.to_string_lossy()).await?;
+        assert_eq!(
+            file_paths(&pool).await?[2].2.as_deref(),
+            Some("2024/03-25/c.jpg")
+        );
+
+        // A root inside it gets its files
+        let nested_id = add_root_folder(&pool, &other_day).await?;
+        assert_eq!(file_paths(&pool).await?[2].2.as_deref(), Some("c.jpg"));
+        assert_eq!(get_root_folders(&pool).await?[0].file_count, 2);
+
+        // The drive shows up under another name, and the root inside it moves along
+        let moved = dir.path().join("drive 1");
+        fs::rename(&drive, &moved)?;
+        set_root_folder_path(&pool, root_id, &moved).await?;
+        let moved_day = moved.join("2024").join("03-24");
+        assert_eq!(
+            file_paths(&pool).await?[0],
+            (
+                moved_day.join("a.jpg").to_string_lossy().to_string(),
+                moved_day.to_string_lossy().to_string(),
+                Some("2024/03-24/a.jpg".to_string())
+            )
+        );
+        let moved_other_day = moved.join("2024").join("03-25");
+        let roots = get_root_folders(&pool).await?;
+        assert_eq!(
+            (roots[1].id, roots[1].path.as_str()),
+            (nested_id, moved_other_day.to_string_lossy().as_ref())
+        );
+        assert_eq!(
+            file_paths(&pool).await?[2],
+            (
+                moved_other_day.join("c.jpg").to_string_lossy().to_string(),
+                moved_other_day.to_string_lossy().to_string(),
+                Some("c.jpg".to_string())
+            )
+        );
+        assert_eq!(
+            crate::missing_files::check_missing_files(&pool).await?,
+            vec![]
+        );
+
+        remove_root_folder(&pool, root_id).await?;
+        assert_eq!(get_root_folders(&pool).await?.len(), 1);
+        assert_eq!(file_paths(&pool).await?[0].2, None);
+        assert_eq!(file_paths(&pool).await?[2].2.as_deref(), Some("c.jpg"));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_copy_and_volume_root(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let drive = dir.path().join("drive");
+        fs::create_dir(&drive)?;
+        fs::write(drive.join("a.jpg"), b"a")?;
+        db::insert_images(&pool, &drive.to_string_lossy()).await?;
+        let root_id = add_root_folder(&pool, &drive).await?;
+
+        // A copy gets its own relative path, so re-pointing doesn't give it the path of
+        // the original
+        let copies = drive.join("copies");
+        fs::create_dir(&copies)?;
+        crate::file_operations::copy_images(&pool, &[1], &copies).await?;
+        assert_eq!(
+            file_paths(&pool).await?[1].2.as_deref(),
+            Some("copies/a.jpg")
+        );
+        let moved = dir.path().join("drive 1");
+        fs::rename(&drive, &moved)?;
+        set_root_folder_path(&pool, root_id, &moved).await?;
+        assert_eq!(
+            file_paths(&pool).await?[1].0,
+            moved.join("copies").join("a.jpg").to_string_lossy()
+        );
+        assert_eq!(
+            file_paths(&pool).await?[0].1,
+            moved.to_string_lossy().to_string()
+        );
+
+        // A drive keeps its separator and still gets its files
+        remove_root_folder(&pool, root_id).await?;
+        let volume = moved.ancestors().last().unwrap();
+        let volume_id = add_root_folder(&pool, volume).await?;
+        let roots = get_root_folders(&pool).await?;
+        assert_eq!(roots[0].path, volume.to_string_lossy());
+        assert_eq!(roots[0].file_count, 2);
+        let relative = moved
+            .join("a.jpg")
+            .strip_prefix(volume)
+            .unwrap()
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().to_string())
+            .collect::<Vec<_>>()
+            .join("/");
+        assert_eq!(file_paths(&pool).await?[0].2, Some(relative));
+        set_root_folder_path(&pool, volume_id, volume).await?;
+        assert_eq!(
+            file_paths(&pool).await?[0].0,
+            moved.join("a.jpg").to_string_lossy()
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_offline_root(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let drive = dir.path().join("drive");
+        fs::create_dir(&drive)?;
+        let first_path = drive.join("DSCF0001.jpg");
+        let second_path = drive.join("DSCF0002.jpg");
+        fs::write(&first_path, b"not a real jpeg")?;
+        fs::write(&second_path, b"not one either")?;
+        db::insert_images(&pool, &drive.to_string_lossy()).await?;
+        let root_id = add_root_folder(&pool, &drive).await?;
+        xmp::set_xmp_write_back(&pool, xmp::XmpWriteBack::Sidecar).await?;
+        let image_id = async |name: &str| -> sqlx::Result<i64> {
+            Ok(sqlx::query("select i.id from image i join library_file lf on lf.id = i.library_file_id where lf.original_file_name=?")
+                .bind(name)
+                .fetch_one(&pool)
+                .await?
+                .get("id"))
+        };
+        let (first, second) = (
+            image_id("DSCF0001.jpg").await?,
+            image_id("DSCF0002.jpg").await?,
+        );
+
+        // Unplugged
+        let unplugged = dir.path().join("elsewhere");
+        fs::rename(&drive, &unplugged)?;
+        assert_eq!(
+            update_online_state(&pool).await?,
+            FlushedWriteBacks::default()
+        );
+        assert!(!get_root_folders(&pool).await?[0].online);
+        assert!(crate::missing_files::check_missing_files(&pool)
+            .await?
+            .is_empty());
+        db::update_image_rating(&pool, &first_path.to_string_lossy(), 3).await?;
+        assert_eq!(
+            db::update_image_rating(&pool, &second_path.to_string_lossy(), 4).await?,
+            WriteBackOutcome::Queued
+        );
+
+        // Plugged in again. The first write-back fails, which doesn't hold up the second.
+        fs::rename(&unplugged, &drive)?;
+        fs::create_dir(drive.join("DSCF0001.xmp"))?;
+        sqlx::query("UPDATE pending_write_back set force=1 where image_id=?")
+            .bind(first)
+            .execute(&pool)
+            .await?;
+        let flushed = update_online_state(&pool).await?;
+        let sidecar = drive.join("DSCF0002.xmp");
+        assert_eq!(
+            flushed.outcomes,
+            vec![(second, WriteBackOutcome::Written(sidecar.clone()))]
+        );
+        assert_eq!(flushed.failed.len(), 1);
+        assert_eq!(flushed.failed[0].0, first);
+        assert_eq!(
+            xmp::XmpData::from_packet(&fs::read_to_string(&sidecar)?).rating,
+            Some(4)
+        );
+
+        // Mounted somewhere else. The failing write-back doesn't fail the re-point.
+        fs::rename(&drive, &unplugged)?;
+        let flushed = set_root_folder_path(&pool, root_id, &unplugged).await?;
+        assert!(flushed.outcomes.is_empty());
+        assert_eq!(flushed.failed.len(), 1);
+        let root = &get_root_folders(&pool).await?[0];
+        assert_eq!(root.path, unplugged.to_string_lossy());
+        assert!(root.online);
+
+        fs::remove_dir(unplugged.join("DSCF0001.xmp"))?;
+        let sidecar = unplugged.join("DSCF0001.xmp");
+        assert_eq!(
+            update_online_state(&pool).await?.outcomes,
+            vec![(first, WriteBackOutcome::Written(sidecar.clone()))]
+        );
+        assert_eq!(
+            xmp::XmpData::from_packet(&fs::read_to_string(&sidecar)?).rating,
+            Some(3)
+        );
+        assert_eq!(
+            update_online_state(&pool).await?,
+            FlushedWriteBacks::default()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,399 @@
+use crate::image_helpers;
+use chrono::{DateTime, Utc};
+use sqlx::{Row, Sqlite, SqlitePool, Transaction};
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Stack {
+    pub id: i64,
+    pub top_image_id: i64,
+    pub collapsed: bool,
+    // Ordered by capture time
+    pub image_ids: Vec<i64>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum AutoStackMode {
+    // Bursts, i.e. images taken right after each other
+    CaptureTime,
+    // Exposure brackets, images taken right after each other with different exposure
+    // bias (e.g. -1, 0, +1)
+    Brackets,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AutoStackOptions {
+    pub mode: AutoStackMode,
+    // Images further apart than this are never stacked together
+    pub max_gap_seconds: f64,
+}
+
+impl Default for AutoStackOptions {
+    fn default() -> Self {
+        AutoStackOptions {
+            mode: AutoStackMode::CaptureTime,
+            max_gap_seconds: 1.0,
+        }
+    }
+}
+
+/// Stacks the given images. Images already in another stack are taken out of it first.
+/// The top image defaults to the first of the given ones.
+pub async fn create_stack(
+    pool: &SqlitePool,
+    image_ids: &[i64],
+    top_image_id: Option<i64>,
+) -> Result<i64, sqlx::Error> {
+    let top_image_id = top_image_id.or(image_ids.first().copied());
+    let Some(top_image_id) = top_image_id.filter(|top| image_ids.contains(top)) else {
+        return Err(sqlx::Error::RowNotFound);
+    };
+
+    let mut tx = pool.begin().await?;
+    let stack_id = insert_stack(&mut tx, image_ids, top_image_id).await?;
+    remove_empty_stacks(&mut tx).await?;
+    tx.commit().await?;
+    Ok(stack_id)
+}
+
+async fn insert_stack<'a>(
+    tx: &mut Transaction<'a, Sqlite>,
+    image_ids: &[i64],
+    top_image_id: i64,
+) -> Result<i64, sqlx::Error> {
+    let query_result = sqlx::query("INSERT INTO stack (top_image_id) values (?)")
+        .bind(top_image_id)
+        .execute(&mut **tx)
+        .await?;
+    let stack_id = query_result.last_insert_rowid();
+    for image_id in image_ids {
+        sqlx::query("UPDATE image set stack_id=? where id=?")
+            .bind(stack_id)
+            .bind(image_id)
+            .execute(&mut **tx)
+            .await?;
+    }
+    Ok(stack_id)
+}
+
+// Stacks left with fewer than two images are dissolved, and stacks which lost their
+// top image get a new one
+pub(crate) async fn remove_empty_stacks<'a>(
+    tx: &mut Transaction<'a, Sqlite>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"UPDATE image set stack_id = null where stack_id in
+        (select stack_id from image where stack_id is not null group by stack_id having count(*) < 2)"#,
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query(
+        "DELETE from stack where id not in (select stack_id from image where stack_id is not null)",
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query(
+        r#"UPDATE stack set top_image_id = (select min(id) from image where stack_id = stack.id)
+        where top_image_id not in (select id from image where stack_id = stack.id)"#,
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+pub async fn unstack(pool: &SqlitePool, stack_id: i64) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("UPDATE image set stack_id = null where stack_id=?")
+        .bind(stack_id)
+        .execute(&mut *tx)
+        .await?;
+    remove_empty_stacks(&mut tx).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn remove_from_stack(pool: &SqlitePool, image_ids: &[i64]) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    for image_id in image_ids {
+        sqlx::query("UPDATE image set stack_id = null where id=?")
+            .bind(image_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    remove_empty_stacks(&mut tx).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn set_stack_top(
+    pool: &SqlitePool,
+    stack_id: i64,
+    image_id: i64,
+) -> Result<(), sqlx::Error> {
+    let query_result = sqlx::query(
+        "UPDATE stack set top_image_id=? where id=? and exists (select 1 from image where id=? and stack_id=?)",
+    )
+    .bind(image_id)
+    .bind(stack_id)
+    .bind(image_id)
+    .bind(stack_id)
+    .execute(pool)
+    .await?;
+    if query_result.rows_affected() == 0 {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    Ok(())
+}
+
+/// Expanded stacks show all their images in image queries, collapsed ones only the top
+pub async fn set_stack_collapsed(
+    pool: &SqlitePool,
+    stack_id: i64,
+    collapsed: bool,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE stack set collapsed=? where id=?")
+        .bind(collapsed)
+        .bind(stack_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_stack(pool: &SqlitePool, stack_id: i64) -> Result<Stack, sqlx::Error> {
+    let row = sqlx::query("SELECT id, top_image_id, collapsed from stack where id=?")
+        .bind(stack_id)
+        .fetch_one(pool)
+        .await?;
+    let image_ids = sqlx::query("SELECT id from image where stack_id=? order by capture_time, id")
+        .bind(stack_id)
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| row.get::<i64, _>("id"))
+        .collect();
+    Ok(Stack {
+        id: row.get("id"),
+        top_image_id: row.get("top_image_id"),
+        collapsed: row.get("collapsed"),
+        image_ids,
+    })
+}
+
+struct StackCandidate {
+    image_id: i64,
+    capture_time: DateTime<Utc>,
+    exposure_bias: Option<f64>,
+}
+
+/// Stacks the images of a folder which aren't in a stack yet, either bursts by capture
+/// time or exposure brackets. Returns the ids of the new stacks.
+pub async fn auto_stack(
+    pool: &SqlitePool,
+    folder_path: &str,
+    options: &AutoStackOptions,
+) -> Result<Vec<i64>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"select i.id, i.capture_time, e.datetime_original, e.offset_time_original, e.exposure_bias from image i
+        join library_file lf on lf.id = i.library_file_id
+        left join exif e on e.image_id = i.id
+        where lf.parent_path=? and i.stack_id is null
+        group by i.id"#,
+    )
+    .bind(folder_path)
+    .fetch_all(pool)
+    .await?;
+
+    // Images without a capture time we understand can't be placed in a sequence
+    let mut candidates: Vec<StackCandidate> = rows
+        .iter()
+        .filter_map(|row| {
+            let offset = row.get::<Option<String>, _>("offset_time_original");
+            let capture_time = row
+                .get::<Option<String>, _>("datetime_original")
+                .and_then(|datetime| {
+                    image_helpers::parse_capture_time(&datetime, offset.as_deref())
+                })
+                .or_else(|| {
+                    row.get::<Option<String>, _>("capture_time")
+                        .and_then(|datetime| image_helpers::parse_capture_time(&datetime, None))
+                })?;
+            Some(StackCandidate {
+                image_id: row.get("id"),
+                capture_time,
+                exposure_bias: row
+                    .get::<Option<String>, _>("exposure_bias")
+                    .and_then(|bias| image_helpers::parse_rational(&bias)),
+            })
+        })
+        .collect();
+    candidates.sort_by_key(|candidate| (candidate.capture_time, candidate.image_id));
+
+    let mut groups = vec![];
+    for run in split_by_gap(&candidates, options.max_gap_seconds) {
+        match options.mode {
+            AutoStackMode::CaptureTime => {
+                if run.len() > 1 {
+                    // The first image of a burst is the top
+                    groups.push((run.iter().collect(), 0));
+                }
+            }
+            AutoStackMode::Brackets => groups.extend(split_brackets(run)),
+        }
+    }
+
+    let mut tx = pool.begin().await?;
+    let mut stack_ids = vec![];
+    for (members, top) in groups {
+        let image_ids: Vec<i64> = members.iter().map(|member| member.image_id).collect();
+        stack_ids.push(insert_stack(&mut tx, &image_ids, image_ids[top]).await?);
+    }
+    tx.commit().await?;
+    Ok(stack_ids)
+}
+
+// Splits images sorted by capture time wherever two of them are further apart than
+// max_gap_seconds
+fn split_by_gap(candidates: &[StackCandidate], max_gap_seconds: f64) -> Vec<&[StackCandidate]> {
+    let mut runs = vec![];
+    let mut start = 0;
+    for index in 1..=candidates.len() {
+        let gap_too_big = index == candidates.len() || {
+            let gap = candidates[index].capture_time - candidates[index - 1].capture_time;
+            gap.num_milliseconds() as f64 / 1000.0 > max_gap_seconds
+        };
+        if gap_too_big {
+            runs.push(&candidates[start..index]);
+            start = index;
+        }
+    }
+    runs
+}
+
+// Within a run of images taken right after each other, a bracket lasts until an
+// exposure bias repeats. Runs where every image has the same bias are bursts, not
+// brackets. Returns the members and the index of the top image, the one closest to 0 EV.
+fn split_brackets(run: &[StackCandidate]) -> Vec<(Vec<&StackCandidate>, usize)> {
+    let mut brackets = vec![];
+    let mut current: Vec<&StackCandidate> = vec![];
+    // Compared in hundredths of a stop, since biases are rationals like -1/3
+    let step = |bias: f64| (bias * 100.0).round() as i64;
+    for candidate in run {
+        let Some(bias) = candidate.exposure_bias else {
+            finish_bracket(&mut current, &mut brackets);
+            continue;
+        };
+        if current
+            .iter()
+            .any(|other| other.exposure_bias.map(step) == Some(step(bias)))
+        {
+            finish_bracket(&mut current, &mut brackets);
+        }
+        current.push(candidate);
+    }
+    finish_bracket(&mut current, &mut brackets);
+    brackets
+}
+
+fn finish_bracket<'a>(
+    current: &mut Vec<&'a StackCandidate>,
+    brackets: &mut Vec<(Vec<&'a StackCandidate>, usize)>,
+) {
+    let has_bias = current
+        .iter()
+        .any(|candidate| candidate.exposure_bias.is_some_and(|bias| bias != 0.0));
+    if current.len() > 1 && has_bias {
+        let top = current
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| {
+                let a = a.exposure_bias.unwrap_or_default().abs();
+                let b = b.exposure_bias.unwrap_or_default().abs();
+                a.total_cmp(&b)
+            })
+            .map_or(0, |(index, _)| index);
+        brackets.push((std::mem::take(current), top));
+    }
+    current.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FOLDER: &str = "/Users/fancy-name/Desktop";
+
+    async fn set_capture(
+        pool: &SqlitePool,
+        image_id: i64,
+        datetime: &str,
+        bias: &str,
+    ) -> sqlx::Result<()> {
+        sqlx::query("UPDATE exif set datetime_original=?, exposure_bias=? where image_id=?")
+            .bind(datetime)
+            .bind(bias)
+            .bind(image_id)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn setup(pool: &SqlitePool) -> sqlx::Result<()> {
+        // A bracket of three, and two more shots of the same exposure much later
+        set_capture(pool, 1, "2024:01:11 10:00:00", "-1/1").await?;
+        set_capture(pool, 2, "2024:01:11 10:00:00", "0/1").await?;
+        set_capture(pool, 3, "2024:01:11 10:00:01", "1/1").await?;
+        set_capture(pool, 4, "2024:01:11 12:00:00", "0/1").await?;
+        set_capture(pool, 5, "2024:01:11 12:00:00", "0/1").await?;
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("library_file", "image", "exif", "iptc"))]
+    async fn test_auto_stack(pool: SqlitePool) -> sqlx::Result<()> {
+        setup(&pool).await?;
+
+        let options = AutoStackOptions {
+            mode: AutoStackMode::Brackets,
+            ..Default::default()
+        };
+        let stack_ids = auto_stack(&pool, FOLDER, &options).await?;
+        assert_eq!(stack_ids.len(), 1);
+        let stack = get_stack(&pool, stack_ids[0]).await?;
+        assert_eq!(stack.image_ids, vec![1, 2, 3]);
+        assert_eq!(stack.top_image_id, 2);
+        unstack(&pool, stack.id).await?;
+
+        let stack_ids = auto_stack(&pool, FOLDER, &AutoStackOptions::default()).await?;
+        assert_eq!(stack_ids.len(), 2);
+        assert_eq!(get_stack(&pool, stack_ids[1]).await?.image_ids, vec![4, 5]);
+        // Images which are stacked already are left alone
+        assert!(auto_stack(&pool, FOLDER, &AutoStackOptions::default())
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("library_file", "image", "exif", "iptc"))]
+    async fn test_manual_stacks(pool: SqlitePool) -> sqlx::Result<()> {
+        let stack_id = create_stack(&pool, &[1, 2, 3], Some(2)).await?;
+        assert_eq!(get_stack(&pool, stack_id).await?.top_image_id, 2);
+        // Moving an image into another stack takes it out of the old one
+        let other_stack_id = create_stack(&pool, &[3, 4], None).await?;
+        assert_eq!(get_stack(&pool, other_stack_id).await?.top_image_id, 3);
+        assert_eq!(get_stack(&pool, stack_id).await?.image_ids, vec![1, 2]);
+
+        set_stack_top(&pool, stack_id, 1).await?;
+        assert!(set_stack_top(&pool, stack_id, 4).await.is_err());
+        // A stack of one is no stack at all
+        remove_from_stack(&pool, &[1]).await?;
+        assert!(get_stack(&pool, stack_id).await.is_err());
+
+        // Taking the top out of the stack picks another one
+        let stack_id = create_stack(&pool, &[5, 6, 7], None).await?;
+        remove_from_stack(&pool, &[5]).await?;
+        assert_eq!(get_stack(&pool, stack_id).await?.top_image_id, 6);
+        assert!(create_stack(&pool, &[1, 2], Some(5)).await.is_err());
+        Ok(())
+    }
+}
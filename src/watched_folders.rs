@@ -0,0 +1,585 @@
+use crate::{
+    db, image_helpers,
+    metadata_sync::{self, SyncDirection},
+};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use sqlx::{Row, SqlitePool};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+
+// How often pending files are checked for being finished
+const TICK: Duration = Duration::from_millis(250);
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchedFolder {
+    pub id: i64,
+    pub path: String,
+    pub enabled: bool,
+}
+
+#[derive(serde::Serialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchChanges {
+    pub imported: Vec<PathBuf>,
+    pub updated: Vec<PathBuf>,
+    // Files of the catalog which were deleted, they are flagged missing
+    pub missing: Vec<PathBuf>,
+    // Files which couldn't be synced, e.g. because they were locked or went away while
+    // being read. The watcher tries them again.
+    pub failed: Vec<FailedSync>,
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FailedSync {
+    pub path: PathBuf,
+    pub error: String,
+}
+
+pub async fn add_watched_folder(pool: &SqlitePool, path: &Path) -> Result<i64, sqlx::Error> {
+    let row = sqlx::query(
+        "INSERT INTO watched_folder (path) values (?) ON CONFLICT(path) DO UPDATE SET enabled=1 returning id",
+    )
+    .bind(path.to_string_lossy().to_string())
+    .fetch_one(pool)
+    .await?;
+    Ok(row.get("id"))
+}
+
+pub async fn remove_watched_folder(pool: &SqlitePool, id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE from watched_folder where id=?")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn set_watched_folder_enabled(
+    pool: &SqlitePool,
+    id: i64,
+    enabled: bool,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE watched_folder set enabled=? where id=?")
+        .bind(enabled)
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn get_watched_folders(pool: &SqlitePool) -> Result<Vec<WatchedFolder>, sqlx::Error> {
+    let rows = sqlx::query("SELECT id, path, enabled from watched_folder order by path")
+        .fetch_all(pool)
+        .await?;
+    Ok(rows
+        .iter()
+        .map(|row| WatchedFolder {
+            id: row.get("id"),
+            path: row.get("path"),
+            enabled: row.get("enabled"),
+        })
+        .collect())
+}
+
+/// Collects the paths notifications are about and hands them out once they have been
+/// quiet for a while. A file which is still growing (a tethered shot being written, an
+/// export in progress) stays pending until its size stops changing.
+#[derive(Debug)]
+pub struct Debouncer {
+    quiet_period: Duration,
+    // When the path was last touched and its size at that time
+    pending: HashMap<PathBuf, (Instant, Option<u64>)>,
+}
+
+impl Debouncer {
+    pub fn new(quiet_period: Duration) -> Debouncer {
+        Debouncer {
+            quiet_period,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn touch(&mut self, path: PathBuf, now: Instant) {
+        let size = file_size(&path);
+        self.pending.insert(path, (now, size));
+    }
+
+    /// Paths which had no events for the quiet period and whose size is still the same
+    pub fn take_ready(&mut self, now: Instant) -> Vec<PathBuf> {
+        let mut ready = vec![];
+        let quiet: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_, (touched, _))| now.duration_since(*touched) >= self.quiet_period)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in quiet {
+            let size = file_size(&path);
+            if self.pending[&path].1 == size {
+                self.pending.remove(&path);
+                ready.push(path);
+            } else {
+                // Written to without an event reaching us, wait another period
+                self.pending.insert(path, (now, size));
+            }
+        }
+        ready.sort();
+        ready
+    }
+}
+
+fn file_size(path: &Path) -> Option<u64> {
+    fs::metadata(path).ok().map(|metadata| metadata.len())
+}
+
+/// Brings the catalog up to date with changes to the given files: new files are
+/// imported, changed ones get their file details and the metadata of their image
+/// refreshed and deleted ones are flagged missing. A file which fails doesn't hold up
+/// the others, it's listed in failed.
+pub async fn sync_paths(pool: &SqlitePool, paths: &[PathBuf]) -> Result<WatchChanges, sqlx::Error> {
+    let mut changes = WatchChanges::default();
+    let mut folders_to_import: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for path in paths {
+        match sync_path(pool, path, &mut changes).await {
+            Ok(true) => {
+                if let Some(folder) = path.parent() {
+                    folders_to_import
+                        .entry(folder.to_path_buf())
+                        .or_default()
+                        .push(path.clone());
+                }
+            }
+            Ok(false) => {}
+            Err(err) => changes.failed.push(FailedSync {
+                path: path.clone(),
+                error: err.to_string(),
+            }),
+        }
+    }
+    // The import takes care of pairs and sidecars, and leaves out what we already have
+    for (folder, new_files) in folders_to_import {
+        match db::insert_images(pool, &folder.to_string_lossy()).await {
+            Ok(()) => changes.imported.extend(new_files),
+            Err(err) => changes
+                .failed
+                .extend(new_files.into_iter().map(|path| FailedSync {
+                    path,
+                    error: err.to_string(),
+                })),
+        }
+    }
+    Ok(changes)
+}
+
+// Syncs a file of the catalog. Returns whether it's a new file for the import.
+async fn sync_path(
+    pool: &SqlitePool,
+    path: &Path,
+    changes: &mut WatchChanges,
+) -> Result<bool, sqlx::Error> {
+    let path_string = path.to_string_lossy().to_string();
+    let row = sqlx::query("select id, file_modified_time from library_file where path=?")
+        .bind(&path_string)
+        .fetch_optional(pool)
+        .await?;
+    match row {
+        Some(row) if path.is_file() => {
+            let modified_time = image_helpers::file_modified_time(path)?;
+            if modified_time == row.get::<String, _>("file_modified_time") {
+                return Ok(false);
+            }
+            sqlx::query(
+                "UPDATE library_file set file_modified_time=?, file_size=?, hash=?, detected_format=?, missing=0 where id=?",
+            )
+            .bind(modified_time)
+            .bind(fs::metadata(path)?.len() as i64)
+            .bind(image_helpers::file_hash(path)?)
+            .bind(image_helpers::sniff_format(path))
+            .bind(row.get::<i64, _>("id"))
+            .execute(pool)
+            .await?;
+            // Other programs mostly change the metadata, in the image or its sidecar
+            let images = sqlx::query(
+                "select image_id, role from image_file where library_file_id=? and role in ('primary', 'sidecar')",
+            )
+            .bind(row.get::<i64, _>("id"))
+            .fetch_all(pool)
+            .await?;
+            for image in images {
+                let image_id = image.get::<i64, _>("image_id");
+                if image.get::<String, _>("role") == "primary" {
+                    db::refresh_exif(pool, image_id).await?;
+                }
+                metadata_sync::resync_images(pool, &[image_id], SyncDirection::ReadFromFile)
+                    .await?;
+            }
+            changes.updated.push(path.to_path_buf());
+            Ok(false)
+        }
+        Some(row) => {
+            sqlx::query("UPDATE library_file set missing=1 where id=?")
+                .bind(row.get::<i64, _>("id"))
+                .execute(pool)
+                .await?;
+            changes.missing.push(path.to_path_buf());
+            Ok(false)
+        }
+        // A new sidecar is picked up by the import too
+        None => Ok(path.is_file()
+            && (image_helpers::is_importable_file(path)
+                || path
+                    .extension()
+                    .is_some_and(|extension| extension.eq_ignore_ascii_case("xmp")))),
+    }
+}
+
+// Syncs the files in the folder and the ones the catalog has there
+async fn catch_up(pool: &SqlitePool, folder: &Path) -> Result<WatchChanges, sqlx::Error> {
+    let mut paths: BTreeSet<PathBuf> =
+        sqlx::query("select path from library_file where parent_path=?")
+            .bind(folder.to_string_lossy().to_string())
+            .fetch_all(pool)
+            .await?
+            .iter()
+            .map(|row| PathBuf::from(row.get::<String, _>("path")))
+            .collect();
+    for entry in fs::read_dir(folder)? {
+        paths.insert(entry?.path());
+    }
+    sync_paths(pool, &paths.into_iter().collect::<Vec<_>>()).await
+}
+
+/// Keeps the enabled watched folders in sync with the catalog while it's alive
+pub struct FolderWatcher {
+    watcher: Mutex<RecommendedWatcher>,
+    watching: Mutex<Vec<PathBuf>>,
+    // Paths for the worker, from notifications and from queue_path
+    paths: mpsc::UnboundedSender<PathBuf>,
+    worker: JoinHandle<()>,
+}
+
+impl FolderWatcher {
+    /// Starts watching the enabled folders. Files are synced once they were quiet for
+    /// the quiet period, and on_sync is called with what each sync did or why it failed.
+    /// Files which failed are tried again after another quiet period.
+    pub async fn start(
+        pool: SqlitePool,
+        quiet_period: Duration,
+        on_sync: impl Fn(Result<WatchChanges, sqlx::Error>) + Send + 'static,
+    ) -> Result<FolderWatcher, sqlx::Error> {
+        let (paths, mut receiver) = mpsc::unbounded_channel::<PathBuf>();
+        let sender = paths.clone();
+        let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            if let Ok(event) = event {
+                for path in event.paths {
+                    let _ = sender.send(path);
+                }
+            }
+        })
+        .map_err(io::Error::other)?;
+
+        // Importing holds on to exiv2 handles across awaits, which can't move between
+        // threads, so the syncing runs on a thread of its own
+        let worker_pool = pool.clone();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let worker = thread::spawn(move || {
+            runtime.block_on(async move {
+                let mut debouncer = Debouncer::new(quiet_period);
+                let mut tick = tokio::time::interval(TICK);
+                loop {
+                    tokio::select! {
+                        path = receiver.recv() => match path {
+                            Some(path) => debouncer.touch(path, Instant::now()),
+                            // The watcher was stopped
+                            None => break,
+                        },
+                        _ = tick.tick() => {
+                            let ready = debouncer.take_ready(Instant::now());
+                            if !ready.is_empty() {
+                                let result = sync_paths(&worker_pool, &ready).await;
+                                // Taken from the debouncer already, so they go back in
+                                let retry = match &result {
+                                    Ok(changes) => changes.failed.iter().map(|failed| failed.path.clone()).collect(),
+                                    Err(_) => ready,
+                                };
+                                for path in retry {
+                                    debouncer.touch(path, Instant::now());
+                                }
+                                on_sync(result);
+                            }
+                        }
+                    }
+                }
+            })
+        });
+
+        let folder_watcher = FolderWatcher {
+            watcher: Mutex::new(watcher),
+            watching: Mutex::new(vec![]),
+            paths,
+            worker,
+        };
+        folder_watcher.reload(&pool).await?;
+        Ok(folder_watcher)
+    }
+
+    /// Picks up folders which were added, removed, turned on or turned off. A folder which
+    /// starts being watched is caught up with what changed while it wasn't.
+    pub async fn reload(&self, pool: &SqlitePool) -> Result<(), sqlx::Error> {
+        let wanted: Vec<PathBuf> = get_watched_folders(pool)
+            .await?
+            .into_iter()
+            .filter(|folder| folder.enabled)
+            .map(|folder| PathBuf::from(folder.path))
+            .collect();
+        let mut started = vec![];
+        {
+            let mut watcher = self.watcher.lock().unwrap();
+            let mut watching = self.watching.lock().unwrap();
+            for folder in watching.iter().filter(|folder| !wanted.contains(folder)) {
+                let _ = watcher.unwatch(folder);
+            }
+            watching.retain(|folder| wanted.contains(folder));
+            for folder in wanted {
+                if watching.contains(&folder) {
+                    continue;
+                }
+                // A folder on a disk which isn't connected is skipped until the next reload
+                if watcher.watch(&folder, RecursiveMode::NonRecursive).is_ok() {
+                    watching.push(folder.clone());
+                    started.push(folder);
+                }
+            }
+        }
+        for folder in started {
+            catch_up(pool, &folder).await?;
+        }
+        Ok(())
+    }
+
+    /// Syncs the file once it was quiet for the quiet period, the same as when a
+    /// notification comes for it. For changes which don't notify, e.g. on network shares.
+    pub fn queue_path(&self, path: PathBuf) {
+        let _ = self.paths.send(path);
+    }
+
+    /// Stops watching. Files which were still pending are picked up when the folder is
+    /// watched again.
+    pub fn stop(self) {
+        drop(self.watcher);
+        drop(self.paths);
+        let _ = self.worker.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debouncer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("DSCF0001.RAF");
+        fs::write(&path, b"half").unwrap();
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(Duration::from_secs(2));
+        debouncer.touch(path.clone(), start);
+        debouncer.touch(path.clone(), start + Duration::from_secs(1));
+        assert!(debouncer
+            .take_ready(start + Duration::from_secs(2))
+            .is_empty());
+
+        // Still being written
+        fs::write(&path, b"half and the rest").unwrap();
+        assert!(debouncer
+            .take_ready(start + Duration::from_secs(3))
+            .is_empty());
+        assert_eq!(
+            debouncer.take_ready(start + Duration::from_secs(5)),
+            vec![path]
+        );
+        assert!(debouncer
+            .take_ready(start + Duration::from_secs(9))
+            .is_empty());
+    }
+
+    #[sqlx::test]
+    async fn test_sync_paths(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let first = dir.path().join("DSCF0001.jpg");
+        let second = dir.path().join("DSCF0002.jpg");
+        fs::write(&first, b"first")?;
+        db::insert_images(&pool, &dir.path().to_string_lossy()).await?;
+
+        fs::write(&second, b"second")?;
+        fs::File::options()
+            .write(true)
+            .open(&first)?
+            .set_modified(std::time::SystemTime::now() + Duration::from_secs(10))?;
+        let changes = sync_paths(&pool, &[first.clone(), second.clone()]).await?;
+        assert_eq!(changes.imported, vec![second.clone()]);
+        assert_eq!(changes.updated, vec![first.clone()]);
+
+        fs::remove_file(&second)?;
+        let changes = sync_paths(&pool, std::slice::from_ref(&second)).await?;
+        assert_eq!(changes.missing, vec![second]);
+        let row = sqlx::query("select count(*) as count from library_file where missing=1")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(row.get::<i64, _>("count"), 1);
+
+        // The JPEG of a RAW which is in the catalog already joins its image
+        let raw = dir.path().join("DSCF0003.RAF");
+        let jpeg = dir.path().join("DSCF0003.JPG");
+        fs::write(&raw, b"raw")?;
+        sync_paths(&pool, std::slice::from_ref(&raw)).await?;
+        fs::write(&jpeg, b"jpeg")?;
+        let changes = sync_paths(&pool, std::slice::from_ref(&jpeg)).await?;
+        assert_eq!(changes.imported, vec![jpeg.clone()]);
+        let row = sqlx::query(
+            "select f.image_id, f.role from image_file f join library_file lf on lf.id = f.library_file_id where lf.path=?",
+        )
+        .bind(jpeg.to_string_lossy().to_string())
+        .fetch_one(&pool)
+        .await?;
+        assert_eq!(row.get::<String, _>("role"), "secondary");
+        let raw_image_id: i64 = sqlx::query(
+            "select f.image_id from image_file f join library_file lf on lf.id = f.library_file_id where lf.path=? and f.role='primary'",
+        )
+        .bind(raw.to_string_lossy().to_string())
+        .fetch_one(&pool)
+        .await?
+        .get("image_id");
+        assert_eq!(row.get::<i64, _>("image_id"), raw_image_id);
+
+        // Another program rates the image in its sidecar
+        let sidecar = dir.path().join("DSCF0003.xmp");
+        fs::write(&sidecar, r#"<rdf:Description xmp:Rating="2"/>"#)?;
+        let changes = sync_paths(&pool, std::slice::from_ref(&sidecar)).await?;
+        assert_eq!(changes.imported, vec![sidecar.clone()]);
+        let row = sqlx::query("select rating from image where id=?")
+            .bind(raw_image_id)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(row.get::<i64, _>("rating"), 2);
+        fs::write(&sidecar, r#"<rdf:Description xmp:Rating="5"/>"#)?;
+        fs::File::options()
+            .write(true)
+            .open(&sidecar)?
+            .set_modified(std::time::SystemTime::now() + Duration::from_secs(10))?;
+        let changes = sync_paths(&pool, std::slice::from_ref(&sidecar)).await?;
+        assert_eq!(changes.updated, vec![sidecar]);
+        let row = sqlx::query("select rating from image where id=?")
+            .bind(raw_image_id)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(row.get::<i64, _>("rating"), 5);
+
+        // A file which can't be synced doesn't hold up the others
+        sqlx::query(
+            "create trigger locked before update on library_file when new.path like '%DSCF0001.jpg' begin select raise(abort, 'locked'); end",
+        )
+        .execute(&pool)
+        .await?;
+        for path in [&first, &raw] {
+            fs::File::options()
+                .write(true)
+                .open(path)?
+                .set_modified(std::time::SystemTime::now() + Duration::from_secs(20))?;
+        }
+        let changes = sync_paths(&pool, &[first.clone(), raw.clone()]).await?;
+        assert_eq!(changes.updated, vec![raw]);
+        assert_eq!(changes.failed.len(), 1);
+        assert_eq!(changes.failed[0].path, first);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_watch_folder(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let id = add_watched_folder(&pool, dir.path()).await?;
+        let (sender, mut synced) = mpsc::unbounded_channel();
+        let watcher =
+            FolderWatcher::start(pool.clone(), Duration::from_millis(200), move |result| {
+                let _ = sender.send(result);
+            })
+            .await?;
+
+        // Queued rather than left to a notification, which may come late on a busy machine
+        let shot = dir.path().join("DSCF0001.jpg");
+        fs::write(&shot, b"tethered shot")?;
+        watcher.queue_path(shot.clone());
+        let changes = tokio::time::timeout(Duration::from_secs(10), synced.recv())
+            .await
+            .expect("no sync after the file was written")
+            .unwrap()?;
+        assert_eq!(changes.imported, vec![shot]);
+
+        // Turned off, and the state is kept in the catalog
+        set_watched_folder_enabled(&pool, id, false).await?;
+        watcher.reload(&pool).await?;
+        assert!(!get_watched_folders(&pool).await?[0].enabled);
+        assert!(watcher.watching.lock().unwrap().is_empty());
+        fs::write(dir.path().join("DSCF0002.jpg"), b"not watched")?;
+
+        // Turning it back on catches up
+        set_watched_folder_enabled(&pool, id, true).await?;
+        watcher.reload(&pool).await?;
+        assert_eq!(
+            *watcher.watching.lock().unwrap(),
+            vec![dir.path().to_path_buf()]
+        );
+        let row = sqlx::query("select count(*) as count from image")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(row.get::<i64, _>("count"), 2);
+
+        // Removed, the catalog keeps the images
+        remove_watched_folder(&pool, id).await?;
+        watcher.reload(&pool).await?;
+        assert!(get_watched_folders(&pool).await?.is_empty());
+        assert!(watcher.watching.lock().unwrap().is_empty());
+        watcher.stop();
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_watch_folder_notification(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        add_watched_folder(&pool, dir.path()).await?;
+        let (sender, mut synced) = mpsc::unbounded_channel();
+        let watcher =
+            FolderWatcher::start(pool.clone(), Duration::from_millis(200), move |result| {
+                let _ = sender.send(result);
+            })
+            .await?;
+
+        // Written by another program, only the notification tells the watcher. It can take
+        // a while on a busy machine.
+        let shot = dir.path().join("DSCF0001.jpg");
+        fs::write(&shot, b"tethered shot")?;
+        let imported = tokio::time::timeout(Duration::from_secs(30), async {
+            while let Some(result) = synced.recv().await {
+                if result?.imported.contains(&shot) {
+                    return Ok(true);
+                }
+            }
+            Ok::<_, sqlx::Error>(false)
+        })
+        .await
+        .expect("the written file wasn't imported")?;
+        assert!(imported);
+        watcher.stop();
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+// Small scanners for the few bits of xml we read: points of gpx tracks and properties of
+// xmp packets, which can also sit in the middle of binary image data. They don't check
+// that the document is well formed, other applications' files often aren't.
+
+/// The value of an attribute, the first one of that name in the given xml. The name has
+/// to match as a whole ("lat" doesn't match "xlat="), there can be whitespace around the
+/// "=" and either quote can be used. Entities are decoded.
+pub fn attribute(xml: &str, name: &str) -> Option<String> {
+    let mut search_from = 0;
+    while let Some(found) = xml[search_from..].find(name) {
+        let position = search_from + found;
+        search_from = position + name.len();
+        let whole_name = xml[..position]
+            .chars()
+            .last()
+            .is_none_or(char::is_whitespace);
+        if !whole_name {
+            continue;
+        }
+        let Some(value) = xml[search_from..]
+            .trim_start()
+            .strip_prefix('=')
+            .map(str::trim_start)
+        else {
+            continue;
+        };
+        let Some(quote) = value.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            continue;
+        };
+        let value = &value[1..];
+        return value.find(quote).map(|end| unescape(&value[..end]));
+    }
+    None
+}
+
+/// Everything between <name ...> and </name>, for the first element of that name. None
+/// when there is no such element or it's empty (<name/>).
+pub fn element_content<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}", name);
+    let close = format!("</{}>", name);
+    let mut search_from = 0;
+    while let Some(found) = xml[search_from..].find(&open) {
+        let start = search_from + found + open.len();
+        search_from = start;
+        // Make sure this is the element and not one whose name starts the same way
+        match xml[start..].chars().next() {
+            Some('>') => {}
+            Some(c) if c.is_whitespace() => {}
+            Some('/') => return None,
+            _ => continue,
+        }
+        let tag_end = start + xml[start..].find('>')?;
+        if xml[..tag_end].ends_with('/') {
+            return None;
+        }
+        let content_end = tag_end + 1 + xml[tag_end + 1..].find(&close)?;
+        return Some(&xml[tag_end + 1..content_end]);
+    }
+    None
+}
+
+/// The text of the first element of that name, trimmed and with entities decoded
+pub fn element_text(xml: &str, name: &str) -> Option<String> {
+    element_content(xml, name).map(|content| unescape(content.trim()))
+}
+
+/// The start tags of the elements of that name, from after the name up to the closing
+/// ">", i.e. their attributes
+pub fn start_tags<'a>(xml: &'a str, name: &str) -> impl Iterator<Item = &'a str> {
+    let open = format!("<{}", name);
+    let mut rest = xml;
+    std::iter::from_fn(move || loop {
+        let start = rest.find(&open)? + open.len();
+        let tag_end = start + rest[start..].find('>')?;
+        let tag = &rest[start..tag_end];
+        rest = &rest[tag_end..];
+        if tag.is_empty() || tag.starts_with(char::is_whitespace) || tag == "/" {
+            return Some(tag);
+        }
+    })
+}
+
+pub fn unescape(value: &str) -> String {
+    let mut text = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('&') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest.find(';').and_then(|end| {
+            let character = match &rest[1..end] {
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "amp" => Some('&'),
+                entity => entity
+                    .strip_prefix("#x")
+                    .map(|hex| u32::from_str_radix(hex, 16))
+                    .or_else(|| entity.strip_prefix('#').map(str::parse))
+                    .and_then(Result::ok)
+                    .and_then(char::from_u32),
+            }?;
+            Some((character, end + 1))
+        });
+        match decoded {
+            Some((character, length)) => {
+                text.push(character);
+                rest = &rest[length..];
+            }
+            None => {
+                text.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attribute() {
+        let tag = r#"<trkpt xlat="1" lat = '12.97' lon="77.59" name="A &amp; B">"#;
+        assert_eq!(attribute(tag, "lat").as_deref(), Some("12.97"));
+        assert_eq!(attribute(tag, "lon").as_deref(), Some("77.59"));
+        assert_eq!(attribute(tag, "name").as_deref(), Some("A & B"));
+        assert_eq!(attribute(tag, "ele"), None);
+        assert_eq!(attribute("lat=12", "lat"), None);
+    }
+
+    #[test]
+    fn test_element_text() {
+        let xml = "<trkpt><elevation>1</elevation><ele unit=\"m\"> 900 </ele><time/></trkpt>";
+        assert_eq!(element_text(xml, "ele").as_deref(), Some("900"));
+        assert_eq!(element_text(xml, "time"), None);
+        assert_eq!(element_text(xml, "name"), None);
+        assert_eq!(
+            element_text(
+                "<dc:title>Tom &#x26; Jerry&#33; &nbsp;</dc:title>",
+                "dc:title"
+            )
+            .as_deref(),
+            Some("Tom & Jerry! &nbsp;")
+        );
+    }
+
+    #[test]
+    fn test_start_tags() {
+        let xml = r#"<Container:Item Item:Mime="image/jpeg"/><Container:Items><Container:Item
+            Item:Semantic="MotionPhoto" Item:Length="42"/>"#;
+        let tags: Vec<&str> = start_tags(xml, "Container:Item").collect();
+        assert_eq!(tags.len(), 2);
+        assert_eq!(attribute(tags[1], "Item:Length").as_deref(), Some("42"));
+    }
+}
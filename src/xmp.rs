@@ -0,0 +1,1255 @@
+use crate::{db, image_helpers, xml};
+use sqlx::{Row, Sqlite, SqlitePool, Transaction};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The parts of xmp we carry over into the catalog. Every field is optional since other
+/// tools only write what the user actually set.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct XmpData {
+    // xmp:Rating, 0 to 5, with -1 meaning rejected
+    pub rating: Option<i32>,
+    // xmp:Label, e.g. "Red"
+    pub label: Option<String>,
+    // xmpDM:pick, 1 for picked, -1 for rejected and 0 for neither
+    pub pick: Option<i32>,
+    // dc:subject
+    pub keywords: Vec<String>,
+    // lr:hierarchicalSubject, e.g. "Places|India|Bengaluru"
+    pub hierarchical_keywords: Vec<String>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub creator: Option<String>,
+    pub copyright: Option<String>,
+    // The other IPTC Core and Extension values of the catalog, by their xmp tag in
+    // image_helpers::IPTC_FIELDS. Only filled for writing back, imports read them through
+    // the registry.
+    pub iptc: Vec<(&'static str, String)>,
+}
+
+impl XmpData {
+    /// Reads the xmp embedded in an image through exiv2
+    pub fn from_metadata(meta: &rexiv2::Metadata) -> XmpData {
+        let text = |tag: &str| {
+            meta.get_tag_string(tag)
+                .ok()
+                .map(|value| strip_lang_alt(&value).to_string())
+                .filter(|value| !value.is_empty())
+        };
+        XmpData {
+            rating: text("Xmp.xmp.Rating").and_then(|rating| parse_number(&rating)),
+            label: text("Xmp.xmp.Label"),
+            pick: text("Xmp.xmpDM.pick").and_then(|pick| parse_number(&pick)),
+            keywords: meta
+                .get_tag_multiple_strings("Xmp.dc.subject")
+                .unwrap_or_default(),
+            hierarchical_keywords: meta
+                .get_tag_multiple_strings("Xmp.lr.hierarchicalSubject")
+                .unwrap_or_default(),
+            title: text("Xmp.dc.title"),
+            description: text("Xmp.dc.description"),
+            creator: meta
+                .get_tag_multiple_strings("Xmp.dc.creator")
+                .ok()
+                .and_then(|creators| creators.into_iter().next()),
+            copyright: text("Xmp.dc.rights"),
+            iptc: vec![],
+        }
+    }
+
+    /// Parses an xmp packet, i.e. the content of a sidecar file. Properties can be
+    /// written either as attributes of rdf:Description or as elements and we accept both.
+    /// Namespaces are matched by their usual prefixes, which is what every photo
+    /// application writes.
+    pub fn from_packet(packet: &str) -> XmpData {
+        XmpData {
+            rating: property(packet, "xmp:Rating").and_then(|rating| parse_number(&rating)),
+            label: property(packet, "xmp:Label"),
+            pick: property(packet, "xmpDM:pick").and_then(|pick| parse_number(&pick)),
+            keywords: property_list(packet, "dc:subject"),
+            hierarchical_keywords: property_list(packet, "lr:hierarchicalSubject"),
+            title: property(packet, "dc:title"),
+            description: property(packet, "dc:description"),
+            creator: property(packet, "dc:creator"),
+            copyright: property(packet, "dc:rights"),
+            iptc: vec![],
+        }
+    }
+
+    /// Fills the gaps in self with the values from other, e.g. sidecar values on top of
+    /// the embedded ones
+    pub fn or(self, other: XmpData) -> XmpData {
+        XmpData {
+            rating: self.rating.or(other.rating),
+            label: self.label.or(other.label),
+            pick: self.pick.or(other.pick),
+            keywords: if self.keywords.is_empty() {
+                other.keywords
+            } else {
+                self.keywords
+            },
+            hierarchical_keywords: if self.hierarchical_keywords.is_empty() {
+                other.hierarchical_keywords
+            } else {
+                self.hierarchical_keywords
+            },
+            title: self.title.or(other.title),
+            description: self.description.or(other.description),
+            creator: self.creator.or(other.creator),
+            copyright: self.copyright.or(other.copyright),
+            iptc: if self.iptc.is_empty() {
+                other.iptc
+            } else {
+                self.iptc
+            },
+        }
+    }
+
+    /// The catalog flag ("picked", "rejected" or "unpicked") if the xmp says anything
+    /// about it. Lightroom marks rejects with a rating of -1.
+    pub fn flag(&self) -> Option<&'static str> {
+        match (self.pick, self.rating) {
+            (Some(pick), _) if pick > 0 => Some("picked"),
+            (Some(pick), _) if pick < 0 => Some("rejected"),
+            (_, Some(-1)) => Some("rejected"),
+            (Some(_), _) => Some("unpicked"),
+            _ => None,
+        }
+    }
+
+    pub fn color_label(&self) -> Option<String> {
+        self.label.as_ref().map(|label| label.to_lowercase())
+    }
+
+    /// Keywords as we keep them in the tag table. Hierarchical keywords are stored as
+    /// their full path ("Places|India"). Applications also write each part of the path
+    /// to dc:subject, and we don't want those to show up as separate keywords.
+    pub fn catalog_keywords(&self) -> Vec<String> {
+        let parts: HashSet<&str> = self
+            .hierarchical_keywords
+            .iter()
+            .flat_map(|keyword| keyword.split('|'))
+            .collect();
+        let mut keywords = self.hierarchical_keywords.clone();
+        keywords.extend(
+            self.keywords
+                .iter()
+                .filter(|keyword| !parts.contains(keyword.as_str()))
+                .cloned(),
+        );
+        keywords
+    }
+
+    /// Builds the xmp for an image from what the catalog has for it
+    pub async fn from_catalog(pool: &SqlitePool, image_id: i64) -> Result<XmpData, sqlx::Error> {
+        let image = sqlx::query("SELECT rating, flag, color_label from image where id=?")
+            .bind(image_id)
+            .fetch_one(pool)
+            .await?;
+        let tags = sqlx::query("SELECT tag_name from tag where image_id=? order by tag_name")
+            .bind(image_id)
+            .fetch_all(pool)
+            .await?;
+        let iptc = sqlx::query("SELECT * from iptc where image_id=? limit 1")
+            .bind(image_id)
+            .fetch_optional(pool)
+            .await?;
+
+        let mut keywords: Vec<String> = vec![];
+        let mut hierarchical_keywords = vec![];
+        for tag in tags {
+            let tag_name = tag.get::<String, _>("tag_name");
+            for part in tag_name.split('|') {
+                if !keywords.iter().any(|keyword| keyword == part) {
+                    keywords.push(part.to_string());
+                }
+            }
+            if tag_name.contains('|') {
+                hierarchical_keywords.push(tag_name);
+            }
+        }
+
+        let color_label = image.get::<String, _>("color_label");
+        let label = Some(color_label)
+            .filter(|label| label != "none" && !label.is_empty())
+            .map(|label| {
+                let mut chars = label.chars();
+                match chars.next() {
+                    Some(first) => first.to_uppercase().chain(chars).collect(),
+                    None => label,
+                }
+            });
+        let iptc_value = |column: &str| {
+            iptc.as_ref()
+                .and_then(|iptc| iptc.get::<Option<String>, _>(column))
+                .filter(|value| !value.is_empty())
+        };
+        let pick = match image.get::<Option<String>, _>("flag").as_deref() {
+            Some("picked") => 1,
+            Some("rejected") => -1,
+            _ => 0,
+        };
+
+        Ok(XmpData {
+            rating: image.get::<Option<i32>, _>("rating"),
+            label,
+            pick: Some(pick),
+            keywords,
+            hierarchical_keywords,
+            title: iptc_value("title"),
+            description: iptc_value("description"),
+            creator: None,
+            copyright: None,
+            iptc: image_helpers::IPTC_FIELDS
+                .iter()
+                .filter(|field| !["title", "description"].contains(&field.column))
+                .filter_map(|field| Some((field.xmp, iptc_value(field.column)?)))
+                .collect(),
+        })
+    }
+}
+
+fn parse_number(value: &str) -> Option<i32> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .map(|value| value.round() as i32)
+}
+
+// exiv2 returns LangAlt values like `lang="x-default" A title`
+pub(crate) fn strip_lang_alt(value: &str) -> &str {
+    if let Some(rest) = value.strip_prefix("lang=\"") {
+        if let Some(end) = rest.find('"') {
+            return rest[end + 1..].trim_start();
+        }
+    }
+    value
+}
+
+/// Sidecars are usually named after the image without its extension (IMG_0001.xmp), but
+/// some applications keep the extension (IMG_0001.CR2.xmp). We look for both, the
+/// second first since it can only belong to this image.
+pub fn sidecar_path(image_path: &Path) -> Option<PathBuf> {
+    [
+        full_name_sidecar_path(image_path),
+        image_path.with_extension("xmp"),
+        image_path.with_extension("XMP"),
+    ]
+    .into_iter()
+    .find(|candidate| candidate.is_file())
+}
+
+/// IMG_0001.CR2.xmp for IMG_0001.CR2
+pub fn full_name_sidecar_path(image_path: &Path) -> PathBuf {
+    let mut with_extension = image_path.as_os_str().to_owned();
+    with_extension.push(".xmp");
+    PathBuf::from(with_extension)
+}
+
+/// The file an image's xmp is read from: its sidecar if it has one, otherwise the
+/// image itself
+pub fn metadata_source(image_path: &Path) -> PathBuf {
+    sidecar_path(image_path).unwrap_or_else(|| image_path.to_path_buf())
+}
+
+/// Reads xmp from the image itself and from its sidecar. When both have a value the
+/// sidecar wins, since that is where editors write when they don't touch the original.
+pub fn read_xmp(image_path: &Path) -> XmpData {
+    let sidecar = sidecar_path(image_path)
+        .and_then(|sidecar| fs::read_to_string(sidecar).ok())
+        .map(|packet| XmpData::from_packet(&packet))
+        .unwrap_or_default();
+    let embedded = rexiv2::Metadata::new_from_path(image_path)
+        .map(|meta| XmpData::from_metadata(&meta))
+        .unwrap_or_default();
+    sidecar.or(embedded)
+}
+
+/// Copies the xmp values into the catalog. Values the xmp doesn't have are left as they
+/// are, and iptc fields are only filled if iptc didn't already have them.
+pub async fn write_xmp_to_db<'a>(
+    tx: &mut Transaction<'a, Sqlite>,
+    image_id: i64,
+    xmp: &XmpData,
+) -> Result<(), sqlx::Error> {
+    if let Some(rating) = xmp.rating {
+        sqlx::query("UPDATE image set rating=? where id=?")
+            .bind(rating.clamp(0, 5))
+            .bind(image_id)
+            .execute(&mut **tx)
+            .await?;
+    }
+    if let Some(flag) = xmp.flag() {
+        sqlx::query("UPDATE image set flag=? where id=?")
+            .bind(flag)
+            .bind(image_id)
+            .execute(&mut **tx)
+            .await?;
+    }
+    if let Some(color_label) = xmp.color_label() {
+        sqlx::query("UPDATE image set color_label=? where id=?")
+            .bind(color_label)
+            .bind(image_id)
+            .execute(&mut **tx)
+            .await?;
+    }
+    for keyword in &xmp.catalog_keywords() {
+        sqlx::query("INSERT OR IGNORE into tag (image_id, tag_name) values (?, ?)")
+            .bind(image_id)
+            .bind(keyword)
+            .execute(&mut **tx)
+            .await?;
+    }
+    sqlx::query(
+        r#"UPDATE iptc set title = coalesce(title, ?), description = coalesce(description, ?),
+        creator = coalesce(creator, ?), copyright = coalesce(copyright, ?) where image_id=?"#,
+    )
+    .bind(&xmp.title)
+    .bind(&xmp.description)
+    .bind(&xmp.creator)
+    .bind(&xmp.copyright)
+    .bind(image_id)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+const WRITE_BACK_SETTING: &str = "xmp_write_back";
+
+/// Whether catalog changes are written back to xmp so that other applications see them.
+/// Embed only applies to jpeg files, everything else still gets a sidecar.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum XmpWriteBack {
+    Off,
+    Sidecar,
+    Embed,
+}
+
+impl XmpWriteBack {
+    fn as_str(&self) -> &'static str {
+        match self {
+            XmpWriteBack::Off => "off",
+            XmpWriteBack::Sidecar => "sidecar",
+            XmpWriteBack::Embed => "embed",
+        }
+    }
+
+    fn from_setting(value: &str) -> XmpWriteBack {
+        match value {
+            "sidecar" => XmpWriteBack::Sidecar,
+            "embed" => XmpWriteBack::Embed,
+            _ => XmpWriteBack::Off,
+        }
+    }
+}
+
+pub async fn get_xmp_write_back(pool: &SqlitePool) -> Result<XmpWriteBack, sqlx::Error> {
+    let value = db::get_setting(pool, WRITE_BACK_SETTING).await?;
+    Ok(value.map_or(XmpWriteBack::Off, |value| {
+        XmpWriteBack::from_setting(&value)
+    }))
+}
+
+pub async fn set_xmp_write_back(pool: &SqlitePool, mode: XmpWriteBack) -> Result<(), sqlx::Error> {
+    db::set_setting(pool, WRITE_BACK_SETTING, mode.as_str()).await
+}
+
+#[derive(serde::Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", tag = "status", content = "path")]
+pub enum WriteBackOutcome {
+    Disabled,
+    Written(PathBuf),
+    // The file was changed by another program since we last read or wrote it, so we
+    // didn't touch it
+    Conflict(PathBuf),
+    // The image is on a root which is offline, it's written when the root is back
+    Queued,
+    // Writing the file went wrong, e.g. it's read-only. The catalog has the change all
+    // the same.
+    Failed(String),
+}
+
+/// Writes the catalog metadata of an image to xmp if write back is turned on. Called
+/// after every rating, label, flag or keyword change.
+pub async fn write_back_if_enabled(
+    pool: &SqlitePool,
+    image_id: i64,
+) -> Result<WriteBackOutcome, sqlx::Error> {
+    let mode = get_xmp_write_back(pool).await?;
+    failed_on_io(write_back_image(pool, image_id, mode, false).await)
+}
+
+// A write-back which follows a change to the catalog reports a file it couldn't write
+// rather than failing the change, which was saved already
+pub(crate) fn failed_on_io(
+    result: Result<WriteBackOutcome, sqlx::Error>,
+) -> Result<WriteBackOutcome, sqlx::Error> {
+    match result {
+        Err(sqlx::Error::Io(err)) => Ok(WriteBackOutcome::Failed(err.to_string())),
+        result => result,
+    }
+}
+
+/// Writes rating, label, pick flag, keywords, title and caption of an image to its
+/// sidecar, or into the jpeg itself when embedding. Unless force is set, nothing is
+/// written when the target changed on disk since we last synced with it.
+pub async fn write_back_image(
+    pool: &SqlitePool,
+    image_id: i64,
+    mode: XmpWriteBack,
+    force: bool,
+) -> Result<WriteBackOutcome, sqlx::Error> {
+    if mode == XmpWriteBack::Off {
+        return Ok(WriteBackOutcome::Disabled);
+    }
+    let row = sqlx::query(
+        r#"SELECT lf.path, exists (select 1 from root_folder r where r.id = lf.root_folder_id and r.online = 0) as offline
+        from image i join library_file lf on lf.id = i.library_file_id where i.id=?"#,
+    )
+    .bind(image_id)
+    .fetch_one(pool)
+    .await?;
+    if row.get::<bool, _>("offline") {
+        sqlx::query(
+            r#"INSERT INTO pending_write_back (image_id, force) values (?, ?)
+            ON CONFLICT(image_id) DO UPDATE SET force = max(force, excluded.force)"#,
+        )
+        .bind(image_id)
+        .bind(force)
+        .execute(pool)
+        .await?;
+        return Ok(WriteBackOutcome::Queued);
+    }
+    let image_path = PathBuf::from(row.get::<String, _>("path"));
+
+    let embed = mode == XmpWriteBack::Embed
+        && image_helpers::read_image_header(&image_path)
+            .is_some_and(|header| header.format == "jpeg");
+    let target = if embed {
+        image_path.clone()
+    } else if shares_stem_with_other_image(pool, image_id, &image_path).await? {
+        // IMG_0001.jpg and IMG_0001.jpeg can't both use IMG_0001.xmp
+        full_name_sidecar_path(&image_path)
+    } else {
+        sidecar_path(&image_path).unwrap_or_else(|| image_path.with_extension("xmp"))
+    };
+
+    if !force && has_changed_since_sync(pool, image_id, &target, !embed).await? {
+        return Ok(WriteBackOutcome::Conflict(target));
+    }
+
+    let xmp = XmpData::from_catalog(pool, image_id).await?;
+    if embed {
+        embed_xmp(&target, &xmp).map_err(|e| sqlx::Error::Io(std::io::Error::other(e)))?;
+    } else {
+        let existing = fs::read_to_string(&target).ok();
+        fs::write(&target, update_packet(existing.as_deref(), &xmp))?;
+    }
+    record_sync_state(pool, image_id, &target).await?;
+    Ok(WriteBackOutcome::Written(target))
+}
+
+// Whether another image in the folder has a file with the same name before the
+// extension. Files of the same image, like a RAW+JPEG pair, share their sidecar.
+async fn shares_stem_with_other_image(
+    pool: &SqlitePool,
+    image_id: i64,
+    image_path: &Path,
+) -> Result<bool, sqlx::Error> {
+    let (Some(parent), Some(stem)) = (image_path.parent(), image_path.file_stem()) else {
+        return Ok(false);
+    };
+    let row = sqlx::query(
+        r#"select count(*) as count from library_file lf join image_file f on f.library_file_id = lf.id
+        where lf.parent_path = ? and lf.base_name = ? and f.image_id != ? and f.role in ('primary', 'secondary')"#,
+    )
+    .bind(parent.to_string_lossy().to_string())
+    .bind(stem.to_string_lossy().to_string())
+    .bind(image_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(row.get::<i64, _>("count") > 0)
+}
+
+#[derive(serde::Serialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FlushedWriteBacks {
+    pub outcomes: Vec<(i64, WriteBackOutcome)>,
+    // Write-backs which failed, e.g. because the file can't be written. They stay queued
+    // and are tried again with the next flush.
+    pub failed: Vec<(i64, String)>,
+}
+
+/// Does the write-backs which were queued while their root was offline, for the roots
+/// which are online now. The current write back mode applies, and a sidecar changed by
+/// another program in the meantime is left alone as usual. An image which fails doesn't
+/// hold up the others.
+pub async fn flush_pending_write_backs(
+    pool: &SqlitePool,
+) -> Result<FlushedWriteBacks, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"SELECT p.image_id, p.force from pending_write_back p
+        join image i on i.id = p.image_id join library_file lf on lf.id = i.library_file_id
+        where not exists (select 1 from root_folder r where r.id = lf.root_folder_id and r.online = 0)
+        order by p.queued_at, p.image_id"#,
+    )
+    .fetch_all(pool)
+    .await?;
+    let mode = get_xmp_write_back(pool).await?;
+    let mut flushed = FlushedWriteBacks::default();
+    for row in rows {
+        let image_id: i64 = row.get("image_id");
+        match write_back_image(pool, image_id, mode, row.get("force")).await {
+            Ok(outcome) => {
+                sqlx::query("DELETE from pending_write_back where image_id=?")
+                    .bind(image_id)
+                    .execute(pool)
+                    .await?;
+                flushed.outcomes.push((image_id, outcome));
+            }
+            Err(err) => flushed.failed.push((image_id, err.to_string())),
+        }
+    }
+    Ok(flushed)
+}
+
+/// Remembers the modified time and hash of the file we just read xmp from or wrote it
+/// to, so that later changes by other programs can be detected
+pub async fn record_sync_state<'c, E>(
+    executor: E,
+    image_id: i64,
+    path: &Path,
+) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'c, Database = Sqlite>,
+{
+    let hash = image_helpers::file_hash(path)?;
+    record_sync_state_with_hash(executor, image_id, path, &hash).await
+}
+
+/// record_sync_state for a file whose hash we have already
+pub async fn record_sync_state_with_hash<'c, E>(
+    executor: E,
+    image_id: i64,
+    path: &Path,
+    hash: &str,
+) -> Result<(), sqlx::Error>
+where
+    E: sqlx::Executor<'c, Database = Sqlite>,
+{
+    let file_modified_time = image_helpers::file_modified_time(path)?;
+    sqlx::query(
+        r#"INSERT INTO xmp_sync (image_id, path, file_modified_time, hash) values (?, ?, ?, ?)
+        ON CONFLICT(image_id) DO UPDATE SET path=excluded.path, file_modified_time=excluded.file_modified_time,
+        hash=excluded.hash, synced_at=CURRENT_TIMESTAMP"#,
+    )
+    .bind(image_id)
+    .bind(path.to_string_lossy().to_string())
+    .bind(file_modified_time)
+    .bind(hash)
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// True when the file exists and isn't the one we last synced with. A sidecar we have
+/// never seen was made by someone else, so that counts as changed too.
+async fn has_changed_since_sync(
+    pool: &SqlitePool,
+    image_id: i64,
+    path: &Path,
+    unknown_is_changed: bool,
+) -> Result<bool, sqlx::Error> {
+    if !path.exists() {
+        return Ok(false);
+    }
+    let row = sqlx::query("SELECT path, file_modified_time, hash from xmp_sync where image_id=?")
+        .bind(image_id)
+        .fetch_optional(pool)
+        .await?;
+    let Some(row) = row.filter(|row| row.get::<String, _>("path") == path.to_string_lossy()) else {
+        return Ok(unknown_is_changed);
+    };
+    // Only hash when the modified time moved, most of the time it didn't
+    if image_helpers::file_modified_time(path)? == row.get::<String, _>("file_modified_time") {
+        return Ok(false);
+    }
+    Ok(image_helpers::file_hash(path)? != row.get::<String, _>("hash"))
+}
+
+fn embed_xmp(path: &Path, xmp: &XmpData) -> Result<(), rexiv2::Rexiv2Error> {
+    let meta = rexiv2::Metadata::new_from_path(path)?;
+    let set = |tag: &str, value: Option<String>| match value {
+        Some(value) => meta.set_tag_string(tag, &value),
+        None => {
+            meta.clear_tag(tag);
+            Ok(())
+        }
+    };
+    set(
+        "Xmp.xmp.Rating",
+        xmp.rating.map(|rating| rating.to_string()),
+    )?;
+    set("Xmp.xmp.Label", xmp.label.clone())?;
+    set("Xmp.xmpDM.pick", xmp.pick.map(|pick| pick.to_string()))?;
+    set("Xmp.dc.title", xmp.title.clone())?;
+    set("Xmp.dc.description", xmp.description.clone())?;
+    for field in image_helpers::IPTC_FIELDS {
+        if ["title", "description"].contains(&field.column) {
+            continue;
+        }
+        let value = xmp
+            .iptc
+            .iter()
+            .find(|(tag, _)| *tag == field.xmp)
+            .map(|(_, value)| value.as_str());
+        match (iptc_kind(field.xmp), value) {
+            (IptcKind::Bag | IptcKind::Seq, Some(value)) => {
+                meta.clear_tag(field.xmp);
+                let values: Vec<&str> = value.split(image_helpers::IPTC_LIST_SEPARATOR).collect();
+                meta.set_tag_multiple_strings(field.xmp, &values)?;
+            }
+            (_, value) => set(field.xmp, value.map(str::to_string))?,
+        }
+    }
+    for (tag, values) in [
+        ("Xmp.dc.subject", &xmp.keywords),
+        ("Xmp.lr.hierarchicalSubject", &xmp.hierarchical_keywords),
+    ] {
+        meta.clear_tag(tag);
+        if !values.is_empty() {
+            let values: Vec<&str> = values.iter().map(|value| value.as_str()).collect();
+            meta.set_tag_multiple_strings(tag, &values)?;
+        }
+    }
+    meta.save_to_file(path)
+}
+
+// The properties we write, along with the IPTC ones (see iptc_xml_name). Everything
+// else in an existing sidecar (develop settings and such from other applications) is
+// kept as it is.
+const MANAGED_PROPERTIES: [&str; 7] = [
+    "xmp:Rating",
+    "xmp:Label",
+    "xmpDM:pick",
+    "dc:title",
+    "dc:description",
+    "dc:subject",
+    "lr:hierarchicalSubject",
+];
+
+const NAMESPACES: [(&str, &str); 4] = [
+    ("xmp", "http://ns.adobe.com/xap/1.0/"),
+    ("xmpDM", "http://ns.adobe.com/xmp/1.0/DynamicMedia/"),
+    ("dc", "http://purl.org/dc/elements/1.1/"),
+    ("lr", "http://ns.adobe.com/lightroom/1.0/"),
+];
+
+// Declared when there is an IPTC value of theirs to write
+const IPTC_NAMESPACES: [(&str, &str); 4] = [
+    ("photoshop", "http://ns.adobe.com/photoshop/1.0/"),
+    ("xmpRights", "http://ns.adobe.com/xap/1.0/rights/"),
+    (
+        "Iptc4xmpCore",
+        "http://iptc.org/std/Iptc4xmpCore/1.0/xmlns/",
+    ),
+    ("Iptc4xmpExt", "http://iptc.org/std/Iptc4xmpExt/2008-02-29/"),
+];
+
+// How an IPTC property is laid out in xmp. Several values are stored joined with
+// image_helpers::IPTC_LIST_SEPARATOR and become the items of a Bag or Seq.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum IptcKind {
+    Text,
+    LangAlt,
+    Bag,
+    Seq,
+}
+
+fn iptc_kind(tag: &str) -> IptcKind {
+    match tag {
+        "Xmp.dc.rights" | "Xmp.xmpRights.UsageTerms" | "Xmp.iptcExt.Event" => IptcKind::LangAlt,
+        "Xmp.dc.creator" => IptcKind::Seq,
+        "Xmp.iptc.SubjectCode"
+        | "Xmp.iptc.Scene"
+        | "Xmp.iptcExt.PersonInImage"
+        | "Xmp.iptcExt.OrganisationInImageName" => IptcKind::Bag,
+        _ => IptcKind::Text,
+    }
+}
+
+// The element name of an exiv2 xmp tag, and the one of the struct it's in, if any:
+// Xmp.photoshop.City is photoshop:City and
+// Xmp.iptc.CreatorContactInfo/Iptc4xmpCore:CiAdrCity is Iptc4xmpCore:CiAdrCity inside
+// Iptc4xmpCore:CreatorContactInfo
+fn iptc_xml_name(tag: &str) -> (Option<String>, String) {
+    let tag = tag.strip_prefix("Xmp.").unwrap_or(tag);
+    let (group, name) = tag.split_once('.').unwrap_or(("", tag));
+    let prefix = match group {
+        "iptc" => "Iptc4xmpCore",
+        "iptcExt" => "Iptc4xmpExt",
+        group => group,
+    };
+    match name.split_once('/') {
+        Some((parent, child)) => (Some(format!("{prefix}:{parent}")), child.to_string()),
+        None => (None, format!("{prefix}:{name}")),
+    }
+}
+
+const EMPTY_PACKET: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="">
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+"#;
+
+/// Puts the given values into an xmp packet, replacing whatever the packet had for
+/// them. Without an existing packet a new one is created.
+pub fn update_packet(existing: Option<&str>, xmp: &XmpData) -> String {
+    existing
+        .and_then(|packet| insert_properties(packet, xmp))
+        .or_else(|| insert_properties(EMPTY_PACKET, xmp))
+        .unwrap_or_default()
+}
+
+fn insert_properties(packet: &str, xmp: &XmpData) -> Option<String> {
+    let mut packet = packet.to_string();
+    let iptc_names = image_helpers::IPTC_FIELDS
+        .iter()
+        .filter(|field| !["title", "description"].contains(&field.column))
+        .map(|field| match iptc_xml_name(field.xmp) {
+            (Some(parent), _) => parent,
+            (None, name) => name,
+        });
+    for name in MANAGED_PROPERTIES
+        .map(str::to_string)
+        .into_iter()
+        .chain(iptc_names)
+    {
+        packet = remove_property(&packet, &name);
+    }
+
+    let start = packet.find("<rdf:Description")?;
+    let tag_end = start + packet[start..].find('>')?;
+    let mut start_tag = packet[start..tag_end].to_string();
+    let self_closing = start_tag.ends_with('/');
+    if self_closing {
+        start_tag.pop();
+    }
+    let properties = properties_xml(xmp);
+    // Declaring a namespace again on this element is fine even if an ancestor has it
+    let used_iptc_namespaces = IPTC_NAMESPACES
+        .into_iter()
+        .filter(|(prefix, _)| properties.contains(&format!("<{prefix}:")));
+    for (prefix, uri) in NAMESPACES.into_iter().chain(used_iptc_namespaces) {
+        if !start_tag.contains(&format!("xmlns:{}=", prefix)) {
+            start_tag.push_str(&format!("\n    xmlns:{}=\"{}\"", prefix, uri));
+        }
+    }
+
+    let rest = &packet[tag_end + 1..];
+    if self_closing {
+        Some(format!(
+            "{}{}>{}\n  </rdf:Description>{}",
+            &packet[..start],
+            start_tag,
+            properties,
+            rest
+        ))
+    } else {
+        Some(format!(
+            "{}{}>{}{}",
+            &packet[..start],
+            start_tag,
+            properties,
+            rest
+        ))
+    }
+}
+
+fn properties_xml(xmp: &XmpData) -> String {
+    let mut xml = String::new();
+    let mut simple = |name: &str, value: Option<String>| {
+        if let Some(value) = value {
+            xml.push_str(&format!("\n   <{name}>{}</{name}>", escape(&value)));
+        }
+    };
+    simple("xmp:Rating", xmp.rating.map(|rating| rating.to_string()));
+    simple("xmp:Label", xmp.label.clone());
+    simple("xmpDM:pick", xmp.pick.map(|pick| pick.to_string()));
+
+    for (name, value) in [
+        ("dc:title", &xmp.title),
+        ("dc:description", &xmp.description),
+    ] {
+        if let Some(value) = value {
+            xml.push_str(&lang_alt_xml(name, value, "   "));
+        }
+    }
+    for (name, values) in [
+        ("dc:subject", &xmp.keywords),
+        ("lr:hierarchicalSubject", &xmp.hierarchical_keywords),
+    ] {
+        if values.is_empty() {
+            continue;
+        }
+        xml.push_str(&array_xml(name, "rdf:Bag", values, "   "));
+    }
+
+    // Struct members go into one element per struct, in the order of the registry
+    let mut structs: Vec<(String, String)> = vec![];
+    for (tag, value) in &xmp.iptc {
+        let (parent, name) = iptc_xml_name(tag);
+        let indent = if parent.is_some() { "    " } else { "   " };
+        let element = match iptc_kind(tag) {
+            IptcKind::Text => format!("\n{indent}<{name}>{}</{name}>", escape(value)),
+            IptcKind::LangAlt => lang_alt_xml(&name, value, indent),
+            kind => {
+                let values: Vec<String> = value
+                    .split(image_helpers::IPTC_LIST_SEPARATOR)
+                    .map(str::to_string)
+                    .collect();
+                let array = if kind == IptcKind::Seq {
+                    "rdf:Seq"
+                } else {
+                    "rdf:Bag"
+                };
+                array_xml(&name, array, &values, indent)
+            }
+        };
+        match parent {
+            Some(parent) => match structs.iter_mut().find(|(name, _)| *name == parent) {
+                Some((_, members)) => members.push_str(&element),
+                None => structs.push((parent, element)),
+            },
+            None => xml.push_str(&element),
+        }
+    }
+    for (name, members) in structs {
+        xml.push_str(&format!(
+            "\n   <{name} rdf:parseType=\"Resource\">{members}\n   </{name}>"
+        ));
+    }
+    xml
+}
+
+fn lang_alt_xml(name: &str, value: &str, indent: &str) -> String {
+    format!(
+        "\n{indent}<{name}>\n{indent} <rdf:Alt>\n{indent}  <rdf:li xml:lang=\"x-default\">{}</rdf:li>\n{indent} </rdf:Alt>\n{indent}</{name}>",
+        escape(value)
+    )
+}
+
+fn array_xml(name: &str, array: &str, values: &[String], indent: &str) -> String {
+    let mut xml = format!("\n{indent}<{name}>\n{indent} <{array}>");
+    for value in values {
+        xml.push_str(&format!("\n{indent}  <rdf:li>{}</rdf:li>", escape(value)));
+    }
+    xml.push_str(&format!("\n{indent} </{array}>\n{indent}</{name}>"));
+    xml
+}
+
+// Removes a property in both its attribute and element forms
+fn remove_property(packet: &str, name: &str) -> String {
+    let mut packet = packet.to_string();
+
+    let attribute = format!("{}=", name);
+    let mut search_from = 0;
+    while let Some(found) = packet[search_from..].find(&attribute) {
+        let position = search_from + found;
+        search_from = position + attribute.len();
+        let whitespace_start = packet[..position].trim_end().len();
+        let quote = packet[search_from..].chars().next();
+        if whitespace_start == position || !matches!(quote, Some('"') | Some('\'')) {
+            continue;
+        }
+        let quote = quote.unwrap_or('"');
+        let Some(value_end) = packet[search_from + 1..].find(quote) else {
+            break;
+        };
+        packet.replace_range(whitespace_start..search_from + 1 + value_end + 1, "");
+        search_from = whitespace_start;
+    }
+
+    let open = format!("<{}", name);
+    let close = format!("</{}>", name);
+    let mut search_from = 0;
+    while let Some(found) = packet[search_from..].find(&open) {
+        let position = search_from + found;
+        let after_name = position + open.len();
+        if !matches!(
+            packet[after_name..].chars().next(),
+            Some('>') | Some('/') | Some(' ') | Some('\n') | Some('\r') | Some('\t')
+        ) {
+            search_from = after_name;
+            continue;
+        }
+        let Some(tag_end) = packet[position..].find('>').map(|end| position + end) else {
+            break;
+        };
+        let end = if packet[..tag_end].ends_with('/') {
+            tag_end + 1
+        } else {
+            match packet[tag_end..].find(&close) {
+                Some(close_start) => tag_end + close_start + close.len(),
+                None => break,
+            }
+        };
+        // Take the indentation in front of the element along with it
+        let line_start = packet[..position].trim_end_matches([' ', '\t']).len();
+        let start = if packet[..line_start].ends_with('\n') {
+            line_start - 1
+        } else {
+            position
+        };
+        packet.replace_range(start..end, "");
+        search_from = start;
+    }
+    packet
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Value of a simple property, or of the first rdf:li for arrays and language
+/// alternatives (dc:title, dc:creator)
+fn property(packet: &str, name: &str) -> Option<String> {
+    if let Some(value) = xml::attribute(packet, name) {
+        return Some(value);
+    }
+    let content = xml::element_content(packet, name)?;
+    let value = match list_items(content).into_iter().next() {
+        Some(item) => item,
+        None => xml::unescape(content.trim()),
+    };
+    Some(value).filter(|value| !value.is_empty())
+}
+
+fn property_list(packet: &str, name: &str) -> Vec<String> {
+    xml::element_content(packet, name)
+        .map(list_items)
+        .unwrap_or_default()
+}
+
+fn list_items(content: &str) -> Vec<String> {
+    let mut items = vec![];
+    let mut rest = content;
+    while let Some(start) = rest.find("<rdf:li") {
+        rest = &rest[start..];
+        let Some(content_start) = rest.find('>') else {
+            break;
+        };
+        let Some(content_end) = rest.find("</rdf:li>") else {
+            break;
+        };
+        if content_start < content_end {
+            let item = xml::unescape(rest[content_start + 1..content_end].trim());
+            if !item.is_empty() {
+                items.push(item);
+            }
+        }
+        rest = &rest[content_end + "</rdf:li>".len()..];
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::HashMap,
+        time::{Duration, SystemTime},
+    };
+
+    const SIDECAR: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmp:Rating="4"
+    xmp:Label="Green">
+   <dc:title>
+    <rdf:Alt>
+     <rdf:li xml:lang="x-default">Lalbagh &amp; flowers</rdf:li>
+    </rdf:Alt>
+   </dc:title>
+   <dc:subject>
+    <rdf:Bag>
+     <rdf:li>nature</rdf:li>
+     <rdf:li>flowers</rdf:li>
+    </rdf:Bag>
+   </dc:subject>
+   <dc:creator><rdf:Seq><rdf:li>Mukesh Soni</rdf:li></rdf:Seq></dc:creator>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+
+    #[test]
+    fn test_parse_xmp_packet() {
+        let xmp = XmpData::from_packet(SIDECAR);
+        assert_eq!(xmp.rating, Some(4));
+        assert_eq!(xmp.color_label().as_deref(), Some("green"));
+        assert_eq!(xmp.keywords, vec!["nature", "flowers"]);
+        assert_eq!(xmp.title.as_deref(), Some("Lalbagh & flowers"));
+        assert_eq!(xmp.creator.as_deref(), Some("Mukesh Soni"));
+        assert_eq!(xmp.description, None);
+        assert_eq!(xmp.flag(), None);
+
+        let xmp =
+            XmpData::from_packet("<rdf:Description><xmp:Rating>-1</xmp:Rating></rdf:Description>");
+        assert_eq!(xmp.rating, Some(-1));
+        assert_eq!(xmp.flag(), Some("rejected"));
+    }
+
+    #[test]
+    fn test_strip_lang_alt() {
+        assert_eq!(strip_lang_alt("lang=\"x-default\" A title"), "A title");
+        assert_eq!(strip_lang_alt("A title"), "A title");
+    }
+
+    #[sqlx::test]
+    async fn test_insert_images_reads_sidecar(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("DSCF0001.jpg"), b"not a real jpeg")?;
+        fs::write(dir.path().join("DSCF0001.xmp"), SIDECAR)?;
+        fs::write(dir.path().join("DSCF0002.jpg"), b"not a real jpeg")?;
+        fs::write(
+            dir.path().join("DSCF0002.jpg.xmp"),
+            r#"<rdf:Description xmp:Rating="-1" xmpDM:pick="-1"/>"#,
+        )?;
+
+        let path = dir.path().to_string_lossy().to_string();
+        db::insert_images(&pool, &path).await?;
+
+        let rows = sqlx::query("select i.id, i.rating, i.flag, i.color_label, iptc.title, iptc.creator from image i join library_file lf on lf.id = i.library_file_id join iptc on iptc.image_id = i.id order by lf.base_name")
+            .fetch_all(&pool)
+            .await?;
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].get::<u32, _>("rating"), 4);
+        assert_eq!(rows[0].get::<String, _>("flag"), "unpicked");
+        assert_eq!(rows[0].get::<String, _>("color_label"), "green");
+        assert_eq!(rows[0].get::<String, _>("title"), "Lalbagh & flowers");
+        assert_eq!(rows[0].get::<String, _>("creator"), "Mukesh Soni");
+        assert_eq!(rows[1].get::<u32, _>("rating"), 0);
+        assert_eq!(rows[1].get::<String, _>("flag"), "rejected");
+
+        let keywords = db::get_keywords(&pool).await?;
+        assert_eq!(keywords.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_packet() {
+        let existing = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:crs="http://ns.adobe.com/camera-raw-settings/1.0/"
+    xmp:Rating="2"
+    crs:Exposure2012="+0.35">
+   <dc:subject>
+    <rdf:Bag>
+     <rdf:li>old</rdf:li>
+    </rdf:Bag>
+   </dc:subject>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+        let xmp = XmpData {
+            rating: Some(5),
+            label: Some("Red".to_string()),
+            pick: Some(1),
+            keywords: vec![
+                "Places".to_string(),
+                "India".to_string(),
+                "a & b".to_string(),
+            ],
+            hierarchical_keywords: vec!["Places|India".to_string()],
+            description: Some("Sunset <3".to_string()),
+            ..Default::default()
+        };
+        let packet = update_packet(Some(existing), &xmp);
+        assert!(packet.contains(r#"crs:Exposure2012="+0.35""#));
+        assert!(!packet.contains("old"));
+        assert!(!packet.contains(r#"xmp:Rating="2""#));
+        assert_eq!(XmpData::from_packet(&packet), xmp);
+        assert_eq!(xmp.catalog_keywords(), vec!["Places|India", "a & b"]);
+
+        let packet = update_packet(None, &xmp);
+        assert_eq!(XmpData::from_packet(&packet), xmp);
+        let packet = update_packet(Some("<rdf:Description rdf:about=\"\"/>"), &xmp);
+        assert_eq!(XmpData::from_packet(&packet), xmp);
+    }
+
+    #[sqlx::test]
+    async fn test_write_back_to_sidecar(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let image_path = dir.path().join("DSCF0001.jpg");
+        let sidecar = dir.path().join("DSCF0001.xmp");
+        fs::write(&image_path, b"not a real jpeg")?;
+        fs::write(&sidecar, SIDECAR)?;
+        db::insert_images(&pool, &dir.path().to_string_lossy()).await?;
+        let image_path = image_path.to_string_lossy().to_string();
+
+        // Off by default
+        db::update_image_rating(&pool, &image_path, 2).await?;
+        assert_eq!(fs::read_to_string(&sidecar)?, SIDECAR);
+
+        set_xmp_write_back(&pool, XmpWriteBack::Sidecar).await?;
+        db::update_image_rating(&pool, &image_path, 2).await?;
+        db::update_flag(&pool, &image_path, "picked").await?;
+        db::add_keyword(&pool, &image_path, "Places|India").await?;
+        let xmp = XmpData::from_packet(&fs::read_to_string(&sidecar)?);
+        assert_eq!(xmp.rating, Some(2));
+        assert_eq!(xmp.flag(), Some("picked"));
+        assert_eq!(xmp.label.as_deref(), Some("Green"));
+        assert_eq!(xmp.title.as_deref(), Some("Lalbagh & flowers"));
+        assert_eq!(
+            xmp.catalog_keywords(),
+            vec!["Places|India", "flowers", "nature"]
+        );
+
+        // Another program changes the sidecar, we must not overwrite it
+        let changed = SIDECAR.replace(r#"xmp:Rating="4""#, r#"xmp:Rating="1""#);
+        fs::write(&sidecar, &changed)?;
+        fs::File::options()
+            .write(true)
+            .open(&sidecar)?
+            .set_modified(SystemTime::now() + Duration::from_secs(10))?;
+        let image_id = 1;
+        let outcome = write_back_if_enabled(&pool, image_id).await?;
+        assert_eq!(outcome, WriteBackOutcome::Conflict(sidecar.clone()));
+        // The catalog change still happens, and the caller learns about the conflict
+        let outcome = db::update_color_label(&pool, &image_path, "red").await?;
+        assert_eq!(outcome, WriteBackOutcome::Conflict(sidecar.clone()));
+        assert_eq!(fs::read_to_string(&sidecar)?, changed);
+
+        let outcome = write_back_image(&pool, image_id, XmpWriteBack::Sidecar, true).await?;
+        assert_eq!(outcome, WriteBackOutcome::Written(sidecar.clone()));
+        let xmp = XmpData::from_packet(&fs::read_to_string(&sidecar)?);
+        assert_eq!(xmp.rating, Some(2));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_write_back_creates_sidecar(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let image_path = dir.path().join("DSCF0002.jpg");
+        fs::write(&image_path, b"not a real jpeg")?;
+        db::insert_images(&pool, &dir.path().to_string_lossy()).await?;
+        set_xmp_write_back(&pool, XmpWriteBack::Embed).await?;
+
+        // The folder is gone, e.g. the card was pulled out. The label is changed all the
+        // same.
+        let away = dir.path().with_extension("away");
+        fs::rename(dir.path(), &away)?;
+        let outcome = db::update_color_label(&pool, &image_path.to_string_lossy(), "red").await?;
+        fs::rename(&away, dir.path())?;
+        assert!(matches!(outcome, WriteBackOutcome::Failed(_)));
+        let row = sqlx::query("select color_label from image where id = 1")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(row.get::<String, _>("color_label"), "red");
+
+        // Not actually a jpeg, so this falls back to a sidecar
+        db::update_color_label(&pool, &image_path.to_string_lossy(), "blue").await?;
+        let xmp = XmpData::from_packet(&fs::read_to_string(dir.path().join("DSCF0002.xmp"))?);
+        assert_eq!(xmp.label.as_deref(), Some("Blue"));
+        assert_eq!(xmp.rating, Some(0));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_write_back_iptc(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let image_path = dir.path().join("DSCF0003.jpg");
+        let sidecar = dir.path().join("DSCF0003.xmp");
+        fs::write(&image_path, b"not a real jpeg")?;
+        db::insert_images(&pool, &dir.path().to_string_lossy()).await?;
+        set_xmp_write_back(&pool, XmpWriteBack::Sidecar).await?;
+
+        let fields = HashMap::from([
+            ("headline".to_string(), Some("Monsoon".to_string())),
+            ("persons_shown".to_string(), Some("Asha; Ravi".to_string())),
+            ("usage_terms".to_string(), Some("No reuse".to_string())),
+            ("contact_city".to_string(), Some("Bengaluru".to_string())),
+            (
+                "contact_email".to_string(),
+                Some("me@example.com".to_string()),
+            ),
+        ]);
+        let outcomes = db::update_iptc_fields(&pool, &[1], &fields).await?;
+        assert_eq!(
+            outcomes,
+            vec![(1, WriteBackOutcome::Written(sidecar.clone()))]
+        );
+        let packet = fs::read_to_string(&sidecar)?;
+        assert_eq!(
+            property(&packet, "photoshop:Headline").as_deref(),
+            Some("Monsoon")
+        );
+        assert_eq!(
+            property_list(&packet, "Iptc4xmpExt:PersonInImage"),
+            vec!["Asha", "Ravi"]
+        );
+        assert_eq!(
+            property(&packet, "xmpRights:UsageTerms").as_deref(),
+            Some("No reuse")
+        );
+        let contact = xml::element_content(&packet, "Iptc4xmpCore:CreatorContactInfo").unwrap();
+        assert_eq!(
+            property(contact, "Iptc4xmpCore:CiAdrCity").as_deref(),
+            Some("Bengaluru")
+        );
+        assert_eq!(
+            property(contact, "Iptc4xmpCore:CiEmailWork").as_deref(),
+            Some("me@example.com")
+        );
+        assert!(packet.contains("xmlns:Iptc4xmpExt="));
+
+        // Cleared fields go, and the struct is written once
+        let fields = HashMap::from([("headline".to_string(), None)]);
+        db::update_iptc_fields(&pool, &[1], &fields).await?;
+        let packet = fs::read_to_string(&sidecar)?;
+        assert_eq!(property(&packet, "photoshop:Headline"), None);
+        assert_eq!(
+            packet.matches("<Iptc4xmpCore:CreatorContactInfo").count(),
+            1
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_write_back_same_stem(pool: SqlitePool) -> sqlx::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let jpg = dir.path().join("IMG_0001.jpg");
+        let jpeg = dir.path().join("IMG_0001.jpeg");
+        fs::write(&jpg, b"one")?;
+        fs::write(&jpeg, b"another")?;
+        db::insert_images(&pool, &dir.path().to_string_lossy()).await?;
+        set_xmp_write_back(&pool, XmpWriteBack::Sidecar).await?;
+
+        db::update_image_rating(&pool, &jpg.to_string_lossy(), 2).await?;
+        db::update_image_rating(&pool, &jpeg.to_string_lossy(), 5).await?;
+        let outcome = db::update_flag(&pool, &jpg.to_string_lossy(), "picked").await?;
+        assert_eq!(
+            outcome,
+            WriteBackOutcome::Written(dir.path().join("IMG_0001.jpg.xmp"))
+        );
+        assert!(!dir.path().join("IMG_0001.xmp").exists());
+        assert_eq!(read_xmp(&jpg).rating, Some(2));
+        assert_eq!(read_xmp(&jpeg).rating, Some(5));
+        Ok(())
+    }
+}